@@ -6,6 +6,7 @@ import shutil
 #############################################
 # CONFIG
 EFI_BIN = "./deploy/ESP/EFI/BOOT/BOOTX64.EFI"
+INITRD = "./deploy/ESP/initrd.tar"
 OUT_ISO = "uefi_boot.iso"
 LABEL = "MYUEFIISO"
 #############################################
@@ -24,6 +25,8 @@ try:
     subprocess.check_call(["mmd", "-i", esp_img, "::EFI/BOOT"])
 
     subprocess.check_call(["mcopy", "-i", esp_img, EFI_BIN, "::EFI/BOOT/BOOTX64.EFI"])
+    if os.path.exists(INITRD):
+        subprocess.check_call(["mcopy", "-i", esp_img, INITRD, "::initrd.tar"])
 
     iso_root = os.path.join(tmp, "root")
     os.makedirs(iso_root, exist_ok=True)
@@ -2,6 +2,23 @@ import os
 import shutil
 import sys
 import subprocess
+import tarfile
+
+import symtab
+
+TEST_TIMEOUT = 120
+QEMU_EXIT_SUCCESS = (0x10 << 1) | 1
+INITRD_DIR = "initrd"
+DISK_IMAGE = "disk.img"
+NVME_IMAGE = "nvme.img"
+MODE_CONFIG = "mode.cfg"
+SYMBOL_MAP = os.path.join("target", "FigOS.map")
+
+
+def build_initrd(src_dir, out_path):
+    with tarfile.open(out_path, "w", format=tarfile.USTAR_FORMAT) as tar:
+        for name in sorted(os.listdir(src_dir)):
+            tar.add(os.path.join(src_dir, name), arcname=name)
 
 
 def main():
@@ -10,6 +27,7 @@ def main():
         sys.exit(1)
 
     efi_path = sys.argv[1]
+    is_test = os.path.basename(os.path.dirname(os.path.abspath(efi_path))) == "deps"
     root_dir = os.getcwd()
     deploy_dir = os.path.join(root_dir, "deploy")
     esp_dir = os.path.join(deploy_dir, "ESP")
@@ -19,8 +37,18 @@ def main():
         shutil.rmtree(deploy_dir)
     os.makedirs(boot_dir)
 
-    shutil.copy(efi_path, os.path.join(root_dir, "FigOS.efi"))
-    shutil.copy(efi_path, os.path.join(boot_dir, "BOOTX64.EFI"))
+    boot_efi = os.path.join(boot_dir, "BOOTX64.EFI")
+    shutil.copy(efi_path, boot_efi)
+    symtab.patch(boot_efi, os.path.join(root_dir, SYMBOL_MAP))
+    shutil.copy(boot_efi, os.path.join(root_dir, "FigOS.efi"))
+
+    initrd_dir = os.path.join(root_dir, INITRD_DIR)
+    if os.path.isdir(initrd_dir):
+        build_initrd(initrd_dir, os.path.join(esp_dir, "initrd.tar"))
+
+    mode_config = os.path.join(root_dir, MODE_CONFIG)
+    if os.path.isfile(mode_config):
+        shutil.copy(mode_config, os.path.join(esp_dir, MODE_CONFIG))
 
     ovmf_path = os.path.join(root_dir, "OVMF.fd")
 
@@ -29,7 +57,7 @@ def main():
         "-bios",
         ovmf_path,
         "-drive",
-        f"format=raw,file=fat:rw:{esp_dir}",
+        f"format=raw,file=fat:{esp_dir},if=virtio,readonly=on",
         "-m",
         "1G",
         "-device",
@@ -47,6 +75,42 @@ def main():
         "-no-reboot",
     ]
 
+    disk_image = os.path.join(root_dir, DISK_IMAGE)
+    if os.path.isfile(disk_image) and not is_test:
+        if os.environ.get("FIGOS_DISK_BUS") == "ahci":
+            qemu_cmd += [
+                "-device",
+                "ahci,id=ahci",
+                "-drive",
+                f"id=disk,file={disk_image},if=none,format=raw",
+                "-device",
+                "ide-hd,drive=disk,bus=ahci.0",
+            ]
+        else:
+            qemu_cmd += ["-drive", f"file={disk_image},if=virtio,format=raw"]
+
+    nvme_image = os.path.join(root_dir, NVME_IMAGE)
+    if os.path.isfile(nvme_image) and not is_test:
+        qemu_cmd += [
+            "-drive",
+            f"id=nvme,file={nvme_image},if=none,format=raw",
+            "-device",
+            "nvme,serial=figos0001,drive=nvme",
+        ]
+
+    if is_test:
+        qemu_cmd = [arg for arg in qemu_cmd if arg not in ("-d", "int,cpu_reset", "-D", "qemu.log")]
+        qemu_cmd[qemu_cmd.index("sdl")] = "none"
+        qemu_cmd += ["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]
+
+        print("Running FigOS kernel tests in QEMU")
+        try:
+            result = subprocess.run(qemu_cmd, timeout=TEST_TIMEOUT)
+        except subprocess.TimeoutExpired:
+            print(f"Tests timed out after {TEST_TIMEOUT}s")
+            sys.exit(1)
+        sys.exit(0 if result.returncode == QEMU_EXIT_SUCCESS else 1)
+
     print(f"Starting QEMU with high resolution support")
     subprocess.run(qemu_cmd)
 
@@ -0,0 +1,81 @@
+# genhello.py
+# Position-independent ELF64 ring 3 program, run with `exec /bin/hello`.
+# Prints a message through a pointer fixed up by an R_X86_64_RELATIVE
+# relocation, then exits with argc as its status code.
+import struct
+
+SYS_WRITE = 0
+SYS_EXIT = 2
+
+TEXT_OFFSET = 0x100
+DATA_OFFSET = 0x1000
+
+message = b"\nHello from ring 3!"
+
+# Data segment: [message pointer][dynamic section][rela table]
+msg_ptr = DATA_OFFSET
+dynamic = msg_ptr + 8
+rela = dynamic + 4 * 16
+
+code = b""
+code += b"\x48\x8b\x1c\x24"                         # mov rbx, [rsp] (argc)
+rip_after = TEXT_OFFSET + len(code) + 7
+code += b"\x48\x8b\x35" + struct.pack("<i", msg_ptr - rip_after)  # mov rsi, [rip + msg_ptr]
+code += b"\xba" + struct.pack("<I", len(message))   # mov edx, len
+code += b"\xbf" + struct.pack("<I", 1)              # mov edi, 1 (stdout)
+code += b"\xb8" + struct.pack("<I", SYS_WRITE)      # mov eax, SYS_WRITE
+code += b"\x0f\x05"                                 # syscall
+code += b"\x48\x89\xdf"                             # mov rdi, rbx
+code += b"\xb8" + struct.pack("<I", SYS_EXIT)       # mov eax, SYS_EXIT
+code += b"\x0f\x05"                                 # syscall
+code += b"\x0f\x0b"                                 # ud2
+
+msg_addr = TEXT_OFFSET + len(code)
+text = code + message
+text_end = TEXT_OFFSET + len(text)
+
+DT_NULL, DT_RELA, DT_RELASZ, DT_RELAENT = 0, 7, 8, 9
+R_X86_64_RELATIVE = 8
+
+data = struct.pack("<Q", 0)
+data += struct.pack("<qQ", DT_RELA, rela)
+data += struct.pack("<qQ", DT_RELASZ, 24)
+data += struct.pack("<qQ", DT_RELAENT, 24)
+data += struct.pack("<qQ", DT_NULL, 0)
+data += struct.pack("<QQq", msg_ptr, R_X86_64_RELATIVE, msg_addr)
+
+PT_LOAD, PT_DYNAMIC = 1, 2
+PF_X, PF_W, PF_R = 1, 2, 4
+
+phdrs = [
+    (PT_LOAD, PF_R | PF_X, 0, 0, text_end, text_end, 0x1000),
+    (PT_LOAD, PF_R | PF_W, DATA_OFFSET, DATA_OFFSET, len(data), len(data), 0x1000),
+    (PT_DYNAMIC, PF_R | PF_W, dynamic, dynamic, 4 * 16, 4 * 16, 8),
+]
+
+header = b"\x7fELF" + bytes([2, 1, 1, 0]) + bytes(8)
+header += struct.pack("<HHIQQQIHHHHHH",
+    3,                  # ET_DYN
+    0x3E,               # EM_X86_64
+    1,                  # EV_CURRENT
+    TEXT_OFFSET,        # e_entry
+    64,                 # e_phoff
+    0,                  # e_shoff
+    0,                  # e_flags
+    64,                 # e_ehsize
+    56,                 # e_phentsize
+    len(phdrs),         # e_phnum
+    64,                 # e_shentsize
+    0,                  # e_shnum
+    0,                  # e_shstrndx
+)
+
+image = bytearray(DATA_OFFSET + len(data))
+image[0:64] = header
+for i, (ty, flags, offset, vaddr, filesz, memsz, align) in enumerate(phdrs):
+    image[64 + i * 56:64 + (i + 1) * 56] = struct.pack("<IIQQQQQQ", ty, flags, offset, vaddr, vaddr, filesz, memsz, align)
+image[TEXT_OFFSET:text_end] = text
+image[DATA_OFFSET:] = data
+
+with open("hello.elf", "wb") as f:
+    f.write(image)
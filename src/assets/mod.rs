@@ -1,5 +1,9 @@
 pub const FONT: &[u8] = include_bytes!("font.psf");
 
+pub const PROGRAMS: [(&str, &[u8]); 1] = [
+    ("/bin/hello", include_bytes!("hello.elf")),
+];
+
 pub const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
 pub const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
 
@@ -20,4 +24,8 @@ pub struct Psf2Header {
     pub char_size: u32,
     pub height: u32,
     pub width: u32,
-}
\ No newline at end of file
+}
+
+pub fn find_program(path: &str) -> Option<&'static [u8]> {
+    PROGRAMS.iter().find(|(name, _)| *name == path).map(|(_, image)| *image)
+}
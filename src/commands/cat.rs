@@ -0,0 +1,35 @@
+use crate::print;
+use crate::fs::vfs::{self, O_READ};
+
+pub fn execute(args: &[u8]) {
+    let path = core::str::from_utf8(args).unwrap_or("").trim();
+    if path.is_empty() {
+        print!("\nUsage: cat [path]");
+        return;
+    }
+
+    let fd = match vfs::open(path, O_READ) {
+        Ok(fd) => fd,
+        Err(e) => {
+            print!("\ncat: {}: {:?}", path, e);
+            return;
+        }
+    };
+
+    print!("\n");
+    let mut buf = [0u8; 256];
+    loop {
+        match vfs::read(fd, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => match core::str::from_utf8(&buf[..n]) {
+                Ok(text) => print!("{}", text),
+                Err(_) => for &b in &buf[..n] { print!("{}", b as char); },
+            },
+            Err(e) => {
+                print!("\ncat: {}: {:?}", path, e);
+                break;
+            }
+        }
+    }
+    let _ = vfs::close(fd);
+}
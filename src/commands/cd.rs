@@ -0,0 +1,10 @@
+use crate::print;
+use crate::fs::vfs;
+
+pub fn execute(args: &[u8]) {
+    let path = core::str::from_utf8(args).unwrap_or("").trim();
+    let path = if path.is_empty() { "/" } else { path };
+    if let Err(e) = vfs::chdir(path) {
+        print!("\ncd: {}: {:?}", path, e);
+    }
+}
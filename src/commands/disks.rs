@@ -0,0 +1,20 @@
+use crate::print;
+use crate::drivers::block;
+
+pub fn execute() {
+    let devices = block::devices();
+    if devices.is_empty() {
+        print!("\nNo block devices");
+        return;
+    }
+    print!("\nNAME         SIZE        BLOCK  MODEL");
+    for dev in devices {
+        let bytes = dev.block_count() * dev.block_size() as u64;
+        let size = if bytes >= 1 << 30 {
+            alloc::format!("{}.{} GiB", bytes >> 30, (bytes % (1 << 30)) * 10 >> 30)
+        } else {
+            alloc::format!("{} MiB", bytes >> 20)
+        };
+        print!("\n{:<12} {:<11} {:<6} {}", dev.name(), size, dev.block_size(), dev.model());
+    }
+}
@@ -0,0 +1,31 @@
+use alloc::vec::Vec;
+use crate::print;
+use crate::assets;
+use crate::fs::vfs;
+use crate::system::elf;
+
+pub fn execute(args: &[u8]) {
+    let Ok(line) = core::str::from_utf8(args) else {
+        print!("\nInvalid arguments");
+        return;
+    };
+    let argv: Vec<&str> = line.split(' ').filter(|s| !s.is_empty()).collect();
+    let Some(&path) = argv.first() else {
+        print!("\nUsage: exec [path] [args...]");
+        return;
+    };
+    let file = vfs::read_all(path).ok();
+    let Some(image) = file.as_deref().or_else(|| assets::find_program(path)) else {
+        print!("\nexec: {}: not found", path);
+        return;
+    };
+
+    match elf::exec(image, &argv, &[]) {
+        Ok(handle) => {
+            let pid = handle.pid;
+            let code = handle.wait();
+            print!("\nProcess {} exited with code {}", pid, code);
+        }
+        Err(e) => print!("\nexec: {}: {:?}", path, e),
+    }
+}
@@ -1,8 +1,11 @@
 use crate::print;
 use crate::system::GLOBAL_CONSOLE;
 use crate::system::time;
-use crate::MM_INSTANCE;
+use crate::system::heap;
+use crate::system::smp;
+use crate::system::memory::with_mm;
 use crate::system::graphic::{GraphicBackend, Backend};
+use crate::drivers::{pci, uefi_fb};
 
 fn get_cpu_name() -> &'static str {
     use core::arch::x86_64::__cpuid;
@@ -42,12 +45,13 @@ pub fn execute() {
 
             let mut used_mb = 0;
             let mut total_mb = 0;
-            if let Some(mm_mutex) = MM_INSTANCE.get() {
-                let mm = mm_mutex.lock();
-                used_mb = mm.get_used_memory_kb() / 1024;
-                total_mb = mm.get_total_memory_kb() / 1024;
+            if let Some((used_kb, total_kb)) = with_mm(|mm| (mm.get_used_memory_kb(), mm.get_total_memory_kb())) {
+                used_mb = used_kb / 1024;
+                total_mb = total_kb / 1024;
             }
 
+            let (heap_used_kb, heap_total_kb) = heap::get_heap_usage_kb();
+
             let yellow = 0xFFFF00;
             let white = 0xFFFFFF;
 
@@ -64,12 +68,26 @@ pub fn execute() {
             c.set_color(white);
             print!("{}", cpu_model);
 
+            print!("\n");
+            c.set_color(yellow);
+            print!("Cores:       ");
+            c.set_color(white);
+            print!("{} (shell on CPU {})", smp::online_cpus(), smp::current().index);
+
             print!("\n");
             c.set_color(yellow);
             print!("Backend:     "); 
             c.set_color(white);
             print!("{}", backend_name);
 
+            if let Some(gpu) = uefi_fb::display_device() {
+                print!("\n");
+                c.set_color(yellow);
+                print!("GPU:         ");
+                c.set_color(white);
+                print!("{} [{:04x}:{:04x}]", pci::vendor_name(gpu.vendor_id), gpu.vendor_id, gpu.device_id);
+            }
+
             print!("\n");
             c.set_color(yellow);
             print!("Resolution:  ");
@@ -82,12 +100,25 @@ pub fn execute() {
             c.set_color(white);
             print!("{}MB / {}MB", used_mb, total_mb);
 
+            print!("\n");
+            c.set_color(yellow);
+            print!("Heap:        ");
+            c.set_color(white);
+            print!("{}KB / {}KB", heap_used_kb, heap_total_kb);
+
             print!("\n");
             c.set_color(yellow);
             print!("Time:        ");
             c.set_color(white);
             print!("{:02}:{:02}:{:02}", h, m, s);
 
+            let uptime_ms = time::now_ns() / 1_000_000;
+            print!("\n");
+            c.set_color(yellow);
+            print!("Uptime:      ");
+            c.set_color(white);
+            print!("{}.{:03}s ({})", uptime_ms / 1000, uptime_ms % 1000, time::timer_mode().name());
+
             print!("\n");
             c.set_color(yellow);
             print!("---");
@@ -1,25 +1,50 @@
 use crate::system::GLOBAL_CONSOLE;
 use crate::system::graphic::{Backend, GraphicBackend};
-use crate::drivers::gpu_fb::Framebuffer;
+use crate::drivers::gpu_fb::{self, DirtyRegion, Framebuffer};
+use crate::drivers::{pci, uefi_fb};
 use crate::print;
-use crate::MM_INSTANCE;
+use crate::system::memory::{with_mm, FRAME_SIZE};
 
-pub fn execute() {
+pub fn execute(args: &[u8]) {
+    let requested = uefi_fb::parse_resolution(core::str::from_utf8(args).unwrap_or(""));
+    if !args.is_empty() && requested.is_none() {
+        print!("\nUsage: gpu [WIDTHxHEIGHT]");
+        return;
+    }
     unsafe {
         if let Some(ref mut c) = GLOBAL_CONSOLE {
-            let target_width = c.backend.width();
-            let target_height = c.backend.height();
-            let target_pitch = c.backend.pitch();
-            let phys_addr = c.backend.addr();
+            let mut target_width = c.backend.width();
+            let mut target_height = c.backend.height();
+            let mut target_pitch = c.backend.pitch();
+            let mut phys_addr = c.backend.addr();
+            let gpu = gpu_fb::device();
+
+            if let Some(dev) = gpu {
+                let size = match requested {
+                    Some(size) => Ok(size),
+                    None => dev.display_size(),
+                };
+                match size.and_then(|(w, h)| dev.set_mode(w, h).map(|addr| (w, h, addr))) {
+                    Ok((w, h, addr)) => {
+                        target_width = w;
+                        target_height = h;
+                        target_pitch = w;
+                        phys_addr = addr;
+                    }
+                    Err(e) => {
+                        print!("\nvirtio-gpu: {}", e);
+                        return;
+                    }
+                }
+            } else if requested.is_some() {
+                print!("\nNo virtio-gpu device, resolution unchanged");
+            }
             let mut back_buffer_slice: Option<&'static mut [u32]> = None;
             
-            if let Some(mm_mutex) = MM_INSTANCE.get() {
-                let mut mm = mm_mutex.lock();
-                
-                let bytes_needed = target_width * target_height * 4;
-                let frames_needed = (bytes_needed + 4095) / 4096;
-
-                if let Some(ptr) = mm.alloc_frames(frames_needed) {
+            let bytes_needed = target_width * target_height * 4;
+            let frames_needed = (bytes_needed + 4095) / 4096;
+            if let Some(frame) = with_mm(|mm| mm.alloc_frames(frames_needed)) {
+                if let Some(ptr) = frame {
                     back_buffer_slice = Some(core::slice::from_raw_parts_mut(
                         ptr as *mut u32,
                         target_width * target_height
@@ -30,12 +55,20 @@ pub fn execute() {
                 }
             }
 
+            if let Backend::Gpu(old) = &mut c.backend {
+                if let Some(old) = old.back_buffer.take() {
+                    let bytes = (old.len() * 4 + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+                    with_mm(|mm| mm.free_region(old.as_ptr() as usize, bytes));
+                }
+            }
             c.backend = Backend::Gpu(Framebuffer {
                 fb_addr: phys_addr,
                 back_buffer: back_buffer_slice,
                 width: target_width,
                 height: target_height,
                 pitch: target_pitch,
+                gpu,
+                dirty: DirtyRegion::new(),
             });
             
             c.cursor_x = 0;
@@ -44,6 +77,13 @@ pub fn execute() {
             
             c.set_color(0x00FF00);
             print!("GPU Backend is now active!\n");
+            if let Some(dev) = uefi_fb::display_device() {
+                print!("Display device: {} {} [{:04x}:{:04x}] at {}\n", pci::vendor_name(dev.vendor_id),
+                    pci::class_name(dev.class, dev.subclass), dev.vendor_id, dev.device_id, dev.address);
+            }
+            if gpu.is_some() {
+                print!("virtio-gpu scanout: {}x{}\n", target_width, target_height);
+            }
             c.set_color(0xFFFFFF);
             
             c.backend.swap_buffers();
@@ -9,6 +9,21 @@ pub fn execute() {
     print!("\npanic      : Force a kernel panic");
     print!("\nwait [s]   : Wait for [s] seconds");
     print!("\nfetch      : Show system information");
-    print!("\ngpu        : Switch to gpu buffer (beta)");
+    print!("\ngpu [WxH]  : Switch to gpu buffer (beta)");
+    print!("\nexec [path]: Run an ELF program in user mode");
+    print!("\nls [path]  : List a directory");
+    print!("\ncat [path] : Print a file");
+    print!("\ncd [path]  : Change the current directory");
+    print!("\npwd        : Print the current directory");
+    print!("\ntouch [f]  : Create an empty file");
+    print!("\nmkdir [d]  : Create a directory");
+    print!("\nrm [path]  : Remove a file or empty directory");
+    print!("\nmv [a] [b] : Move or rename a file");
+    print!("\nwrite [f] [text] : Write text to a file");
+    print!("\nmount [dev] [dir] : Mount a FAT32 device (no args lists mounts)");
+    print!("\nlspci [-v] : List PCI devices");
+    print!("\ndisks      : List block devices");
+    print!("\nmode [n]   : List or switch graphics modes");
+    print!("\ncmd > [f]  : Redirect output to a file (>> appends)");
     print!("\n");
 }
\ No newline at end of file
@@ -0,0 +1,34 @@
+use crate::print;
+use crate::fs::vfs::{self, FileType};
+
+pub fn execute(args: &[u8]) {
+    let path = core::str::from_utf8(args).unwrap_or("").trim();
+    let path = if path.is_empty() { "." } else { path };
+
+    match vfs::stat(path) {
+        Ok(stat) if stat.kind == FileType::File => {
+            print!("\n{:>8}  {}", stat.size, path);
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            print!("\nls: {}: {:?}", path, e);
+            return;
+        }
+    }
+
+    match vfs::readdir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry.kind {
+                    FileType::Directory => print!("\n{:>8}  {}/", "<DIR>", entry.name),
+                    FileType::File => {
+                        let size = vfs::stat(&vfs::normalize(&vfs::absolute(path), &entry.name)).map(|s| s.size).unwrap_or(0);
+                        print!("\n{:>8}  {}", size, entry.name);
+                    }
+                }
+            }
+        }
+        Err(e) => print!("\nls: {}: {:?}", path, e),
+    }
+}
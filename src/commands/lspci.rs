@@ -0,0 +1,60 @@
+use crate::print;
+use crate::drivers::pci::{self, Bar, PciDevice, CAP_MSI, CAP_MSIX};
+
+fn print_details(dev: &PciDevice) {
+    print!("\n    Rev {:02x}, prog-if {:02x}, header {:02x}", dev.revision, dev.prog_if, dev.header_type & 0x7F);
+    if (1..=4).contains(&dev.interrupt_pin) {
+        print!(", INT{} -> IRQ {}", (b'A' + dev.interrupt_pin - 1) as char, dev.interrupt_line);
+    }
+    if let Some(bus) = pci::secondary_bus(dev) {
+        print!(", secondary bus {:02x}", bus);
+    }
+    for (i, bar) in dev.bars.iter().enumerate() {
+        match *bar {
+            Some(Bar::Memory { address, size, prefetchable, wide }) => print!(
+                "\n    BAR{}: memory at {:#x} ({}-bit, {}prefetchable) [size={}K]",
+                i, address, if wide { 64 } else { 32 }, if prefetchable { "" } else { "non-" }, size / 1024
+            ),
+            Some(Bar::Io { port, size }) => print!("\n    BAR{}: I/O ports at {:#x} [size={}]", i, port, size),
+            None => {}
+        }
+    }
+    for cap in dev.capabilities.iter() {
+        print!("\n    Capability [{:02x}] {}", cap.offset, pci::capability_name(cap.id));
+        match (cap.id, dev.msi, dev.msix) {
+            (CAP_MSI, Some(msi), _) if msi.offset == cap.offset => print!(
+                ": Enable{} Count={} 64bit{} Maskable{}",
+                flag(msi.enabled), msi.vectors, flag(msi.wide), flag(msi.per_vector_mask)
+            ),
+            (CAP_MSIX, _, Some(msix)) if msix.offset == cap.offset => print!(
+                ": Enable{} Count={} Table=BAR{}+{:#x} PBA=BAR{}+{:#x}",
+                flag(msix.enabled), msix.table_size, msix.table_bar, msix.table_offset, msix.pba_bar, msix.pba_offset
+            ),
+            _ => {}
+        }
+    }
+}
+
+fn flag(set: bool) -> char {
+    if set { '+' } else { '-' }
+}
+
+pub fn execute(args: &[u8]) {
+    let verbose = core::str::from_utf8(args).unwrap_or("").trim() == "-v";
+    let devices = pci::devices();
+    if devices.is_empty() {
+        print!("\nNo PCI devices found");
+        return;
+    }
+    for dev in devices {
+        print!("\n{} {}: {} {:04x}:{:04x}", dev.address, pci::class_name(dev.class, dev.subclass),
+            pci::vendor_name(dev.vendor_id), dev.vendor_id, dev.device_id);
+        if let Some(driver) = pci::driver_for(dev.address) {
+            print!(" [{}]", driver);
+        }
+        if verbose {
+            print_details(dev);
+        }
+    }
+    print!("\n{} device(s) via {}", devices.len(), if pci::uses_ecam() { "ECAM" } else { "port I/O" });
+}
@@ -3,6 +3,20 @@ pub mod say;
 pub mod wait;
 pub mod fetch; 
 pub mod gpu; 
+pub mod exec;
+pub mod ls;
+pub mod cat;
+pub mod cd;
+pub mod pwd;
+pub mod touch;
+pub mod mkdir;
+pub mod rm;
+pub mod mv;
+pub mod write;
+pub mod mount;
+pub mod lspci;
+pub mod disks;
+pub mod mode;
 
 use crate::print;
 use crate::system::GLOBAL_CONSOLE;
@@ -57,6 +71,44 @@ pub fn delete_last_char() {
     }
 }
 
+pub fn split_command(cmd_line: &[u8]) -> (&[u8], &[u8]) {
+    let mut split_idx = cmd_line.len();
+    for (i, &b) in cmd_line.iter().enumerate() {
+        if b == b' ' { split_idx = i; break; }
+    }
+
+    let cmd_name = &cmd_line[..split_idx];
+    let args = if split_idx < cmd_line.len() { &cmd_line[split_idx + 1..] } else { b"" };
+    (cmd_name, args)
+}
+
+pub fn split_redirect(cmd_line: &[u8]) -> (&[u8], Option<(&[u8], bool)>) {
+    let Some(pos) = cmd_line.iter().position(|&b| b == b'>') else { return (cmd_line, None) };
+    let append = cmd_line.get(pos + 1) == Some(&b'>');
+    let target = &cmd_line[pos + if append { 2 } else { 1 }..];
+    let trim = |s: &[u8]| -> usize { s.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1) };
+    let start = target.iter().position(|&b| b != b' ').unwrap_or(target.len());
+    let target = &target[start..start + trim(&target[start..])];
+    let command = &cmd_line[..trim(&cmd_line[..pos])];
+    (command, Some((target, append)))
+}
+
+fn finish_redirect(target: &[u8], append: bool) {
+    let output = crate::system::end_capture().unwrap_or_default();
+    let Ok(path) = core::str::from_utf8(target) else { return };
+    if path.is_empty() {
+        print!("\nMissing redirection target");
+        return;
+    }
+    let mut text = alloc::string::String::from(output.strip_prefix('\n').unwrap_or(&output));
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    if let Err(e) = write::to_file(path, text.as_bytes(), append) {
+        print!("\n{}: {:?}", path, e);
+    }
+}
+
 pub fn process_command() {
     unsafe {
         if BUFFER_IDX == 0 { return; }
@@ -70,26 +122,78 @@ pub fn process_command() {
         }
         HISTORY_POS = -1;
 
-        let mut split_idx = BUFFER_IDX;
-        for (i, &b) in cmd_line.iter().enumerate() {
-            if b == b' ' { split_idx = i; break; }
+        let (cmd_line, redirect) = split_redirect(cmd_line);
+        if redirect.is_some() {
+            crate::system::start_capture();
         }
-
-        let cmd_name = &cmd_line[..split_idx];
-        let _args = if split_idx < BUFFER_IDX { &cmd_line[split_idx + 1..] } else { b"" };
+        let (cmd_name, _args) = split_command(cmd_line);
 
         match cmd_name {
             b"help"  => help::execute(),
             b"fetch" => fetch::execute(), 
-            b"gpu"   => gpu::execute(), 
-            b"clear" => { if let Some(ref mut c) = GLOBAL_CONSOLE { c.clear(0x000000); } },
+            b"gpu"   => gpu::execute(_args), 
+            b"clear" => { crate::system::with_console(|c| c.clear(0x000000)); },
             b"wait"  => wait::execute(_args),
             b"say"   => say::execute(_args),
+            b"exec"  => exec::execute(_args),
+            b"ls"    => ls::execute(_args),
+            b"cat"   => cat::execute(_args),
+            b"cd"    => cd::execute(_args),
+            b"pwd"   => pwd::execute(),
+            b"touch" => touch::execute(_args),
+            b"mkdir" => mkdir::execute(_args),
+            b"rm"    => rm::execute(_args),
+            b"mv"    => mv::execute(_args),
+            b"write" => write::execute(_args),
+            b"mount" => mount::execute(_args),
+            b"lspci" => lspci::execute(_args),
+            b"disks" => disks::execute(),
+            b"mode"  => mode::execute(_args),
             b"panic" => panic!("User requested panic test"),
             _ => print!("\nUnknown command"),
         }
+        if let Some((target, append)) = redirect {
+            finish_redirect(target, append);
+        }
 
         BUFFER_IDX = 0;
         for i in 0..64 { COMMAND_BUFFER[i] = 0; }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_command, split_redirect};
+
+    #[test_case]
+    fn split_name_only() {
+        let (name, args) = split_command(b"fetch");
+        assert_eq!(name, b"fetch");
+        assert_eq!(args, b"");
+    }
+
+    #[test_case]
+    fn split_name_and_args() {
+        let (name, args) = split_command(b"say hello world");
+        assert_eq!(name, b"say");
+        assert_eq!(args, b"hello world");
+    }
+
+    #[test_case]
+    fn split_redirect_target() {
+        let (cmd, redirect) = split_redirect(b"say hi > out.txt ");
+        assert_eq!(cmd, b"say hi");
+        assert_eq!(redirect, Some((&b"out.txt"[..], false)));
+        let (cmd, redirect) = split_redirect(b"ls>>log");
+        assert_eq!(cmd, b"ls");
+        assert_eq!(redirect, Some((&b"log"[..], true)));
+        assert_eq!(split_redirect(b"pwd"), (&b"pwd"[..], None));
+    }
+
+    #[test_case]
+    fn split_trailing_space() {
+        let (name, args) = split_command(b"wait ");
+        assert_eq!(name, b"wait");
+        assert_eq!(args, b"");
+    }
+}
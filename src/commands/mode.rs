@@ -0,0 +1,91 @@
+use x86_64::instructions::interrupts;
+use crate::system::GLOBAL_CONSOLE;
+use crate::system::console::Console;
+use crate::system::graphic::{Backend, GraphicBackend};
+use crate::system::memory::{with_mm, FRAME_SIZE};
+use crate::drivers::gpu_fb::{Framebuffer, VirtioGpu};
+use crate::drivers::uefi_fb;
+use crate::print;
+
+fn list(c: &Console) {
+    let modes = uefi_fb::modes();
+    if modes.is_empty() {
+        print!("\nNo GOP modes were captured at boot");
+    }
+    let current = uefi_fb::current_mode().map(|m| (m.width, m.height));
+    for (i, mode) in modes.iter().enumerate() {
+        let marker = if current == Some((mode.width, mode.height)) { " (boot)" } else { "" };
+        print!("\n{:>3}: {}x{} stride {} {}{}", i, mode.width, mode.height, mode.stride, mode.format, marker);
+    }
+    print!("\nActive: {}x{}", c.backend.width(), c.backend.height());
+}
+
+fn switch_native(fb: &mut Framebuffer, gpu: &VirtioGpu, width: usize, height: usize) -> Result<&'static str, &'static str> {
+    let frames = (width * height * 4 + FRAME_SIZE - 1) / FRAME_SIZE;
+    let back = with_mm(|mm| mm.alloc_frames(frames)).ok_or("no memory manager")?.ok_or("not enough memory")?;
+    let addr = match gpu.set_mode(width, height) {
+        Ok(addr) => addr,
+        Err(_) => {
+            with_mm(|mm| mm.free_region(back as usize, frames * FRAME_SIZE));
+            return Err("virtio-gpu rejected the mode");
+        }
+    };
+    if let Some(old) = fb.back_buffer.take() {
+        let bytes = (old.len() * 4 + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+        with_mm(|mm| mm.free_region(old.as_ptr() as usize, bytes));
+    }
+    fb.back_buffer = Some(unsafe { core::slice::from_raw_parts_mut(back as *mut u32, width * height) });
+    fb.fb_addr = addr;
+    fb.pitch = width;
+    fb.width = width;
+    fb.height = height;
+    Ok("virtio-gpu")
+}
+
+fn switch_virtual(backend: &Backend, width: usize, height: usize) -> Result<&'static str, &'static str> {
+    let Some(mode) = uefi_fb::current_mode() else { return Err("no GOP mode information") };
+    if width > mode.width || height > mode.height || width > backend.pitch() {
+        return Err("mode is larger than the GOP framebuffer and no native driver is active");
+    }
+    if let Backend::Gpu(Framebuffer { back_buffer: Some(back), .. }) = backend {
+        if width * height > back.len() {
+            return Err("mode is larger than the back buffer");
+        }
+    }
+    Ok("GOP framebuffer")
+}
+
+pub fn execute(args: &[u8]) {
+    let arg = core::str::from_utf8(args).unwrap_or("").trim();
+    let Some(c) = (unsafe { (*core::ptr::addr_of_mut!(GLOBAL_CONSOLE)).as_mut() }) else { return };
+    if arg.is_empty() {
+        list(c);
+        return;
+    }
+    let target = match arg.parse::<usize>() {
+        Ok(index) => uefi_fb::modes().get(index).map(|m| (m.width, m.height)),
+        Err(_) => uefi_fb::parse_resolution(arg),
+    };
+    let Some((width, height)) = target else {
+        print!("\nUsage: mode [index | WIDTHxHEIGHT]");
+        return;
+    };
+
+    let result = interrupts::without_interrupts(|| {
+        let result = match &mut c.backend {
+            Backend::Gpu(fb) => match fb.gpu {
+                Some(gpu) => switch_native(fb, gpu, width, height),
+                None => switch_virtual(&c.backend, width, height),
+            },
+            Backend::Uefi(_) => switch_virtual(&c.backend, width, height),
+        };
+        if result.is_ok() {
+            c.resize(width, height);
+        }
+        result
+    });
+    match result {
+        Ok(via) => print!("\nSwitched to {}x{} via {}", width, height, via),
+        Err(e) => print!("\nCannot switch to {}x{}: {}", width, height, e),
+    }
+}
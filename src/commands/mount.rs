@@ -0,0 +1,36 @@
+use alloc::sync::Arc;
+use crate::print;
+use crate::drivers::block;
+use crate::fs::fat32::Fat32Fs;
+use crate::fs::vfs;
+
+pub fn execute(args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or("").trim();
+    if args.is_empty() {
+        for (path, fs) in vfs::mounts() {
+            print!("\n{:<6} on {}", fs, path);
+        }
+        return;
+    }
+    let Some((device, path)) = args.split_once(' ') else {
+        print!("\nUsage: mount [device] [dir]");
+        return;
+    };
+    let path = path.trim();
+    let Some(dev) = block::find(device) else {
+        print!("\nmount: {}: no such device", device);
+        return;
+    };
+    let fs = match Fat32Fs::mount(dev) {
+        Ok(fs) => fs,
+        Err(e) => {
+            print!("\nmount: {}: {:?}", device, e);
+            return;
+        }
+    };
+    let free = fs.free_bytes();
+    match vfs::mount(&vfs::absolute(path), Arc::new(fs)) {
+        Ok(()) => print!("\nMounted {} on {} ({} KiB free)", device, vfs::absolute(path), free / 1024),
+        Err(e) => print!("\nmount: {}: {:?}", path, e),
+    }
+}
@@ -0,0 +1,21 @@
+use crate::print;
+use crate::fs::vfs::{self, FileType};
+
+pub fn execute(args: &[u8]) {
+    let line = core::str::from_utf8(args).unwrap_or("").trim();
+    let Some((from, to)) = line.split_once(' ').map(|(a, b)| (a, b.trim())) else {
+        print!("\nUsage: mv [from] [to]");
+        return;
+    };
+
+    let target = match vfs::stat(to) {
+        Ok(stat) if stat.kind == FileType::Directory => {
+            let name = from.trim_end_matches('/').rsplit('/').next().unwrap_or(from);
+            vfs::normalize(&vfs::absolute(to), name)
+        }
+        _ => alloc::string::String::from(to),
+    };
+    if let Err(e) = vfs::rename(from, &target) {
+        print!("\nmv: {}: {:?}", from, e);
+    }
+}
@@ -0,0 +1,6 @@
+use crate::print;
+use crate::fs::vfs;
+
+pub fn execute() {
+    print!("\n{}", vfs::cwd());
+}
@@ -0,0 +1,13 @@
+use crate::print;
+use crate::fs::vfs;
+
+pub fn execute(args: &[u8]) {
+    let path = core::str::from_utf8(args).unwrap_or("").trim();
+    if path.is_empty() {
+        print!("\nUsage: rm [path]");
+        return;
+    }
+    if let Err(e) = vfs::remove(path) {
+        print!("\nrm: {}: {:?}", path, e);
+    }
+}
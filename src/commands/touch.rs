@@ -0,0 +1,14 @@
+use crate::print;
+use crate::fs::vfs::{self, O_CREATE, O_WRITE};
+
+pub fn execute(args: &[u8]) {
+    let path = core::str::from_utf8(args).unwrap_or("").trim();
+    if path.is_empty() {
+        print!("\nUsage: touch [file]");
+        return;
+    }
+    match vfs::open(path, O_WRITE | O_CREATE) {
+        Ok(fd) => { let _ = vfs::close(fd); }
+        Err(e) => print!("\ntouch: {}: {:?}", path, e),
+    }
+}
@@ -1,5 +1,5 @@
 use crate::print;
-use crate::system::time;
+use crate::system::sched;
 
 pub fn execute(args: &[u8]) {
     if args.is_empty() {
@@ -19,7 +19,7 @@ pub fn execute(args: &[u8]) {
 
     if seconds > 0 {
         print!("\nWaiting for {} seconds...", seconds);
-        time::sleep(seconds);
+        sched::sleep_ms(seconds * 1000);
         print!("\nDone!");
     } else {
         print!("\nInvalid duration.");
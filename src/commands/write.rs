@@ -0,0 +1,25 @@
+use crate::print;
+use crate::fs::vfs::{self, FsError, O_APPEND, O_CREATE, O_TRUNC, O_WRITE};
+
+pub fn to_file(path: &str, data: &[u8], append: bool) -> Result<(), FsError> {
+    let mode = if append { O_APPEND } else { O_TRUNC };
+    let fd = vfs::open(path, O_WRITE | O_CREATE | mode)?;
+    let result = vfs::write(fd, data).map(|_| ());
+    let _ = vfs::close(fd);
+    result
+}
+
+pub fn execute(args: &[u8]) {
+    let line = core::str::from_utf8(args).unwrap_or("").trim();
+    let (path, text) = line.split_once(' ').unwrap_or((line, ""));
+    if path.is_empty() {
+        print!("\nUsage: write [file] [text]");
+        return;
+    }
+
+    let mut data = alloc::vec::Vec::from(text.as_bytes());
+    data.push(b'\n');
+    if let Err(e) = to_file(path, &data, false) {
+        print!("\nwrite: {}: {:?}", path, e);
+    }
+}
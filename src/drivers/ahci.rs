@@ -0,0 +1,357 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::drivers::block::{self, check_range, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::drivers::pci::{self, PciDevice, PciDriver, PciMatch};
+use crate::system::apic;
+use crate::system::memory::{with_mm, FRAME_SIZE};
+use crate::system::time;
+use crate::log;
+
+const MAX_CONTROLLERS: usize = 4;
+const MAX_PORTS: usize = 32;
+const BOUNCE_FRAMES: usize = 16;
+const MAX_SECTORS: usize = BOUNCE_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+const TIMEOUT_NS: u64 = 5_000_000_000;
+
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const HBA_CAP2: usize = 0x24;
+const HBA_BOHC: usize = 0x28;
+
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+
+const PX_CLB: usize = 0x00;
+const PX_FB: usize = 0x08;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const IS_DHRS: u32 = 1 << 0;
+const IS_TFES: u32 = 1 << 30;
+const TFD_BSY: u32 = 1 << 7;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_ERR: u32 = 1 << 0;
+
+const SIG_SATA: u32 = 0x0000_0101;
+const FIS_H2D: u8 = 0x27;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_IDENTIFY: u8 = 0xEC;
+
+const CLB_OFFSET: usize = 0;
+const FB_OFFSET: usize = 0x400;
+const TABLE_OFFSET: usize = 0x800;
+const PRDT_OFFSET: usize = 0x80;
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[PciMatch { class: Some(0x01), subclass: Some(0x06), prog_if: Some(0x01), ..PciMatch::ANY }],
+    probe,
+};
+
+static CONTROLLERS: [AtomicUsize; MAX_CONTROLLERS] = [const { AtomicUsize::new(0) }; MAX_CONTROLLERS];
+static PORT_STATUS: [[AtomicU32; MAX_PORTS]; MAX_CONTROLLERS] = [const { [const { AtomicU32::new(0) }; MAX_PORTS] }; MAX_CONTROLLERS];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read(base: usize, reg: usize) -> u32 {
+    core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+unsafe fn write(base: usize, reg: usize, value: u32) {
+    core::ptr::write_volatile((base + reg) as *mut u32, value);
+}
+
+fn wait_until(timeout_ns: u64, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = time::now_ns() + timeout_ns;
+    while !done() {
+        if time::now_ns() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn handle_interrupt() {
+    for (index, slot) in CONTROLLERS.iter().enumerate() {
+        let hba = slot.load(Ordering::Acquire);
+        if hba == 0 {
+            continue;
+        }
+        unsafe {
+            let pending = read(hba, HBA_IS);
+            for port in 0..MAX_PORTS {
+                if pending & (1 << port) == 0 {
+                    continue;
+                }
+                let regs = port_base(hba, port);
+                let status = read(regs, PX_IS);
+                write(regs, PX_IS, status);
+                PORT_STATUS[index][port].fetch_or(status, Ordering::AcqRel);
+            }
+            write(hba, HBA_IS, pending);
+        }
+    }
+}
+
+fn port_base(hba: usize, port: usize) -> usize {
+    hba + 0x100 + port * 0x80
+}
+
+pub struct AhciDisk {
+    name: String,
+    model: String,
+    controller: usize,
+    port: usize,
+    regs: usize,
+    mem: usize,
+    bounce: usize,
+    sectors: u64,
+    lock: Mutex<()>,
+}
+
+impl AhciDisk {
+    fn command(&self, command: u8, lba: u64, count: usize, write_data: bool) -> Result<(), BlockError> {
+        let bytes = count * SECTOR_SIZE;
+        unsafe {
+            let header = (self.mem + CLB_OFFSET) as *mut u32;
+            let flags = 5 | if write_data { 1 << 6 } else { 0 } | if bytes > 0 { 1 << 16 } else { 0 };
+            core::ptr::write_volatile(header, flags);
+            core::ptr::write_volatile(header.add(1), 0);
+
+            let table = self.mem + TABLE_OFFSET;
+            core::ptr::write_bytes(table as *mut u8, 0, PRDT_OFFSET + 16);
+            let fis = table as *mut u8;
+            let lba_bytes = lba.to_le_bytes();
+            *fis = FIS_H2D;
+            *fis.add(1) = 0x80;
+            *fis.add(2) = command;
+            for i in 0..3 {
+                *fis.add(4 + i) = lba_bytes[i];
+                *fis.add(8 + i) = lba_bytes[3 + i];
+            }
+            *fis.add(7) = if command == ATA_IDENTIFY { 0 } else { 1 << 6 };
+            *fis.add(12) = count as u8;
+            *fis.add(13) = (count >> 8) as u8;
+
+            if bytes > 0 {
+                let prd = (table + PRDT_OFFSET) as *mut u32;
+                core::ptr::write_volatile(prd, self.bounce as u32);
+                core::ptr::write_volatile(prd.add(1), (self.bounce as u64 >> 32) as u32);
+                core::ptr::write_volatile(prd.add(3), (bytes as u32 - 1) | 1 << 31);
+            }
+
+            let status = &PORT_STATUS[self.controller][self.port];
+            status.store(0, Ordering::Release);
+            if !wait_until(TIMEOUT_NS, || read(self.regs, PX_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+                return Err(BlockError::Io);
+            }
+            write(self.regs, PX_CI, 1);
+
+            let polling = !interrupts::are_enabled();
+            let done = wait_until(TIMEOUT_NS, || {
+                if status.load(Ordering::Acquire) & IS_TFES != 0 {
+                    return true;
+                }
+                if read(self.regs, PX_CI) & 1 == 0 {
+                    return true;
+                }
+                if !polling {
+                    x86_64::instructions::hlt();
+                }
+                false
+            });
+            let error = status.load(Ordering::Acquire) & IS_TFES != 0
+                || read(self.regs, PX_IS) & IS_TFES != 0
+                || read(self.regs, PX_TFD) & TFD_ERR != 0;
+            if !done || error {
+                write(self.regs, PX_IS, read(self.regs, PX_IS));
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, lba: u64, len: usize, mut chunk: impl FnMut(usize, &mut [u8]), write_data: bool) -> Result<(), BlockError> {
+        check_range(self, lba, len)?;
+        let _guard = self.lock.lock();
+        let mut done = 0;
+        while done < len {
+            let count = ((len - done) / SECTOR_SIZE).min(MAX_SECTORS);
+            let bytes = count * SECTOR_SIZE;
+            let bounce = unsafe { core::slice::from_raw_parts_mut(self.bounce as *mut u8, bytes) };
+            let sector = lba + (done / SECTOR_SIZE) as u64;
+            if write_data {
+                chunk(done, bounce);
+                self.command(ATA_WRITE_DMA_EXT, sector, count, true)?;
+            } else {
+                self.command(ATA_READ_DMA_EXT, sector, count, false)?;
+                chunk(done, bounce);
+            }
+            done += bytes;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let len = buf.len();
+        self.transfer(lba, len, |offset, bounce| {
+            buf[offset..offset + bounce.len()].copy_from_slice(bounce);
+        }, false)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.transfer(lba, buf.len(), |offset, bounce| {
+            bounce.copy_from_slice(&buf[offset..offset + bounce.len()]);
+        }, true)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let _guard = self.lock.lock();
+        self.command(ATA_FLUSH_CACHE_EXT, 0, 0, false)
+    }
+}
+
+fn identify_string(words: &[u16]) -> String {
+    let mut bytes = alloc::vec::Vec::with_capacity(words.len() * 2);
+    for w in words {
+        bytes.extend_from_slice(&w.to_be_bytes());
+    }
+    String::from(core::str::from_utf8(&bytes).unwrap_or("").trim())
+}
+
+unsafe fn stop_port(regs: usize) -> bool {
+    write(regs, PX_CMD, read(regs, PX_CMD) & !(CMD_ST | CMD_FRE));
+    wait_until(500_000_000, || read(regs, PX_CMD) & (CMD_CR | CMD_FR) == 0)
+}
+
+fn alloc_frames(count: usize) -> Option<usize> {
+    let frame = with_mm(|mm| mm.alloc_frames_below(count, 1 << 32))??;
+    unsafe { core::ptr::write_bytes(frame, 0, count * FRAME_SIZE); }
+    Some(frame as usize)
+}
+
+fn free_frames(frame: usize, count: usize) {
+    with_mm(|mm| mm.free_region(frame, count * FRAME_SIZE));
+}
+
+unsafe fn init_port(controller: usize, hba: usize, port: usize) -> Option<AhciDisk> {
+    let regs = port_base(hba, port);
+    let ssts = read(regs, PX_SSTS);
+    if ssts & 0xF != 3 || (ssts >> 8) & 0xF != 1 || read(regs, PX_SIG) != SIG_SATA {
+        return None;
+    }
+    if !stop_port(regs) {
+        return None;
+    }
+
+    let mem = alloc_frames(1)?;
+    let Some(bounce) = alloc_frames(BOUNCE_FRAMES) else {
+        free_frames(mem, 1);
+        return None;
+    };
+    let table = (mem + TABLE_OFFSET) as u64;
+    core::ptr::write_volatile((mem + CLB_OFFSET + 8) as *mut u32, table as u32);
+    core::ptr::write_volatile((mem + CLB_OFFSET + 12) as *mut u32, (table >> 32) as u32);
+    write(regs, PX_CLB, (mem + CLB_OFFSET) as u32);
+    write(regs, PX_CLB + 4, ((mem + CLB_OFFSET) as u64 >> 32) as u32);
+    write(regs, PX_FB, (mem + FB_OFFSET) as u32);
+    write(regs, PX_FB + 4, ((mem + FB_OFFSET) as u64 >> 32) as u32);
+    write(regs, PX_SERR, 0xFFFF_FFFF);
+    write(regs, PX_IS, 0xFFFF_FFFF);
+    write(regs, PX_CMD, read(regs, PX_CMD) | CMD_FRE);
+    write(regs, PX_CMD, read(regs, PX_CMD) | CMD_ST);
+    write(regs, PX_IE, IS_DHRS | IS_TFES);
+
+    let mut disk = AhciDisk {
+        name: String::new(),
+        model: String::new(),
+        controller,
+        port,
+        regs,
+        mem,
+        bounce,
+        sectors: 0,
+        lock: Mutex::new(()),
+    };
+    if disk.command(ATA_IDENTIFY, 0, 1, false).is_err() {
+        stop_port(regs);
+        free_frames(mem, 1);
+        free_frames(bounce, BOUNCE_FRAMES);
+        return None;
+    }
+    let words = core::slice::from_raw_parts(bounce as *const u16, 256);
+    let lba48 = words[100] as u64 | (words[101] as u64) << 16 | (words[102] as u64) << 32 | (words[103] as u64) << 48;
+    let lba28 = words[60] as u64 | (words[61] as u64) << 16;
+    disk.sectors = if words[83] & (1 << 10) != 0 && lba48 != 0 { lba48 } else { lba28 };
+    disk.model = identify_string(&words[27..47]);
+    disk.name = alloc::format!("ahci{}", DISK_COUNT.fetch_add(1, Ordering::SeqCst));
+    Some(disk)
+}
+
+fn probe(dev: &PciDevice) -> bool {
+    let Some(controller) = CONTROLLERS.iter().position(|c| c.load(Ordering::Acquire) == 0) else { return false };
+    let Some(hba) = pci::map_bar(dev, 5) else { return false };
+    pci::enable_bus_master(dev);
+
+    unsafe {
+        if read(hba, HBA_CAP2) & 1 != 0 {
+            write(hba, HBA_BOHC, read(hba, HBA_BOHC) | 1 << 1);
+            wait_until(100_000_000, || read(hba, HBA_BOHC) & 1 == 0);
+        }
+        write(hba, HBA_GHC, read(hba, HBA_GHC) | GHC_AE);
+    }
+    CONTROLLERS[controller].store(hba, Ordering::Release);
+
+    let msi = dev.msi.is_some().then(|| apic::register_msi(handle_interrupt).ok()).flatten();
+    let irq = match msi {
+        Some(vector) if pci::enable_msi(dev, vector) => "MSI",
+        _ if (1..=4).contains(&dev.interrupt_pin) && apic::register_irq(dev.interrupt_line, handle_interrupt).is_ok() => "INTx",
+        _ => "polling",
+    };
+    let (ports, slots) = unsafe {
+        write(hba, HBA_IS, 0xFFFF_FFFF);
+        write(hba, HBA_GHC, read(hba, HBA_GHC) | GHC_IE);
+        (read(hba, HBA_PI), ((read(hba, HBA_CAP) >> 8) & 0x1F) + 1)
+    };
+
+    for port in (0..MAX_PORTS).filter(|p| ports & (1 << p) != 0) {
+        let Some(disk) = (unsafe { init_port(controller, hba, port) }) else { continue };
+        log!("OK", "{}: {} ({} MiB) on {} port {}, {} slots, {}", disk.name, disk.model,
+            disk.sectors * SECTOR_SIZE as u64 / (1024 * 1024), dev.address, port, slots, irq);
+        block::register(Arc::new(disk));
+    }
+    true
+}
@@ -0,0 +1,308 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MBR_PROTECTIVE: u8 = 0xEE;
+const GPT_HEADER_MIN: usize = 92;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockError {
+    OutOfRange,
+    BadBuffer,
+    Io,
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+    fn block_count(&self) -> u64;
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn model(&self) -> &str {
+        ""
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+pub fn check_range(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let size = dev.block_size();
+    if len % size != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= dev.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+pub struct RamDisk {
+    name: String,
+    data: Mutex<&'static mut [u8]>,
+}
+
+impl RamDisk {
+    pub fn new(name: &str, data: &'static mut [u8]) -> Self {
+        RamDisk { name: String::from(name), data: Mutex::new(data) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+pub struct Partition {
+    name: String,
+    parent: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn block_size(&self) -> usize {
+        self.parent.block_size()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        self.parent.read_blocks(self.start + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        self.parent.write_blocks(self.start + lba, buf)
+    }
+
+    fn model(&self) -> &str {
+        self.parent.model()
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.parent.flush()
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+pub fn register(dev: Arc<dyn BlockDevice>) -> usize {
+    let partitions = scan_partitions(&dev);
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        devices.push(dev);
+        devices.extend(partitions.iter().cloned());
+    });
+    partitions.len()
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| DEVICES.lock().iter().find(|d| d.name() == name).cloned())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn partition(dev: &Arc<dyn BlockDevice>, index: usize, start: u64, count: u64) -> Option<Arc<dyn BlockDevice>> {
+    if count == 0 || start.checked_add(count)? > dev.block_count() {
+        return None;
+    }
+    let name = alloc::format!("{}p{}", dev.name(), index);
+    Some(Arc::new(Partition { name, parent: dev.clone(), start, count }))
+}
+
+pub fn scan_partitions(dev: &Arc<dyn BlockDevice>) -> Vec<Arc<dyn BlockDevice>> {
+    let size = dev.block_size();
+    let mut mbr = alloc::vec![0u8; size];
+    if size < SECTOR_SIZE || dev.read_blocks(0, &mut mbr).is_err() || mbr[510..512] != [0x55, 0xAA] {
+        return Vec::new();
+    }
+
+    if &mbr[82..87] == b"FAT32" || &mbr[54..57] == b"FAT" {
+        return Vec::new();
+    }
+
+    let raw: Vec<&[u8]> = (0..4).map(|i| &mbr[446 + i * 16..462 + i * 16]).collect();
+    if raw.iter().any(|e| e[0] != 0 && e[0] != 0x80) {
+        return Vec::new();
+    }
+    let entries: Vec<(u8, u64, u64)> = raw.iter()
+        .map(|e| (e[4], read_u32(e, 8) as u64, read_u32(e, 12) as u64))
+        .collect();
+    if entries.iter().any(|e| e.0 == MBR_PROTECTIVE) {
+        return scan_gpt(dev);
+    }
+    entries.iter().enumerate()
+        .filter(|(_, e)| e.0 != 0)
+        .filter_map(|(i, e)| partition(dev, i + 1, e.1, e.2))
+        .collect()
+}
+
+fn scan_gpt(dev: &Arc<dyn BlockDevice>) -> Vec<Arc<dyn BlockDevice>> {
+    let size = dev.block_size();
+    let mut header = alloc::vec![0u8; size];
+    if dev.read_blocks(1, &mut header).is_err() || &header[0..8] != GPT_SIGNATURE {
+        return Vec::new();
+    }
+    let header_size = read_u32(&header, 12) as usize;
+    if header_size < GPT_HEADER_MIN || header_size > size {
+        return Vec::new();
+    }
+    let header_crc = read_u32(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Vec::new();
+    }
+
+    let table_lba = read_u64(&header, 72);
+    let count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    if !entry_size.is_power_of_two() || !(128..=512).contains(&entry_size) || count > 1024 {
+        return Vec::new();
+    }
+
+    let Some(bytes) = count.checked_mul(entry_size) else { return Vec::new() };
+    let blocks = (bytes + size - 1) / size;
+    let mut table = alloc::vec![0u8; blocks * size];
+    if dev.read_blocks(table_lba, &mut table).is_err() || crc32(&table[..bytes]) != read_u32(&header, 88) {
+        return Vec::new();
+    }
+    (0..count)
+        .map(|i| &table[i * entry_size..(i + 1) * entry_size])
+        .enumerate()
+        .filter(|(_, e)| e[0..16].iter().any(|&b| b != 0))
+        .filter_map(|(i, e)| {
+            let first = read_u64(e, 32);
+            let last = read_u64(e, 40);
+            partition(dev, i + 1, first, last.checked_sub(first)? + 1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ramdisk_rejects_out_of_range() {
+        let disk = RamDisk::new("ram-test", alloc::vec![0u8; 4 * SECTOR_SIZE].leak());
+        let mut buf = [0u8; SECTOR_SIZE * 2];
+        assert_eq!(disk.read_blocks(3, &mut buf), Err(BlockError::OutOfRange));
+        assert_eq!(disk.read_blocks(0, &mut buf[..100]), Err(BlockError::BadBuffer));
+        buf[0] = 0x42;
+        disk.write_blocks(2, &buf).unwrap();
+        let mut out = [0u8; SECTOR_SIZE];
+        disk.read_blocks(2, &mut out).unwrap();
+        assert_eq!(out[0], 0x42);
+    }
+
+    #[test_case]
+    fn finds_mbr_partitions() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("ram-mbr", alloc::vec![0u8; 64 * SECTOR_SIZE].leak()));
+        let mut mbr = [0u8; SECTOR_SIZE];
+        mbr[446 + 4] = 0x0C;
+        mbr[446 + 8..446 + 12].copy_from_slice(&8u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&32u32.to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        disk.write_blocks(0, &mbr).unwrap();
+
+        let parts = scan_partitions(&disk);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].name(), "ram-mbrp1");
+        assert_eq!(parts[0].block_count(), 32);
+    }
+
+    #[test_case]
+    fn gpt_header_crc_is_checked() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("ram-gpt", alloc::vec![0u8; 64 * SECTOR_SIZE].leak()));
+        let mut mbr = [0u8; SECTOR_SIZE];
+        mbr[446 + 4] = MBR_PROTECTIVE;
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        disk.write_blocks(0, &mbr).unwrap();
+
+        let mut table = [0u8; SECTOR_SIZE];
+        table[0] = 1;
+        table[32..40].copy_from_slice(&34u64.to_le_bytes());
+        table[40..48].copy_from_slice(&41u64.to_le_bytes());
+        disk.write_blocks(2, &table).unwrap();
+
+        let mut header = [0u8; SECTOR_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_MIN as u32).to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&table).to_le_bytes());
+        let crc = crc32(&header[..GPT_HEADER_MIN]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(1, &header).unwrap();
+
+        let parts = scan_partitions(&disk);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].block_count(), 8);
+
+        header[72] = 3;
+        disk.write_blocks(1, &header).unwrap();
+        assert!(scan_partitions(&disk).is_empty());
+    }
+}
@@ -1,6 +1,232 @@
 #[allow(dead_code)]
 use core::ptr;
+use core::cell::Cell;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
 use crate::assets::{FONT, PSF1_MAGIC, PSF2_MAGIC, Psf1Header, Psf2Header};
+use crate::drivers::pci::{PciDevice, PciDriver, PciMatch};
+use crate::drivers::virtio::{self, Buffer, VirtioDevice, VirtioError, Virtqueue};
+use crate::system::memory::{with_mm, FRAME_SIZE};
+use crate::log;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const FORMAT_B8G8R8X8: u32 = 2;
+const HEADER_SIZE: usize = 24;
+const RESPONSE_OFFSET: usize = 2048;
+const MAX_SCANOUTS: usize = 16;
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-gpu",
+    matches: &[PciMatch { vendor_id: Some(virtio::VENDOR_ID), device_id: Some(0x1050), ..PciMatch::ANY }],
+    probe,
+};
+
+static GPU: Once<VirtioGpu> = Once::new();
+
+#[derive(Clone, Copy)]
+pub enum GpuError {
+    NoDisplay,
+    OutOfMemory,
+    Command(u32),
+    Transport(VirtioError),
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpuError::NoDisplay => write!(f, "no display attached"),
+            GpuError::OutOfMemory => write!(f, "out of memory"),
+            GpuError::Command(response) => write!(f, "command failed with response {:#x}", response),
+            GpuError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Scanout {
+    resource: u32,
+    addr: usize,
+    width: usize,
+    height: usize,
+}
+
+struct Control {
+    queue: Virtqueue,
+    buffer: usize,
+    next_resource: u32,
+    scanout: Option<Scanout>,
+}
+
+pub struct VirtioGpu {
+    device: VirtioDevice,
+    control: Mutex<Control>,
+}
+
+impl VirtioGpu {
+    fn command(&self, control: &mut Control, kind: u32, body: &[u32], response_len: usize) -> Result<(), GpuError> {
+        let Control { queue, buffer, .. } = control;
+        let request = *buffer as *mut u32;
+        unsafe {
+            ptr::write_bytes(*buffer as *mut u8, 0, RESPONSE_OFFSET + response_len);
+            *request = kind;
+            ptr::copy_nonoverlapping(body.as_ptr(), request.add(HEADER_SIZE / 4), body.len());
+        }
+        let buffers = [
+            Buffer::to_device(*buffer, HEADER_SIZE + body.len() * 4),
+            Buffer::from_device(*buffer + RESPONSE_OFFSET, response_len),
+        ];
+        self.device.transact(queue, &buffers).map_err(GpuError::Transport)?;
+        let response = unsafe { ptr::read_volatile((*buffer + RESPONSE_OFFSET) as *const u32) };
+        let expected = if kind == CMD_GET_DISPLAY_INFO { RESP_OK_DISPLAY_INFO } else { RESP_OK_NODATA };
+        if response != expected {
+            return Err(GpuError::Command(response));
+        }
+        Ok(())
+    }
+
+    pub fn display_size(&self) -> Result<(usize, usize), GpuError> {
+        interrupts::without_interrupts(|| {
+            let mut control = self.control.lock();
+            self.command(&mut control, CMD_GET_DISPLAY_INFO, &[], HEADER_SIZE + MAX_SCANOUTS * 24)?;
+            let info = (control.buffer + RESPONSE_OFFSET + HEADER_SIZE) as *const u32;
+            let (width, height, enabled) = unsafe { (*info.add(2), *info.add(3), *info.add(4)) };
+            if enabled == 0 || width == 0 || height == 0 {
+                return Err(GpuError::NoDisplay);
+            }
+            Ok((width as usize, height as usize))
+        })
+    }
+
+    pub fn set_mode(&self, width: usize, height: usize) -> Result<*mut u32, GpuError> {
+        let bytes = width * height * 4;
+        let frames = (bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+        let addr = with_mm(|mm| mm.alloc_frames(frames)).flatten().ok_or(GpuError::OutOfMemory)? as usize;
+        unsafe { ptr::write_bytes(addr as *mut u8, 0, frames * FRAME_SIZE); }
+
+        interrupts::without_interrupts(|| {
+            let mut control = self.control.lock();
+            let resource = control.next_resource;
+            control.next_resource += 1;
+            let (w, h) = (width as u32, height as u32);
+            let result = (|| {
+                self.command(&mut control, CMD_RESOURCE_CREATE_2D, &[resource, FORMAT_B8G8R8X8, w, h], HEADER_SIZE)?;
+                let backing = [resource, 1, addr as u32, (addr >> 32) as u32, bytes as u32, 0];
+                self.command(&mut control, CMD_RESOURCE_ATTACH_BACKING, &backing, HEADER_SIZE)?;
+                self.command(&mut control, CMD_SET_SCANOUT, &[0, 0, w, h, 0, resource], HEADER_SIZE)
+            })();
+            if let Err(e) = result {
+                self.release(&mut control, Scanout { resource, addr, width, height });
+                return Err(e);
+            }
+            if let Some(old) = control.scanout.replace(Scanout { resource, addr, width, height }) {
+                self.release(&mut control, old);
+            }
+            Ok(addr as *mut u32)
+        })
+    }
+
+    fn release(&self, control: &mut Control, scanout: Scanout) {
+        let _ = self.command(control, CMD_RESOURCE_DETACH_BACKING, &[scanout.resource, 0], HEADER_SIZE);
+        let _ = self.command(control, CMD_RESOURCE_UNREF, &[scanout.resource, 0], HEADER_SIZE);
+        let frames = (scanout.width * scanout.height * 4 + FRAME_SIZE - 1) / FRAME_SIZE;
+        with_mm(|mm| mm.free_region(scanout.addr, frames * FRAME_SIZE));
+    }
+
+    pub fn flush(&self, x: usize, y: usize, w: usize, h: usize) {
+        interrupts::without_interrupts(|| {
+            let mut control = self.control.lock();
+            let Some(scanout) = control.scanout else { return };
+            if x >= scanout.width || y >= scanout.height {
+                return;
+            }
+            let (w, h) = (w.min(scanout.width - x), h.min(scanout.height - y));
+            let offset = ((y * scanout.width + x) * 4) as u64;
+            let rect = [x as u32, y as u32, w as u32, h as u32];
+            let transfer = [rect[0], rect[1], rect[2], rect[3], offset as u32, (offset >> 32) as u32, scanout.resource, 0];
+            let _ = self.command(&mut control, CMD_TRANSFER_TO_HOST_2D, &transfer, HEADER_SIZE);
+            let flush = [rect[0], rect[1], rect[2], rect[3], scanout.resource, 0];
+            let _ = self.command(&mut control, CMD_RESOURCE_FLUSH, &flush, HEADER_SIZE);
+        });
+    }
+}
+
+fn init(dev: &PciDevice) -> Result<VirtioGpu, VirtioError> {
+    let mut device = VirtioDevice::new(dev)?;
+    let setup = (|| {
+        device.negotiate(0)?;
+        Ok((device.setup_queue(0)?, virtio::alloc_frames(1)?))
+    })();
+    let (queue, buffer) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            device.fail();
+            return Err(e);
+        }
+    };
+    device.finish();
+    Ok(VirtioGpu { device, control: Mutex::new(Control { queue, buffer, next_resource: 1, scanout: None }) })
+}
+
+fn probe(dev: &PciDevice) -> bool {
+    if GPU.get().is_some() {
+        return false;
+    }
+    match init(dev) {
+        Ok(gpu) => {
+            log!("OK", "virtio-gpu at {}, {}", dev.address, gpu.device.irq);
+            GPU.call_once(|| gpu);
+            true
+        }
+        Err(e) => {
+            log!("WARN", "virtio-gpu at {} failed: {}", dev.address, e);
+            false
+        }
+    }
+}
+
+pub fn device() -> Option<&'static VirtioGpu> {
+    GPU.get()
+}
+
+pub struct DirtyRegion {
+    bounds: Cell<Option<(usize, usize, usize, usize)>>,
+}
+
+impl DirtyRegion {
+    pub const fn new() -> Self {
+        DirtyRegion { bounds: Cell::new(None) }
+    }
+
+    pub fn mark(&self, x: usize, y: usize, w: usize, h: usize) {
+        if w == 0 || h == 0 { return; }
+        let (x1, y1) = (x + w, y + h);
+        self.bounds.set(Some(match self.bounds.get() {
+            Some((x0, y0, x2, y2)) => (x0.min(x), y0.min(y), x2.max(x1), y2.max(y1)),
+            None => (x, y, x1, y1),
+        }));
+    }
+
+    pub fn take_within(&self, x: usize, y: usize, w: usize, h: usize) -> Option<(usize, usize, usize, usize)> {
+        let (x0, y0, x1, y1) = self.bounds.get()?;
+        let clipped = (x0.max(x), y0.max(y), x1.min(x + w), y1.min(y + h));
+        if clipped.0 >= clipped.2 || clipped.1 >= clipped.3 { return None; }
+        if clipped == (x0, y0, x1, y1) {
+            self.bounds.set(None);
+        }
+        Some((clipped.0, clipped.1, clipped.2 - clipped.0, clipped.3 - clipped.1))
+    }
+}
 
 pub struct Framebuffer {
     pub fb_addr: *mut u32,
@@ -8,36 +234,78 @@ pub struct Framebuffer {
     pub width: usize,
     pub height: usize,
     pub pitch: usize,
+    pub gpu: Option<&'static VirtioGpu>,
+    pub dirty: DirtyRegion,
 }
 
 impl Framebuffer {
     #[inline(always)]
     fn buffer_ptr(&self) -> *mut u32 {
-        self.fb_addr
+        match &self.back_buffer {
+            Some(back) => back.as_ptr() as *mut u32,
+            None => self.fb_addr,
+        }
+    }
+
+    #[inline(always)]
+    fn stride(&self) -> usize {
+        if self.back_buffer.is_some() { self.width } else { self.pitch }
+    }
+
+    fn mark(&self, x: usize, y: usize, w: usize, h: usize) {
+        if x < self.width && y < self.height {
+            self.dirty.mark(x, y, w.min(self.width - x), h.min(self.height - y));
+        }
     }
 
     #[inline(always)]
     pub fn draw_pixel(&self, x: usize, y: usize, color: u32) {
         if x < self.width && y < self.height {
             unsafe {
-                *self.fb_addr.add(y * self.pitch + x) = color;
+                *self.buffer_ptr().add(y * self.stride() + x) = color;
             }
+            self.dirty.mark(x, y, 1, 1);
         }
     }
 
     pub fn clear(&self, color: u32) {
-        let buf = self.fb_addr;
-        let total = self.pitch * self.height;
+        let total = self.stride() * self.height;
         if total == 0 { return; }
         unsafe {
-            for i in 0..total {
-                *buf.add(i) = color;
+            core::slice::from_raw_parts_mut(self.buffer_ptr(), total).fill(color);
+        }
+        self.mark(0, 0, self.width, self.height);
+    }
+
+    fn present(&self, x: usize, y: usize, w: usize, h: usize) {
+        if self.back_buffer.is_some() {
+            let back = self.buffer_ptr();
+            unsafe {
+                if x == 0 && w == self.width && self.pitch == self.width {
+                    ptr::copy_nonoverlapping(back.add(y * self.width), self.fb_addr.add(y * self.pitch), w * h);
+                } else {
+                    for row in y..y + h {
+                        ptr::copy_nonoverlapping(back.add(row * self.width + x), self.fb_addr.add(row * self.pitch + x), w);
+                    }
+                }
             }
         }
+        if let Some(gpu) = self.gpu {
+            gpu.flush(x, y, w, h);
+        }
     }
 
-    pub fn swap_buffers(&self) {}
-    pub fn swap_rect(&self, _x: usize, _y: usize, _w: usize, _h: usize) {}
+    pub fn swap_buffers(&self) {
+        if let Some((x, y, w, h)) = self.dirty.take_within(0, 0, self.width, self.height) {
+            self.present(x, y, w, h);
+        }
+    }
+
+    pub fn swap_rect(&self, x: usize, y: usize, w: usize, h: usize) {
+        if let Some((x, y, w, h)) = self.dirty.take_within(x, y, w, h) {
+            self.present(x, y, w, h);
+        }
+    }
 
     pub fn draw_char(&self, c: char, x: usize, y: usize, color: u32) {
         self.draw_char_ex(c, x, y, color, None);
@@ -46,7 +314,8 @@ impl Framebuffer {
     pub fn draw_char_ex(&self, c: char, x: usize, y: usize, color: u32, bg: Option<u32>) {
         if x + 8 >= self.width || y + 16 >= self.height { return; }
         let glyph = c as usize;
-        let buf = self.fb_addr;
+        let buf = self.buffer_ptr();
+        let stride = self.stride();
         let bg_color = bg.unwrap_or(0);
 
         unsafe {
@@ -61,9 +330,10 @@ impl Framebuffer {
                     for i in 0..8 {
                         row[i] = if (byte << i) & 0x80 != 0 { color } else { bg_color };
                     }
-                    let dst = buf.add((y + r) * self.pitch + x);
+                    let dst = buf.add((y + r) * stride + x);
                     ptr::copy_nonoverlapping(row.as_ptr(), dst, 8);
                 }
+                self.mark(x, y, 8, glyph_size);
             }
 
             if FONT.starts_with(&PSF2_MAGIC) {
@@ -88,9 +358,10 @@ impl Framebuffer {
                             }
                         }
                     }
-                    let dst = buf.add((y + r) * self.pitch + x);
+                    let dst = buf.add((y + r) * stride + x);
                     ptr::copy_nonoverlapping(row.as_ptr(), dst, width);
                 }
+                self.mark(x, y, width, height);
             }
         }
     }
@@ -98,15 +369,56 @@ impl Framebuffer {
     pub fn scroll(&self, lines: usize, char_h: usize, bg: Option<u32>) {
         let px = lines * char_h;
         if px == 0 || px >= self.height { return; }
-        let buf = self.fb_addr;
-        let total = self.pitch * self.height;
-        let keep = (self.height - px) * self.pitch;
+        let buf = self.buffer_ptr();
+        let stride = self.stride();
+        let total = stride * self.height;
+        let keep = (self.height - px) * stride;
         unsafe {
-            ptr::copy(buf.add(px * self.pitch), buf, keep);
-            let color = bg.unwrap_or(0);
-            for i in keep..total {
-                *buf.add(i) = color;
-            }
+            ptr::copy(buf.add(px * stride), buf, keep);
+            core::slice::from_raw_parts_mut(buf.add(keep), total - keep).fill(bg.unwrap_or(0));
         }
+        self.mark(0, 0, self.width, self.height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirtyRegion, Framebuffer};
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 32;
+
+    #[test_case]
+    fn dirty_region_grows_and_clips() {
+        let dirty = DirtyRegion::new();
+        dirty.mark(10, 4, 8, 16);
+        dirty.mark(2, 8, 4, 2);
+        assert_eq!(dirty.take_within(0, 0, 6, 10), Some((2, 4, 4, 6)));
+        assert_eq!(dirty.take_within(0, 0, WIDTH, HEIGHT), Some((2, 4, 16, 16)));
+        assert_eq!(dirty.take_within(0, 0, WIDTH, HEIGHT), None);
     }
-}
\ No newline at end of file
+
+    #[test_case]
+    fn drawing_stays_in_back_buffer_until_swapped() {
+        let front = alloc::vec![0u32; WIDTH * HEIGHT].leak();
+        let back = alloc::vec![0u32; WIDTH * HEIGHT].leak();
+        let fb = Framebuffer {
+            fb_addr: front.as_mut_ptr(),
+            back_buffer: Some(back),
+            width: WIDTH,
+            height: HEIGHT,
+            pitch: WIDTH,
+            gpu: None,
+            dirty: DirtyRegion::new(),
+        };
+        fb.draw_pixel(5, 3, 0xFFFFFF);
+        fb.draw_pixel(40, 20, 0x00FF00);
+        let front = unsafe { core::slice::from_raw_parts(fb.fb_addr, WIDTH * HEIGHT) };
+        assert_eq!(front[3 * WIDTH + 5], 0);
+        fb.swap_rect(0, 0, 8, 8);
+        assert_eq!(front[3 * WIDTH + 5], 0xFFFFFF);
+        assert_eq!(front[20 * WIDTH + 40], 0);
+        fb.swap_buffers();
+        assert_eq!(front[20 * WIDTH + 40], 0x00FF00);
+    }
+}
@@ -67,6 +67,11 @@ impl Keyboard {
         }
     }
 
+    pub fn handle_interrupt() {
+        let scancode = unsafe { Self::read_scancode() };
+        Self::handle_scancode(scancode);
+    }
+
     pub unsafe fn read_scancode() -> u8 {
         let scancode: u8;
         core::arch::asm!("in al, dx", out("al") scancode, in("dx") 0x60u16);
@@ -1,3 +1,10 @@
 pub mod uefi_fb;
 pub mod keyboard;
-pub mod gpu_fb;
\ No newline at end of file
+pub mod gpu_fb;
+pub mod serial;
+pub mod block;
+pub mod pci;
+pub mod ahci;
+pub mod nvme;
+pub mod virtio;
+pub mod virtio_blk;
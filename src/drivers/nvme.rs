@@ -0,0 +1,448 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::drivers::block::{self, check_range, BlockDevice, BlockError};
+use crate::drivers::pci::{self, PciDevice, PciDriver, PciMatch};
+use crate::system::apic;
+use crate::system::memory::{with_mm, FRAME_SIZE};
+use crate::system::time;
+use crate::log;
+
+const MAX_CONTROLLERS: usize = 4;
+const QUEUE_DEPTH: u16 = 64;
+const BOUNCE_FRAMES: usize = 16;
+const IO_TIMEOUT_NS: u64 = 5_000_000_000;
+
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+const CC_QUEUE_SIZES: u32 = 6 << 16 | 4 << 20;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "nvme",
+    matches: &[PciMatch { class: Some(0x01), subclass: Some(0x08), prog_if: Some(0x02), ..PciMatch::ANY }],
+    probe,
+};
+
+static COMPLETIONS: [AtomicBool; MAX_CONTROLLERS] = [const { AtomicBool::new(false) }; MAX_CONTROLLERS];
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+pub enum NvmeError {
+    Timeout,
+    Fatal,
+    Command(u16),
+    OutOfMemory,
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NvmeError::Timeout => write!(f, "timed out"),
+            NvmeError::Fatal => write!(f, "controller fatal status"),
+            NvmeError::Command(code) => write!(f, "command failed with status {:#x}", code),
+            NvmeError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+impl From<NvmeError> for BlockError {
+    fn from(_: NvmeError) -> Self {
+        BlockError::Io
+    }
+}
+
+struct Queue {
+    id: u16,
+    sq: usize,
+    cq: usize,
+    depth: u16,
+    tail: u16,
+    head: u16,
+    phase: bool,
+    next_id: u16,
+}
+
+struct Command {
+    opcode: u8,
+    nsid: u32,
+    prp1: u64,
+    prp2: u64,
+    cdw: [u32; 6],
+}
+
+impl Command {
+    fn new(opcode: u8, nsid: u32, prp1: usize, cdw: &[u32]) -> Self {
+        let mut words = [0; 6];
+        words[..cdw.len()].copy_from_slice(cdw);
+        Command { opcode, nsid, prp1: prp1 as u64, prp2: 0, cdw: words }
+    }
+}
+
+struct IoState {
+    queue: Queue,
+    bounce: usize,
+    prp_list: usize,
+}
+
+pub struct Controller {
+    index: usize,
+    regs: usize,
+    stride: usize,
+    timeout_ns: u64,
+    interrupts: bool,
+    admin: Mutex<Queue>,
+    io: Mutex<IoState>,
+    model: String,
+    serial: String,
+}
+
+pub struct Namespace {
+    name: String,
+    nsid: u32,
+    blocks: u64,
+    block_size: usize,
+    controller: Arc<Controller>,
+}
+
+fn handle_interrupt() {
+    for flag in COMPLETIONS.iter() {
+        flag.store(true, Ordering::Release);
+    }
+}
+
+fn alloc_frames(allocated: &mut Vec<(usize, usize)>, count: usize) -> Result<usize, NvmeError> {
+    let frame = with_mm(|mm| mm.alloc_frames(count)).flatten().ok_or(NvmeError::OutOfMemory)?;
+    allocated.push((frame as usize, count));
+    unsafe { core::ptr::write_bytes(frame, 0, count * FRAME_SIZE); }
+    Ok(frame as usize)
+}
+
+unsafe fn read32(base: usize, reg: usize) -> u32 {
+    core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+unsafe fn write32(base: usize, reg: usize, value: u32) {
+    core::ptr::write_volatile((base + reg) as *mut u32, value);
+}
+
+unsafe fn write64(base: usize, reg: usize, value: u64) {
+    write32(base, reg, value as u32);
+    write32(base, reg + 4, (value >> 32) as u32);
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    String::from(core::str::from_utf8(bytes).unwrap_or("").trim_matches(|c: char| c == ' ' || c == '\0'))
+}
+
+impl Queue {
+    fn new(allocated: &mut Vec<(usize, usize)>, id: u16, depth: u16) -> Result<Self, NvmeError> {
+        let sq = alloc_frames(allocated, 1)?;
+        let cq = alloc_frames(allocated, 1)?;
+        Ok(Queue { id, sq, cq, depth, tail: 0, head: 0, phase: true, next_id: 0 })
+    }
+}
+
+impl Controller {
+    fn doorbell(&self, queue: u16, completion: bool) -> usize {
+        DOORBELLS + (2 * queue as usize + completion as usize) * self.stride
+    }
+
+    fn submit(&self, queue: &mut Queue, command: &Command, timeout_ns: u64) -> Result<u32, NvmeError> {
+        let id = queue.next_id;
+        queue.next_id = queue.next_id.wrapping_add(1);
+        unsafe {
+            let entry = (queue.sq + queue.tail as usize * 64) as *mut u32;
+            core::ptr::write_bytes(entry, 0, 16);
+            *entry = command.opcode as u32 | (id as u32) << 16;
+            *entry.add(1) = command.nsid;
+            *(entry.add(6) as *mut u64) = command.prp1;
+            *(entry.add(8) as *mut u64) = command.prp2;
+            for (i, &word) in command.cdw.iter().enumerate() {
+                *entry.add(10 + i) = word;
+            }
+            queue.tail = (queue.tail + 1) % queue.depth;
+            COMPLETIONS[self.index].store(false, Ordering::Release);
+            write32(self.regs, self.doorbell(queue.id, false), queue.tail as u32);
+        }
+
+        let deadline = time::now_ns() + timeout_ns;
+        let halt = self.interrupts && interrupts::are_enabled();
+        loop {
+            let entry = (queue.cq + queue.head as usize * 16) as *const u32;
+            let status = unsafe { core::ptr::read_volatile(entry.add(3)) };
+            if (status >> 16) & 1 == queue.phase as u32 {
+                let result = unsafe { core::ptr::read_volatile(entry) };
+                queue.head = (queue.head + 1) % queue.depth;
+                if queue.head == 0 {
+                    queue.phase = !queue.phase;
+                }
+                unsafe { write32(self.regs, self.doorbell(queue.id, true), queue.head as u32); }
+                if (status & 0xFFFF) as u16 != id {
+                    continue;
+                }
+                let code = ((status >> 17) & 0x7FF) as u16;
+                return if code == 0 { Ok(result) } else { Err(NvmeError::Command(code)) };
+            }
+            if unsafe { read32(self.regs, REG_CSTS) } & CSTS_FATAL != 0 {
+                return Err(NvmeError::Fatal);
+            }
+            if time::now_ns() > deadline {
+                return Err(NvmeError::Timeout);
+            }
+            if halt && !COMPLETIONS[self.index].load(Ordering::Acquire) {
+                x86_64::instructions::hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn admin(&self, command: Command) -> Result<u32, NvmeError> {
+        self.submit(&mut self.admin.lock(), &command, self.timeout_ns)
+    }
+
+    fn identify(&self, cns: u32, nsid: u32, buf: usize) -> Result<(), NvmeError> {
+        self.admin(Command::new(ADMIN_IDENTIFY, nsid, buf, &[cns])).map(|_| ())
+    }
+
+    fn io(&self, state: &mut IoState, opcode: u8, nsid: u32, lba: u64, blocks: usize, bytes: usize) -> Result<(), NvmeError> {
+        let pages = (bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+        let mut command = Command::new(opcode, nsid, state.bounce, &[lba as u32, (lba >> 32) as u32, blocks.saturating_sub(1) as u32]);
+        if pages == 2 {
+            command.prp2 = (state.bounce + FRAME_SIZE) as u64;
+        } else if pages > 2 {
+            let list = state.prp_list as *mut u64;
+            for page in 1..pages {
+                unsafe { *list.add(page - 1) = (state.bounce + page * FRAME_SIZE) as u64; }
+            }
+            command.prp2 = state.prp_list as u64;
+        }
+        self.submit(&mut state.queue, &command, IO_TIMEOUT_NS).map(|_| ())
+    }
+}
+
+impl Namespace {
+    fn transfer(&self, lba: u64, len: usize, mut chunk: impl FnMut(usize, &mut [u8]), write: bool) -> Result<(), BlockError> {
+        check_range(self, lba, len)?;
+        let max_blocks = BOUNCE_FRAMES * FRAME_SIZE / self.block_size;
+        let controller = &self.controller;
+        let mut state = controller.io.lock();
+        let mut done = 0;
+        while done < len {
+            let blocks = ((len - done) / self.block_size).min(max_blocks);
+            let bytes = blocks * self.block_size;
+            let sector = lba + (done / self.block_size) as u64;
+            let data = unsafe { core::slice::from_raw_parts_mut(state.bounce as *mut u8, bytes) };
+            if write {
+                chunk(done, data);
+                controller.io(&mut state, IO_WRITE, self.nsid, sector, blocks, bytes)?;
+            } else {
+                controller.io(&mut state, IO_READ, self.nsid, sector, blocks, bytes)?;
+                chunk(done, data);
+            }
+            done += bytes;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.controller.model
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let len = buf.len();
+        self.transfer(lba, len, |offset, data| buf[offset..offset + data.len()].copy_from_slice(data), false)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.transfer(lba, buf.len(), |offset, data| data.copy_from_slice(&buf[offset..offset + data.len()]), true)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut state = self.controller.io.lock();
+        Ok(self.controller.io(&mut state, IO_FLUSH, self.nsid, 0, 0, 0)?)
+    }
+}
+
+fn wait_ready(regs: usize, ready: bool, timeout_ns: u64) -> Result<(), NvmeError> {
+    let deadline = time::now_ns() + timeout_ns;
+    loop {
+        let status = unsafe { read32(regs, REG_CSTS) };
+        if status & CSTS_FATAL != 0 && ready {
+            return Err(NvmeError::Fatal);
+        }
+        if (status & CSTS_READY != 0) == ready {
+            return Ok(());
+        }
+        if time::now_ns() > deadline {
+            return Err(NvmeError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn init_controller(dev: &PciDevice, index: usize, regs: usize, vector: Option<u8>) -> Result<(Arc<Controller>, Vec<Namespace>), NvmeError> {
+    let cap = unsafe { read32(regs, REG_CAP) as u64 | (read32(regs, REG_CAP + 4) as u64) << 32 };
+    let timeout_ns = ((cap >> 24) & 0xFF).max(1) * 500_000_000;
+
+    unsafe {
+        write32(regs, REG_CC, read32(regs, REG_CC) & !CC_ENABLE);
+    }
+    wait_ready(regs, false, timeout_ns)?;
+
+    let mut allocated = Vec::new();
+    let result = setup_controller(dev, index, regs, vector, cap, &mut allocated);
+    if result.is_err() {
+        unsafe { write32(regs, REG_CC, read32(regs, REG_CC) & !CC_ENABLE); }
+        let _ = wait_ready(regs, false, timeout_ns);
+        with_mm(|mm| {
+            for &(frame, count) in allocated.iter() {
+                mm.free_region(frame, count * FRAME_SIZE);
+            }
+        });
+    }
+    result
+}
+
+fn setup_controller(dev: &PciDevice, index: usize, regs: usize, vector: Option<u8>, cap: u64, allocated: &mut Vec<(usize, usize)>)
+    -> Result<(Arc<Controller>, Vec<Namespace>), NvmeError> {
+    let depth = QUEUE_DEPTH.min((cap & 0xFFFF) as u16 + 1);
+    let stride = 4 << ((cap >> 32) & 0xF);
+    let timeout_ns = ((cap >> 24) & 0xFF).max(1) * 500_000_000;
+    let admin = Queue::new(allocated, 0, depth)?;
+    unsafe {
+        write32(regs, REG_AQA, (depth as u32 - 1) << 16 | (depth as u32 - 1));
+        write64(regs, REG_ASQ, admin.sq as u64);
+        write64(regs, REG_ACQ, admin.cq as u64);
+        write32(regs, REG_CC, CC_QUEUE_SIZES | CC_ENABLE);
+    }
+    wait_ready(regs, true, timeout_ns)?;
+
+    let controller = Controller {
+        index,
+        regs,
+        stride,
+        timeout_ns,
+        interrupts: vector.is_some(),
+        admin: Mutex::new(admin),
+        io: Mutex::new(IoState { queue: Queue::new(allocated, 1, depth)?, bounce: alloc_frames(allocated, BOUNCE_FRAMES)?, prp_list: alloc_frames(allocated, 1)? }),
+        model: String::new(),
+        serial: String::new(),
+    };
+
+    let page = alloc_frames(allocated, 1)?;
+    let identify = unsafe { core::slice::from_raw_parts(page as *const u8, FRAME_SIZE) };
+    controller.identify(CNS_CONTROLLER, 0, page)?;
+    let model = ascii(&identify[24..64]);
+    let serial = ascii(&identify[4..24]);
+
+    let (sq, cq) = {
+        let io = controller.io.lock();
+        (io.queue.sq, io.queue.cq)
+    };
+    let interrupt = if vector.is_some() { 1 << 1 } else { 0 };
+    controller.admin(Command::new(ADMIN_CREATE_CQ, 0, cq, &[(depth as u32 - 1) << 16 | 1, interrupt | 1]))?;
+    controller.admin(Command::new(ADMIN_CREATE_SQ, 0, sq, &[(depth as u32 - 1) << 16 | 1, 1 << 16 | 1]))?;
+
+    controller.identify(CNS_ACTIVE_NAMESPACES, 0, page)?;
+    let ids: Vec<u32> = identify.chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .take_while(|&id| id != 0)
+        .collect();
+
+    let controller = Arc::new(Controller { model, serial, ..controller });
+    let mut namespaces = Vec::new();
+    for nsid in ids {
+        controller.identify(CNS_NAMESPACE, nsid, page)?;
+        let blocks = u64::from_le_bytes(identify[0..8].try_into().unwrap());
+        let format = (identify[26] & 0xF) as usize;
+        let lbads = identify[128 + format * 4 + 2];
+        if blocks == 0 || !(9..=12).contains(&lbads) {
+            continue;
+        }
+        namespaces.push(Namespace {
+            name: alloc::format!("nvme{}n{}", index, nsid),
+            nsid,
+            blocks,
+            block_size: 1 << lbads,
+            controller: controller.clone(),
+        });
+    }
+    with_mm(|mm| mm.free_frame(page as *mut u8));
+    allocated.clear();
+    log!("OK", "nvme{}: {} (serial {}) at {}, {} namespace(s)", index, controller.model, controller.serial,
+        dev.address, namespaces.len());
+    Ok((controller, namespaces))
+}
+
+fn probe(dev: &PciDevice) -> bool {
+    let index = CONTROLLER_COUNT.load(Ordering::Acquire);
+    if index >= MAX_CONTROLLERS {
+        return false;
+    }
+    let Some(regs) = pci::map_bar(dev, 0) else { return false };
+    pci::enable_bus_master(dev);
+
+    let vector = match (dev.msix.is_some() || dev.msi.is_some()).then(|| apic::register_msi(handle_interrupt).ok()).flatten() {
+        Some(v) if !pci::enable_msix(dev, 0, v) && !pci::enable_msi(dev, v) => {
+            apic::unregister_msi(v);
+            None
+        }
+        v => v,
+    };
+    match init_controller(dev, index, regs, vector) {
+        Ok((_, namespaces)) => {
+            CONTROLLER_COUNT.store(index + 1, Ordering::Release);
+            for ns in namespaces {
+                log!("OK", "{}: {} MiB, {}-byte blocks", ns.name, ns.blocks * ns.block_size as u64 / (1024 * 1024), ns.block_size);
+                block::register(Arc::new(ns));
+            }
+            true
+        }
+        Err(e) => {
+            if let Some(v) = vector {
+                apic::unregister_msi(v);
+            }
+            log!("WARN", "NVMe controller at {} failed: {}", dev.address, e);
+            false
+        }
+    }
+}
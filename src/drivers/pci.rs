@@ -0,0 +1,547 @@
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::system::acpi::McfgEntry;
+use crate::system::apic;
+use crate::system::paging::{NO_CACHE, NO_EXECUTE, WRITABLE, WRITE_THROUGH};
+use crate::KERNEL_SPACE;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const REG_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER: u16 = 0x0C;
+const REG_BAR0: u16 = 0x10;
+const REG_BUS_NUMBERS: u16 = 0x18;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT: u16 = 0x3C;
+
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+const MSI_ADDRESS: u32 = 0xFEE0_0000;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_MSIX: u8 = 0x11;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool, wide: bool },
+    Io { port: u32, size: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    pub offset: u16,
+    pub wide: bool,
+    pub per_vector_mask: bool,
+    pub vectors: u8,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsiX {
+    pub offset: u16,
+    pub table_size: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+}
+
+#[derive(Clone, Copy)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    pub const ANY: PciMatch = PciMatch { vendor_id: None, device_id: None, class: None, subclass: None, prog_if: None };
+
+    pub fn matches(&self, dev: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |v| v == dev.vendor_id)
+            && self.device_id.map_or(true, |d| d == dev.device_id)
+            && self.class.map_or(true, |c| c == dev.class)
+            && self.subclass.map_or(true, |s| s == dev.subclass)
+            && self.prog_if.map_or(true, |p| p == dev.prog_if)
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+enum Access {
+    Legacy,
+    Ecam(Vec<McfgEntry>),
+}
+
+struct Pci {
+    access: Access,
+    devices: Vec<PciDevice>,
+}
+
+static PCI: Once<Pci> = Once::new();
+static LEGACY_LOCK: Mutex<()> = Mutex::new(());
+static BOUND: Mutex<Vec<(PciAddress, &'static str)>> = Mutex::new(Vec::new());
+
+impl Access {
+    fn ecam_address(&self, addr: PciAddress, offset: u16) -> Option<usize> {
+        let Access::Ecam(entries) = self else { return None };
+        let entry = entries.iter().find(|e| {
+            e.segment == addr.segment && addr.bus >= e.start_bus && addr.bus <= e.end_bus
+        })?;
+        let bus = (addr.bus - entry.start_bus) as usize;
+        Some(entry.base_address as usize + (bus << 20 | (addr.device as usize) << 15 | (addr.function as usize) << 12) + offset as usize)
+    }
+
+    fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        let offset = offset & !3;
+        if let Some(ptr) = self.ecam_address(addr, offset) {
+            return unsafe { core::ptr::read_volatile(ptr as *const u32) };
+        }
+        if addr.segment != 0 || offset >= 0x100 {
+            return 0xFFFF_FFFF;
+        }
+        interrupts::without_interrupts(|| {
+            let _guard = LEGACY_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(addr, offset));
+                Port::<u32>::new(CONFIG_DATA).read()
+            }
+        })
+    }
+
+    fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        let offset = offset & !3;
+        if let Some(ptr) = self.ecam_address(addr, offset) {
+            unsafe { core::ptr::write_volatile(ptr as *mut u32, value); }
+            return;
+        }
+        if addr.segment != 0 || offset >= 0x100 {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            let _guard = LEGACY_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(addr, offset));
+                Port::<u32>::new(CONFIG_DATA).write(value);
+            }
+        })
+    }
+
+    fn segments(&self) -> Vec<(u16, u8, u8)> {
+        match self {
+            Access::Legacy => alloc::vec![(0, 0, 255)],
+            Access::Ecam(entries) => entries.iter().map(|e| (e.segment, e.start_bus, e.end_bus)).collect(),
+        }
+    }
+}
+
+fn legacy_address(addr: PciAddress, offset: u16) -> u32 {
+    1 << 31 | (addr.bus as u32) << 16 | (addr.device as u32) << 11 | (addr.function as u32) << 8 | offset as u32
+}
+
+fn probe_bars(access: &Access, addr: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = access.read(addr, REG_COMMAND);
+    access.write(addr, REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY) & 0xFFFF);
+
+    let mut index = 0;
+    while index < count {
+        let reg = REG_BAR0 + index as u16 * 4;
+        let original = access.read(addr, reg);
+        access.write(addr, reg, 0xFFFF_FFFF);
+        let mask = access.read(addr, reg);
+        access.write(addr, reg, original);
+
+        if original & 1 == 1 {
+            let size = !(mask & 0xFFFF_FFFC) & 0xFFFF;
+            if mask != 0 && mask != 0xFFFF_FFFF {
+                bars[index] = Some(Bar::Io { port: original & 0xFFFF_FFFC, size: size.wrapping_add(1) });
+            }
+            index += 1;
+            continue;
+        }
+
+        let wide = (original >> 1) & 3 == 2 && index + 1 < count;
+        let mut address = (original & 0xFFFF_FFF0) as u64;
+        let mut size_mask = (mask & 0xFFFF_FFF0) as u64 | 0xFFFF_FFFF_0000_0000;
+        if wide {
+            let high_reg = reg + 4;
+            let high = access.read(addr, high_reg);
+            access.write(addr, high_reg, 0xFFFF_FFFF);
+            let high_mask = access.read(addr, high_reg);
+            access.write(addr, high_reg, high);
+            address |= (high as u64) << 32;
+            size_mask = (size_mask & 0xFFFF_FFFF) | (high_mask as u64) << 32;
+        }
+        if mask & 0xFFFF_FFF0 != 0 || (wide && size_mask >> 32 != 0) {
+            bars[index] = Some(Bar::Memory {
+                address,
+                size: (!size_mask).wrapping_add(1),
+                prefetchable: original & 0x8 != 0,
+                wide,
+            });
+        }
+        index += if wide { 2 } else { 1 };
+    }
+
+    access.write(addr, REG_COMMAND, command & 0xFFFF);
+    bars
+}
+
+fn read_capabilities(access: &Access, addr: PciAddress) -> Vec<Capability> {
+    let mut caps = Vec::new();
+    if access.read(addr, REG_COMMAND) & STATUS_CAPABILITIES == 0 {
+        return caps;
+    }
+    let mut offset = (access.read(addr, REG_CAPABILITIES) & 0xFC) as u16;
+    while offset >= 0x40 && caps.len() < 48 {
+        let header = access.read(addr, offset);
+        caps.push(Capability { id: header as u8, offset });
+        offset = ((header >> 8) & 0xFC) as u16;
+    }
+    caps
+}
+
+fn decode_msi(access: &Access, addr: PciAddress, offset: u16) -> Msi {
+    let control = access.read(addr, offset) >> 16;
+    Msi {
+        offset,
+        wide: control & (1 << 7) != 0,
+        per_vector_mask: control & (1 << 8) != 0,
+        vectors: 1 << ((control >> 1) & 7).min(5),
+        enabled: control & 1 != 0,
+    }
+}
+
+fn decode_msix(access: &Access, addr: PciAddress, offset: u16) -> MsiX {
+    let control = access.read(addr, offset) >> 16;
+    let table = access.read(addr, offset + 4);
+    let pba = access.read(addr, offset + 8);
+    MsiX {
+        offset,
+        table_size: (control & 0x7FF) as u16 + 1,
+        table_bar: (table & 7) as u8,
+        table_offset: table & !7,
+        pba_bar: (pba & 7) as u8,
+        pba_offset: pba & !7,
+        enabled: control & (1 << 15) != 0,
+    }
+}
+
+fn read_device(access: &Access, addr: PciAddress) -> Option<PciDevice> {
+    let id = access.read(addr, REG_ID);
+    if id & 0xFFFF == 0xFFFF {
+        return None;
+    }
+    let class = access.read(addr, REG_CLASS);
+    let header_type = (access.read(addr, REG_HEADER) >> 16) as u8;
+    let interrupt = access.read(addr, REG_INTERRUPT);
+    let bar_count = match header_type & 0x7F {
+        0 => 6,
+        1 => 2,
+        _ => 0,
+    };
+    let capabilities = read_capabilities(access, addr);
+    let find = |id| capabilities.iter().find(|c| c.id == id).map(|c| c.offset);
+
+    Some(PciDevice {
+        address: addr,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+        bars: probe_bars(access, addr, bar_count),
+        msi: find(CAP_MSI).map(|o| decode_msi(access, addr, o)),
+        msix: find(CAP_MSIX).map(|o| decode_msix(access, addr, o)),
+        capabilities,
+    })
+}
+
+fn enumerate(access: &Access) -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for (segment, start, end) in access.segments() {
+        for bus in start..=end {
+            for device in 0..32 {
+                let addr = PciAddress { segment, bus, device, function: 0 };
+                let Some(first) = read_device(access, addr) else { continue };
+                let functions = if first.header_type & 0x80 != 0 { 8 } else { 1 };
+                devices.push(first);
+                for function in 1..functions {
+                    if let Some(dev) = read_device(access, PciAddress { function, ..addr }) {
+                        devices.push(dev);
+                    }
+                }
+            }
+        }
+    }
+    devices
+}
+
+pub fn init(mcfg: &[McfgEntry]) -> usize {
+    let pci = PCI.call_once(|| {
+        let mut entries = Vec::new();
+        if let Some(space) = KERNEL_SPACE.get() {
+            let mut space = space.lock();
+            for entry in mcfg {
+                let len = ((entry.end_bus as usize - entry.start_bus as usize) + 1) << 20;
+                let base = entry.base_address as usize;
+                if space.identity_map(base, len, WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH).is_ok() {
+                    entries.push(*entry);
+                }
+            }
+        }
+        let access = if entries.is_empty() { Access::Legacy } else { Access::Ecam(entries) };
+        let devices = enumerate(&access);
+        Pci { access, devices }
+    });
+    pci.devices.len()
+}
+
+pub fn uses_ecam() -> bool {
+    matches!(PCI.get().map(|p| &p.access), Some(Access::Ecam(_)))
+}
+
+pub fn devices() -> &'static [PciDevice] {
+    PCI.get().map(|p| p.devices.as_slice()).unwrap_or(&[])
+}
+
+pub fn read_config(addr: PciAddress, offset: u16) -> u32 {
+    PCI.get().map_or(0xFFFF_FFFF, |p| p.access.read(addr, offset))
+}
+
+pub fn write_config(addr: PciAddress, offset: u16, value: u32) {
+    if let Some(pci) = PCI.get() {
+        pci.access.write(addr, offset, value);
+    }
+}
+
+pub fn enable_bus_master(dev: &PciDevice) {
+    let command = read_config(dev.address, REG_COMMAND) & 0xFFFF;
+    write_config(dev.address, REG_COMMAND, command | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+}
+
+pub fn map_bar(dev: &PciDevice, index: usize) -> Option<usize> {
+    let Some(Bar::Memory { address, size, .. }) = dev.bars.get(index).copied().flatten() else { return None };
+    let mut space = KERNEL_SPACE.get()?.lock();
+    space.identity_map(address as usize, size as usize, WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH).ok()?;
+    Some(address as usize)
+}
+
+pub fn enable_msi(dev: &PciDevice, vector: u8) -> bool {
+    let Some(msi) = dev.msi else { return false };
+    let addr = dev.address;
+    let control = read_config(addr, msi.offset) >> 16;
+    let data_offset = if msi.wide { msi.offset + 12 } else { msi.offset + 8 };
+    write_config(addr, msi.offset + 4, MSI_ADDRESS | (apic::lapic_id() as u32) << 12);
+    if msi.wide {
+        write_config(addr, msi.offset + 8, 0);
+    }
+    let data = read_config(addr, data_offset) & 0xFFFF_0000;
+    write_config(addr, data_offset, data | vector as u32);
+    let control = (control & !(7 << 4)) | 1;
+    write_config(addr, msi.offset, read_config(addr, msi.offset) & 0xFFFF | control << 16);
+    let command = read_config(addr, REG_COMMAND) & 0xFFFF;
+    write_config(addr, REG_COMMAND, command | COMMAND_INTX_DISABLE);
+    true
+}
+
+pub fn enable_msix(dev: &PciDevice, entry: u16, vector: u8) -> bool {
+    let Some(msix) = dev.msix else { return false };
+    if entry >= msix.table_size {
+        return false;
+    }
+    let Some(bar) = map_bar(dev, msix.table_bar as usize) else { return false };
+    let addr = dev.address;
+    let control = read_config(addr, msix.offset);
+    write_config(addr, msix.offset, control | 1 << 31 | 1 << 30);
+    unsafe {
+        let slot = (bar + msix.table_offset as usize + entry as usize * 16) as *mut u32;
+        core::ptr::write_volatile(slot, MSI_ADDRESS | (apic::lapic_id() as u32) << 12);
+        core::ptr::write_volatile(slot.add(1), 0);
+        core::ptr::write_volatile(slot.add(2), vector as u32);
+        core::ptr::write_volatile(slot.add(3), 0);
+    }
+    write_config(addr, msix.offset, (control | 1 << 31) & !(1 << 30));
+    let command = read_config(addr, REG_COMMAND) & 0xFFFF;
+    write_config(addr, REG_COMMAND, command | COMMAND_INTX_DISABLE);
+    true
+}
+
+pub fn secondary_bus(dev: &PciDevice) -> Option<u8> {
+    if dev.header_type & 0x7F != 1 {
+        return None;
+    }
+    Some((read_config(dev.address, REG_BUS_NUMBERS) >> 8) as u8)
+}
+
+pub fn driver_for(addr: PciAddress) -> Option<&'static str> {
+    interrupts::without_interrupts(|| BOUND.lock().iter().find(|(a, _)| *a == addr).map(|&(_, name)| name))
+}
+
+pub fn register_driver(driver: &'static PciDriver) -> usize {
+    let mut bound = 0;
+    for dev in devices() {
+        if driver_for(dev.address).is_some() || !driver.matches.iter().any(|m| m.matches(dev)) {
+            continue;
+        }
+        if (driver.probe)(dev) {
+            interrupts::without_interrupts(|| BOUND.lock().push((dev.address, driver.name)));
+            bound += 1;
+        }
+    }
+    bound
+}
+
+pub fn vendor_name(vendor: u16) -> &'static str {
+    match vendor {
+        0x8086 => "Intel",
+        0x1022 => "AMD",
+        0x10DE => "NVIDIA",
+        0x1002 => "ATI",
+        0x1AF4 => "Red Hat (virtio)",
+        0x1B36 => "Red Hat",
+        0x1234 => "QEMU",
+        0x15AD => "VMware",
+        0x80EE => "VirtualBox",
+        0x144D => "Samsung",
+        _ => "Unknown vendor",
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Storage controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0xFF, _) => "Unassigned class",
+        _ => "Unclassified device",
+    }
+}
+
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x05 => "MSI",
+        0x09 => "Vendor Specific",
+        0x0D => "Bridge Subsystem",
+        0x10 => "PCI Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(class: u8, subclass: u8) -> PciDevice {
+        PciDevice {
+            address: PciAddress { segment: 0, bus: 0, device: 3, function: 1 },
+            vendor_id: 0x1AF4,
+            device_id: 0x1050,
+            class,
+            subclass,
+            prog_if: 0,
+            revision: 1,
+            header_type: 0,
+            interrupt_line: 11,
+            interrupt_pin: 1,
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            msi: None,
+            msix: None,
+        }
+    }
+
+    #[test_case]
+    fn match_fields_are_optional() {
+        let dev = device(0x03, 0x00);
+        assert!(PciMatch::ANY.matches(&dev));
+        assert!(PciMatch { vendor_id: Some(0x1AF4), device_id: Some(0x1050), ..PciMatch::ANY }.matches(&dev));
+        assert!(PciMatch { class: Some(0x03), subclass: Some(0x00), ..PciMatch::ANY }.matches(&dev));
+        assert!(!PciMatch { class: Some(0x01), ..PciMatch::ANY }.matches(&dev));
+        assert_eq!(alloc::format!("{}", dev.address), "0000:00:03.1");
+    }
+
+    #[test_case]
+    fn enumerates_host_bridge() {
+        let bridge = devices().iter().find(|d| d.address.bus == 0 && d.address.device == 0);
+        assert!(bridge.map_or(false, |d| d.class == 0x06 && d.subclass == 0x00));
+    }
+}
@@ -0,0 +1,124 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+use crate::system;
+
+pub const COM1: u16 = 0x3F8;
+pub const COM1_IRQ: u8 = 4;
+
+static SERIAL_READY: AtomicBool = AtomicBool::new(false);
+static ESCAPE_STATE: AtomicU8 = AtomicU8::new(0);
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    unsafe fn outb(&self, offset: u16, val: u8) {
+        Port::<u8>::new(self.base + offset).write(val);
+    }
+
+    unsafe fn inb(&self, offset: u16) -> u8 {
+        Port::<u8>::new(self.base + offset).read()
+    }
+
+    pub unsafe fn init(&self, baud: u32) -> bool {
+        let divisor = (115200 / baud.clamp(1, 115200)) as u16;
+
+        self.outb(1, 0x00);
+        self.outb(3, 0x80);
+        self.outb(0, (divisor & 0xFF) as u8);
+        self.outb(1, (divisor >> 8) as u8);
+        self.outb(3, 0x03);
+        self.outb(2, 0xC7);
+        self.outb(4, 0x1E);
+
+        self.outb(0, 0xAE);
+        if self.inb(0) != 0xAE {
+            return false;
+        }
+
+        self.outb(4, 0x0F);
+        self.outb(1, 0x01);
+        true
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        unsafe {
+            while self.inb(5) & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            self.outb(0, byte);
+        }
+    }
+
+    pub fn read_byte(&self) -> Option<u8> {
+        unsafe {
+            if self.inb(5) & 0x01 != 0 { Some(self.inb(0)) } else { None }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
+
+pub fn init(baud: u32) -> bool {
+    let ok = unsafe { SerialPort::new(COM1).init(baud) };
+    SERIAL_READY.store(ok, Ordering::SeqCst);
+    ok
+}
+
+pub fn write_fmt(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if SERIAL_READY.load(Ordering::Relaxed) {
+        let _ = SerialPort::new(COM1).write_fmt(args);
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::drivers::serial::write_fmt(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+pub fn write_str(s: &str) {
+    write_fmt(format_args!("{}", s));
+}
+
+fn handle_byte(byte: u8) {
+    match (ESCAPE_STATE.load(Ordering::Relaxed), byte) {
+        (0, 0x1B) => ESCAPE_STATE.store(1, Ordering::Relaxed),
+        (1, b'[') => ESCAPE_STATE.store(2, Ordering::Relaxed),
+        (2, b'A') => { ESCAPE_STATE.store(0, Ordering::Relaxed); system::push_key('\x11'); }
+        (2, b'B') => { ESCAPE_STATE.store(0, Ordering::Relaxed); system::push_key('\x12'); }
+        (1, _) | (2, _) => ESCAPE_STATE.store(0, Ordering::Relaxed),
+        (_, b'\r') | (_, b'\n') => system::push_key('\n'),
+        (_, 0x7F) | (_, 0x08) => system::push_key('\x08'),
+        (_, b) => system::push_key(b as char),
+    }
+}
+
+pub fn handle_interrupt() {
+    let port = SerialPort::new(COM1);
+    while let Some(byte) = port.read_byte() {
+        handle_byte(byte);
+    }
+}
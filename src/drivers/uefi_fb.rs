@@ -1,4 +1,95 @@
 use crate::assets::{FONT, PSF1_MAGIC, PSF2_MAGIC, Psf1Header, Psf2Header};
+use crate::drivers::gpu_fb;
+use crate::drivers::pci::{self, Bar, PciDevice, PciDriver, PciMatch};
+use crate::system::graphic::GraphicBackend;
+use crate::system::GLOBAL_CONSOLE;
+use spin::Once;
+
+pub const MAX_MODES: usize = 64;
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "gop",
+    matches: &[PciMatch { class: Some(0x03), ..PciMatch::ANY }],
+    probe: owns_framebuffer,
+};
+
+fn owns_framebuffer(dev: &PciDevice) -> bool {
+    let console = unsafe { (*core::ptr::addr_of!(GLOBAL_CONSOLE)).as_ref() };
+    let Some(fb) = console.map(|c| c.backend.addr() as u64) else { return false };
+    dev.bars.iter().flatten().any(|bar| match *bar {
+        Bar::Memory { address, size, .. } => fb >= address && fb < address + size,
+        Bar::Io { .. } => false,
+    })
+}
+
+pub fn display_device() -> Option<&'static PciDevice> {
+    let displays = || pci::devices().iter().filter(|d| d.class == 0x03);
+    let bound = |name| name == PCI_DRIVER.name || name == gpu_fb::PCI_DRIVER.name;
+    displays().find(|d| pci::driver_for(d.address).is_some_and(bound)).or_else(|| displays().next())
+}
+
+#[derive(Clone, Copy)]
+pub struct GopMode {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: &'static str,
+}
+
+pub struct GopModes {
+    modes: [GopMode; MAX_MODES],
+    count: usize,
+    pub current: Option<usize>,
+}
+
+impl GopModes {
+    pub const fn new() -> Self {
+        GopModes { modes: [GopMode { width: 0, height: 0, stride: 0, format: "" }; MAX_MODES], count: 0, current: None }
+    }
+
+    pub fn push(&mut self, mode: GopMode) {
+        if self.count < MAX_MODES {
+            self.modes[self.count] = mode;
+            self.count += 1;
+        }
+    }
+
+    pub fn as_slice(&self) -> &[GopMode] {
+        &self.modes[..self.count]
+    }
+
+    pub fn find(&self, width: usize, height: usize) -> Option<usize> {
+        self.as_slice().iter().position(|m| m.width == width && m.height == height && m.format != "blt")
+    }
+
+    pub fn largest(&self) -> Option<usize> {
+        self.as_slice().iter().enumerate()
+            .filter(|(_, m)| m.format != "blt")
+            .max_by_key(|(_, m)| m.width * m.height)
+            .map(|(i, _)| i)
+    }
+}
+
+static GOP_MODES: Once<GopModes> = Once::new();
+
+pub fn set_modes(modes: GopModes) {
+    GOP_MODES.call_once(|| modes);
+}
+
+pub fn modes() -> &'static [GopMode] {
+    GOP_MODES.get().map(|m| m.as_slice()).unwrap_or(&[])
+}
+
+pub fn current_mode() -> Option<GopMode> {
+    let list = GOP_MODES.get()?;
+    list.current.map(|i| list.modes[i])
+}
+
+pub fn parse_resolution(s: &str) -> Option<(usize, usize)> {
+    let (w, h) = s.trim().split_once('x')?;
+    let (w, h) = (w.parse().ok()?, h.parse().ok()?);
+    (w >= 320 && h >= 200).then_some((w, h))
+}
 
 pub struct Framebuffer {
     pub addr: *mut u32,
@@ -0,0 +1,464 @@
+use core::fmt;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use crate::drivers::pci::{self, PciDevice};
+use crate::system::apic;
+use crate::system::memory::{with_mm, FRAME_SIZE};
+use crate::system::time;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const MAX_DEVICES: usize = 8;
+const MAX_QUEUE_SIZE: u16 = 128;
+const TIMEOUT_NS: u64 = 5_000_000_000;
+const NO_VECTOR: u16 = 0xFFFF;
+
+const CAP_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_NEEDS_RESET: u8 = 64;
+const STATUS_FAILED: u8 = 128;
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+const AVAIL_OFFSET: usize = 16 * MAX_QUEUE_SIZE as usize;
+
+static ISR_REGS: [AtomicUsize; MAX_DEVICES] = [const { AtomicUsize::new(0) }; MAX_DEVICES];
+static PENDING: [AtomicBool; MAX_DEVICES] = [const { AtomicBool::new(false) }; MAX_DEVICES];
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+pub enum VirtioError {
+    MissingCapability,
+    FeaturesRejected,
+    QueueUnavailable,
+    OutOfMemory,
+    Timeout,
+    NeedsReset,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtioError::MissingCapability => write!(f, "no modern virtio capabilities"),
+            VirtioError::FeaturesRejected => write!(f, "features rejected"),
+            VirtioError::QueueUnavailable => write!(f, "queue unavailable"),
+            VirtioError::OutOfMemory => write!(f, "out of memory"),
+            VirtioError::Timeout => write!(f, "timed out"),
+            VirtioError::NeedsReset => write!(f, "device needs reset"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub addr: usize,
+    pub len: usize,
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn to_device(addr: usize, len: usize) -> Self {
+        Buffer { addr, len, writable: false }
+    }
+
+    pub fn from_device(addr: usize, len: usize) -> Self {
+        Buffer { addr, len, writable: true }
+    }
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: usize,
+    avail: usize,
+    used: usize,
+    notify: usize,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+pub struct VirtioDevice {
+    slot: Option<usize>,
+    common: usize,
+    notify: usize,
+    notify_multiplier: usize,
+    device: usize,
+    isr: usize,
+    msix: bool,
+    vector: Option<u8>,
+    line: Option<u8>,
+    pub irq: &'static str,
+}
+
+fn handle_interrupt() {
+    for (slot, isr) in ISR_REGS.iter().enumerate() {
+        let isr = isr.load(Ordering::Acquire);
+        if isr != 0 {
+            unsafe { core::ptr::read_volatile(isr as *const u8); }
+            PENDING[slot].store(true, Ordering::Release);
+        }
+    }
+}
+
+pub fn alloc_frames(count: usize) -> Result<usize, VirtioError> {
+    let frame = with_mm(|mm| mm.alloc_frames(count)).flatten().ok_or(VirtioError::OutOfMemory)?;
+    unsafe { core::ptr::write_bytes(frame, 0, count * FRAME_SIZE); }
+    Ok(frame as usize)
+}
+
+unsafe fn read<T: Copy>(addr: usize) -> T {
+    core::ptr::read_volatile(addr as *const T)
+}
+
+unsafe fn write<T: Copy>(addr: usize, value: T) {
+    core::ptr::write_volatile(addr as *mut T, value);
+}
+
+impl VirtioDevice {
+    pub fn new(dev: &PciDevice) -> Result<Self, VirtioError> {
+        let (mut common, mut notify, mut isr, mut device, mut notify_multiplier) = (0, 0, 0, 0, 0);
+        for cap in dev.capabilities.iter().filter(|c| c.id == CAP_VENDOR) {
+            let header = pci::read_config(dev.address, cap.offset);
+            let bar = pci::read_config(dev.address, cap.offset + 4) as u8;
+            let offset = pci::read_config(dev.address, cap.offset + 8) as usize;
+            if bar > 5 {
+                continue;
+            }
+            let Some(base) = pci::map_bar(dev, bar as usize) else { continue };
+            match (header >> 24) as u8 {
+                CFG_COMMON if common == 0 => common = base + offset,
+                CFG_NOTIFY if notify == 0 => {
+                    notify = base + offset;
+                    notify_multiplier = pci::read_config(dev.address, cap.offset + 16) as usize;
+                }
+                CFG_ISR if isr == 0 => isr = base + offset,
+                CFG_DEVICE if device == 0 => device = base + offset,
+                _ => {}
+            }
+        }
+        if common == 0 || notify == 0 || isr == 0 || device == 0 {
+            return Err(VirtioError::MissingCapability);
+        }
+        pci::enable_bus_master(dev);
+
+        let mut virtio = VirtioDevice {
+            slot: None,
+            common,
+            notify,
+            notify_multiplier,
+            device,
+            isr,
+            msix: false,
+            vector: None,
+            line: None,
+            irq: "polling",
+        };
+        virtio.reset()?;
+        let msix = dev.msix.is_some().then(|| apic::register_msi(handle_interrupt).ok()).flatten();
+        match msix {
+            Some(vector) if pci::enable_msix(dev, 0, vector) => {
+                virtio.msix = true;
+                virtio.vector = Some(vector);
+                virtio.irq = "MSI-X";
+                unsafe { write::<u16>(common + COMMON_MSIX_CONFIG, NO_VECTOR); }
+            }
+            _ => {
+                if let Some(vector) = msix {
+                    apic::unregister_msi(vector);
+                }
+                if (1..=4).contains(&dev.interrupt_pin) && apic::register_irq(dev.interrupt_line, handle_interrupt).is_ok() {
+                    virtio.line = Some(dev.interrupt_line);
+                    virtio.irq = "INTx";
+                }
+            }
+        }
+        Ok(virtio)
+    }
+
+    fn release_interrupt(&mut self) {
+        if let Some(vector) = self.vector.take() {
+            apic::unregister_msi(vector);
+        }
+        if let Some(line) = self.line.take() {
+            apic::unregister_irq(line);
+        }
+        self.msix = false;
+        self.irq = "polling";
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { read(self.common + COMMON_STATUS) }
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { write(self.common + COMMON_STATUS, status); }
+    }
+
+    fn reset(&self) -> Result<(), VirtioError> {
+        self.set_status(0);
+        let deadline = time::now_ns() + TIMEOUT_NS;
+        while self.status() != 0 {
+            if time::now_ns() > deadline {
+                return Err(VirtioError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(())
+    }
+
+    pub fn fail(&mut self) {
+        self.set_status(self.status() | STATUS_FAILED);
+        self.release_interrupt();
+    }
+
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let offered = unsafe {
+            write::<u32>(self.common + COMMON_DEVICE_FEATURE_SELECT, 0);
+            let low = read::<u32>(self.common + COMMON_DEVICE_FEATURE) as u64;
+            write::<u32>(self.common + COMMON_DEVICE_FEATURE_SELECT, 1);
+            low | (read::<u32>(self.common + COMMON_DEVICE_FEATURE) as u64) << 32
+        };
+        if offered & F_VERSION_1 == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        let features = offered & (wanted | F_VERSION_1);
+        unsafe {
+            write::<u32>(self.common + COMMON_DRIVER_FEATURE_SELECT, 0);
+            write::<u32>(self.common + COMMON_DRIVER_FEATURE, features as u32);
+            write::<u32>(self.common + COMMON_DRIVER_FEATURE_SELECT, 1);
+            write::<u32>(self.common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+        }
+        self.set_status(self.status() | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    pub fn setup_queue(&mut self, index: u16) -> Result<Virtqueue, VirtioError> {
+        let common = self.common;
+        let (size, notify_off) = unsafe {
+            if index >= read::<u16>(common + COMMON_NUM_QUEUES) {
+                return Err(VirtioError::QueueUnavailable);
+            }
+            write::<u16>(common + COMMON_QUEUE_SELECT, index);
+            (read::<u16>(common + COMMON_QUEUE_SIZE), read::<u16>(common + COMMON_QUEUE_NOTIFY_OFF))
+        };
+        if size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let size = size.min(MAX_QUEUE_SIZE);
+        let ring = alloc_frames(2)?;
+        let queue = Virtqueue {
+            index,
+            size,
+            desc: ring,
+            avail: ring + AVAIL_OFFSET,
+            used: ring + FRAME_SIZE,
+            notify: self.notify + notify_off as usize * self.notify_multiplier,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        };
+        for i in 0..size {
+            unsafe { write::<u16>(queue.desc + i as usize * 16 + 14, (i + 1) % size); }
+        }
+        unsafe {
+            write::<u16>(common + COMMON_QUEUE_SIZE, size);
+            if self.msix {
+                write::<u16>(common + COMMON_QUEUE_MSIX_VECTOR, 0);
+                if read::<u16>(common + COMMON_QUEUE_MSIX_VECTOR) == NO_VECTOR {
+                    self.release_interrupt();
+                }
+            }
+            write::<u64>(common + COMMON_QUEUE_DESC, queue.desc as u64);
+            write::<u64>(common + COMMON_QUEUE_DRIVER, queue.avail as u64);
+            write::<u64>(common + COMMON_QUEUE_DEVICE, queue.used as u64);
+            write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+        }
+        Ok(queue)
+    }
+
+    pub fn finish(&mut self) {
+        if self.irq != "polling" {
+            let slot = DEVICE_COUNT.fetch_add(1, Ordering::AcqRel);
+            if slot < MAX_DEVICES {
+                self.slot = Some(slot);
+                ISR_REGS[slot].store(self.isr, Ordering::Release);
+            } else {
+                self.release_interrupt();
+            }
+        }
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        unsafe { read(self.device + offset) }
+    }
+
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = unsafe { read::<u8>(self.common + COMMON_CONFIG_GENERATION) };
+            let value = self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32;
+            if generation == unsafe { read::<u8>(self.common + COMMON_CONFIG_GENERATION) } {
+                return value;
+            }
+        }
+    }
+
+    pub fn transact(&self, queue: &mut Virtqueue, buffers: &[Buffer]) -> Result<u32, VirtioError> {
+        if buffers.is_empty() || buffers.len() > queue.free_count as usize {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let head = queue.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = queue.desc + index as usize * 16;
+            let mut flags = if buffer.writable { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+            }
+            unsafe {
+                write::<u64>(desc, buffer.addr as u64);
+                write::<u32>(desc + 8, buffer.len as u32);
+                write::<u16>(desc + 12, flags);
+                if i + 1 < buffers.len() {
+                    index = read::<u16>(desc + 14);
+                }
+            }
+        }
+        queue.free_head = unsafe { read::<u16>(queue.desc + index as usize * 16 + 14) };
+        queue.free_count -= buffers.len() as u16;
+
+        if let Some(slot) = self.slot {
+            PENDING[slot].store(false, Ordering::Release);
+        }
+        unsafe {
+            let avail_idx = read::<u16>(queue.avail + 2);
+            write::<u16>(queue.avail + 4 + (avail_idx % queue.size) as usize * 2, head);
+            fence(Ordering::SeqCst);
+            write::<u16>(queue.avail + 2, avail_idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            write::<u16>(queue.notify, queue.index);
+        }
+
+        let deadline = time::now_ns() + TIMEOUT_NS;
+        let halt = self.slot.filter(|_| self.irq != "polling" && interrupts::are_enabled());
+        loop {
+            let used_idx = unsafe { read::<u16>(queue.used + 2) };
+            if used_idx != queue.last_used {
+                fence(Ordering::SeqCst);
+                let entry = queue.used + 4 + (queue.last_used % queue.size) as usize * 8;
+                let (id, len) = unsafe { (read::<u32>(entry) as u16, read::<u32>(entry + 4)) };
+                queue.last_used = queue.last_used.wrapping_add(1);
+                queue.release(id);
+                if id == head {
+                    return Ok(len);
+                }
+                continue;
+            }
+            if self.status() & STATUS_NEEDS_RESET != 0 {
+                return Err(VirtioError::NeedsReset);
+            }
+            if time::now_ns() > deadline {
+                return Err(VirtioError::Timeout);
+            }
+            if halt.is_some_and(|slot| !PENDING[slot].load(Ordering::Acquire)) {
+                x86_64::instructions::hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Virtqueue {
+    fn release(&mut self, head: u16) {
+        let mut index = head;
+        let mut count = 1;
+        unsafe {
+            while read::<u16>(self.desc + index as usize * 16 + 12) & DESC_NEXT != 0 {
+                index = read::<u16>(self.desc + index as usize * 16 + 14);
+                count += 1;
+            }
+            write::<u16>(self.desc + index as usize * 16 + 14, self.free_head);
+        }
+        self.free_head = head;
+        self.free_count += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn polling_device_transacts_without_a_slot() {
+        let common = alloc::vec![0u64; 8].leak().as_mut_ptr() as usize;
+        let notify = alloc::vec![0u64; 1].leak().as_mut_ptr() as usize;
+        let ring = alloc::vec![0u64; 2 * FRAME_SIZE / 8].leak().as_mut_ptr() as usize;
+        let device = VirtioDevice {
+            slot: None,
+            common,
+            notify,
+            notify_multiplier: 0,
+            device: 0,
+            isr: 0,
+            msix: false,
+            vector: None,
+            line: None,
+            irq: "polling",
+        };
+        let mut queue = Virtqueue {
+            index: 0,
+            size: 4,
+            desc: ring,
+            avail: ring + AVAIL_OFFSET,
+            used: ring + FRAME_SIZE,
+            notify,
+            free_head: 0,
+            free_count: 4,
+            last_used: 0,
+        };
+        for i in 0..4u16 {
+            unsafe { write::<u16>(ring + i as usize * 16 + 14, (i + 1) % 4); }
+        }
+        unsafe {
+            write::<u32>(queue.used + 4, 0);
+            write::<u32>(queue.used + 8, 512);
+            write::<u16>(queue.used + 2, 1);
+        }
+        let result = device.transact(&mut queue, &[Buffer::from_device(0x1000, 512)]);
+        assert!(matches!(result, Ok(512)));
+        assert_eq!(queue.free_count, 4);
+        assert_eq!(unsafe { read::<u16>(queue.avail + 2) }, 1);
+    }
+}
@@ -0,0 +1,195 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::drivers::block::{self, check_range, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::drivers::pci::{PciDevice, PciDriver, PciMatch};
+use crate::drivers::virtio::{self, Buffer, VirtioDevice, VirtioError, Virtqueue};
+use crate::system::memory::FRAME_SIZE;
+use crate::log;
+
+const BOUNCE_FRAMES: usize = 16;
+const MAX_SECTORS: usize = BOUNCE_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: usize = 0;
+
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const REQ_FLUSH: u32 = 4;
+const REQ_GET_ID: u32 = 8;
+const ID_LEN: usize = 20;
+
+const STATUS_OFFSET: usize = 16;
+const STATUS_OK: u8 = 0;
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        PciMatch { vendor_id: Some(virtio::VENDOR_ID), device_id: Some(0x1001), ..PciMatch::ANY },
+        PciMatch { vendor_id: Some(virtio::VENDOR_ID), device_id: Some(0x1042), ..PciMatch::ANY },
+    ],
+    probe,
+};
+
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+struct Request {
+    queue: Virtqueue,
+    header: usize,
+    bounce: usize,
+}
+
+pub struct VirtioBlk {
+    name: String,
+    model: String,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+    device: VirtioDevice,
+    request: Mutex<Request>,
+}
+
+impl VirtioBlk {
+    fn submit(&self, request: &mut Request, kind: u32, sector: u64, bytes: usize) -> Result<(), BlockError> {
+        let Request { queue, header, bounce } = request;
+        unsafe {
+            let words = *header as *mut u32;
+            core::ptr::write_volatile(words, kind);
+            core::ptr::write_volatile(words.add(1), 0);
+            core::ptr::write_volatile(words.add(2) as *mut u64, sector);
+            core::ptr::write_volatile((*header + STATUS_OFFSET) as *mut u8, 0xFF);
+        }
+        let head = Buffer::to_device(*header, 16);
+        let status = Buffer::from_device(*header + STATUS_OFFSET, 1);
+        let result = if bytes == 0 {
+            self.device.transact(queue, &[head, status])
+        } else if kind == REQ_OUT {
+            self.device.transact(queue, &[head, Buffer::to_device(*bounce, bytes), status])
+        } else {
+            self.device.transact(queue, &[head, Buffer::from_device(*bounce, bytes), status])
+        };
+        let status = unsafe { core::ptr::read_volatile((*header + STATUS_OFFSET) as *const u8) };
+        match result {
+            Ok(_) if status == STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    fn identify(&self) -> String {
+        let mut request = self.request.lock();
+        if self.submit(&mut request, REQ_GET_ID, 0, ID_LEN).is_err() {
+            return String::new();
+        }
+        let id = unsafe { core::slice::from_raw_parts(request.bounce as *const u8, ID_LEN) };
+        let len = id.iter().position(|&b| b == 0).unwrap_or(ID_LEN);
+        String::from(core::str::from_utf8(&id[..len]).unwrap_or("").trim())
+    }
+
+    fn transfer(&self, lba: u64, len: usize, mut chunk: impl FnMut(usize, &mut [u8]), write: bool) -> Result<(), BlockError> {
+        check_range(self, lba, len)?;
+        if write && self.read_only {
+            return Err(BlockError::Io);
+        }
+        let mut request = self.request.lock();
+        let mut done = 0;
+        while done < len {
+            let count = ((len - done) / SECTOR_SIZE).min(MAX_SECTORS);
+            let bytes = count * SECTOR_SIZE;
+            let data = unsafe { core::slice::from_raw_parts_mut(request.bounce as *mut u8, bytes) };
+            let sector = lba + (done / SECTOR_SIZE) as u64;
+            if write {
+                chunk(done, data);
+                self.submit(&mut request, REQ_OUT, sector, bytes)?;
+            } else {
+                self.submit(&mut request, REQ_IN, sector, bytes)?;
+                chunk(done, data);
+            }
+            done += bytes;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let len = buf.len();
+        self.transfer(lba, len, |offset, data| buf[offset..offset + data.len()].copy_from_slice(data), false)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.transfer(lba, buf.len(), |offset, data| data.copy_from_slice(&buf[offset..offset + data.len()]), true)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush {
+            return Ok(());
+        }
+        let mut request = self.request.lock();
+        self.submit(&mut request, REQ_FLUSH, 0, 0)
+    }
+}
+
+fn init(dev: &PciDevice, name: String) -> Result<VirtioBlk, VirtioError> {
+    let mut device = VirtioDevice::new(dev)?;
+    let setup = (|| {
+        let features = device.negotiate(F_RO | F_FLUSH)?;
+        let queue = device.setup_queue(0)?;
+        Ok((features, Request { queue, header: virtio::alloc_frames(1)?, bounce: virtio::alloc_frames(BOUNCE_FRAMES)? }))
+    })();
+    let (features, request) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            device.fail();
+            return Err(e);
+        }
+    };
+    device.finish();
+    let mut disk = VirtioBlk {
+        name,
+        model: String::new(),
+        sectors: device.config_u64(CONFIG_CAPACITY),
+        read_only: features & F_RO != 0,
+        flush: features & F_FLUSH != 0,
+        device,
+        request: Mutex::new(request),
+    };
+    disk.model = disk.identify();
+    Ok(disk)
+}
+
+fn probe(dev: &PciDevice) -> bool {
+    let index = DISK_COUNT.load(Ordering::Acquire);
+    if index >= 26 {
+        return false;
+    }
+    let name = format!("vd{}", (b'a' + index as u8) as char);
+    match init(dev, name) {
+        Ok(disk) => {
+            DISK_COUNT.store(index + 1, Ordering::Release);
+            log!("OK", "{}: {} MiB{} at {}, {}", disk.name, disk.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+                if disk.read_only { " (read-only)" } else { "" }, dev.address, disk.device.irq);
+            block::register(Arc::new(disk));
+            true
+        }
+        Err(e) => {
+            log!("WARN", "virtio-blk at {} failed: {}", dev.address, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,967 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+use crate::drivers::block::{BlockDevice, BlockError};
+use crate::fs::vfs::{DirEntry, FileSystem, FileType, FsError, InodeId, Stat};
+
+const ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+const ENTRY_FREE: u8 = 0xE5;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+const FAT_EOC: u32 = 0x0FFF_FFF8;
+const FAT_END: u32 = 0x0FFF_FFFF;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+const NO_SECTOR: u64 = u64::MAX;
+
+const ROOT: InodeId = 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatError {
+    Io,
+    NotFat32,
+    UnsupportedSectorSize,
+}
+
+impl From<BlockError> for FatError {
+    fn from(_: BlockError) -> Self {
+        FatError::Io
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(_: BlockError) -> Self {
+        FsError::Io
+    }
+}
+
+struct Node {
+    parent: InodeId,
+    offset: usize,
+    cluster: u32,
+    size: u32,
+    kind: FileType,
+}
+
+struct RawEntry {
+    name: String,
+    short: [u8; 11],
+    first_slot: usize,
+    offset: usize,
+    attr: u8,
+    cluster: u32,
+    size: u32,
+}
+
+struct Directory {
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+}
+
+struct FatState {
+    nodes: Vec<Option<Node>>,
+    free_count: u32,
+    next_free: u32,
+}
+
+struct FatCache {
+    sector: u64,
+    data: Vec<u8>,
+}
+
+pub struct Fat32Fs {
+    dev: Arc<dyn BlockDevice>,
+    scale: u64,
+    sector_size: usize,
+    cluster_sectors: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    data_start: u64,
+    cluster_count: u32,
+    fsinfo_sector: Option<u64>,
+    fat_cache: Mutex<FatCache>,
+    state: Mutex<FatState>,
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &c| (sum >> 1).wrapping_add(sum << 7).wrapping_add(c))
+}
+
+fn short_display(short: &[u8; 11], case: u8) -> String {
+    let base = core::str::from_utf8(&short[..8]).unwrap_or("").trim_end();
+    let ext = core::str::from_utf8(&short[8..]).unwrap_or("").trim_end();
+    let mut name = if case & 0x08 != 0 { base.to_ascii_lowercase() } else { String::from(base) };
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&if case & 0x10 != 0 { ext.to_ascii_lowercase() } else { String::from(ext) });
+    }
+    name
+}
+
+fn short_char(c: char) -> Option<u8> {
+    match c {
+        'A'..='Z' | '0'..='9' => Some(c as u8),
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{' | '}' | '~' => Some(c as u8),
+        _ => None,
+    }
+}
+
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    for (i, c) in base.chars().chain(ext.chars()).enumerate() {
+        if c.is_ascii_lowercase() {
+            return None;
+        }
+        let slot = if i < base.len() { i } else { 8 + i - base.len() };
+        short[slot] = short_char(c)?;
+    }
+    Some(short)
+}
+
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let base: Vec<u8> = base.chars().filter(|&c| c != ' ' && c != '.').map(|c| short_char(c).unwrap_or(b'_')).collect();
+    let ext: Vec<u8> = ext.chars().filter(|&c| c != ' ').map(|c| short_char(c).unwrap_or(b'_')).take(3).collect();
+
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let keep = base.len().min(8 - tail.len()).max(if base.is_empty() { 0 } else { 1 });
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken.contains(&short) {
+            return Some(short);
+        }
+    }
+    None
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && name != ".."
+        && !name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+}
+
+fn parse_directory(data: &[u8]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut lfn: Vec<u16> = Vec::new();
+    let mut lfn_start = None;
+    let mut lfn_sum = 0u8;
+
+    for offset in (0..data.len()).step_by(ENTRY_SIZE) {
+        let e = &data[offset..offset + ENTRY_SIZE];
+        match e[0] {
+            0 => break,
+            ENTRY_FREE => {
+                lfn_start = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attr = e[11];
+        if attr & 0x3F == ATTR_LFN {
+            let seq = (e[0] & 0x1F) as usize;
+            if e[0] & LFN_LAST != 0 {
+                lfn = alloc::vec![0xFFFF; seq * LFN_CHARS];
+                lfn_start = Some(offset);
+                lfn_sum = e[13];
+            }
+            if lfn_start.is_none() || seq == 0 || seq * LFN_CHARS > lfn.len() || e[13] != lfn_sum {
+                lfn_start = None;
+                continue;
+            }
+            for (i, &pos) in LFN_OFFSETS.iter().enumerate() {
+                lfn[(seq - 1) * LFN_CHARS + i] = le16(e, pos);
+            }
+            continue;
+        }
+
+        let mut short = [0u8; 11];
+        short.copy_from_slice(&e[..11]);
+        if short[0] == 0x05 {
+            short[0] = ENTRY_FREE;
+        }
+        let long = lfn_start.filter(|_| lfn_checksum(&short) == lfn_sum);
+        lfn_start = None;
+        if attr & ATTR_VOLUME_ID != 0 || short[0] == b'.' {
+            continue;
+        }
+
+        let name = match long {
+            Some(_) => {
+                let end = lfn.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(lfn.len());
+                char::decode_utf16(lfn[..end].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            }
+            None => short_display(&short, e[12]),
+        };
+        entries.push(RawEntry {
+            name,
+            short,
+            first_slot: long.unwrap_or(offset),
+            offset,
+            attr,
+            cluster: ((le16(e, 20) as u32) << 16) | le16(e, 26) as u32,
+            size: le32(e, 28),
+        });
+    }
+    entries
+}
+
+fn encode_entries(name: &str, short: &[u8; 11], lfn: bool, attr: u8, cluster: u32, size: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    if lfn {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() % LFN_CHARS != 0 {
+            units.push(0);
+        }
+        while units.len() % LFN_CHARS != 0 {
+            units.push(0xFFFF);
+        }
+        let count = units.len() / LFN_CHARS;
+        let sum = lfn_checksum(short);
+        for seq in (1..=count).rev() {
+            let mut e = [0u8; ENTRY_SIZE];
+            e[0] = seq as u8 | if seq == count { LFN_LAST } else { 0 };
+            e[11] = ATTR_LFN;
+            e[13] = sum;
+            for (i, &pos) in LFN_OFFSETS.iter().enumerate() {
+                e[pos..pos + 2].copy_from_slice(&units[(seq - 1) * LFN_CHARS + i].to_le_bytes());
+            }
+            out.extend_from_slice(&e);
+        }
+    }
+    let mut e = [0u8; ENTRY_SIZE];
+    e[..11].copy_from_slice(short);
+    e[11] = attr;
+    e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    e[28..32].copy_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&e);
+    out
+}
+
+impl Fat32Fs {
+    pub fn mount(dev: Arc<dyn BlockDevice>) -> Result<Self, FatError> {
+        let dev_size = dev.block_size();
+        let mut boot = alloc::vec![0u8; dev_size.max(512)];
+        dev.read_blocks(0, &mut boot[..dev_size])?;
+        if boot[510..512] != [0x55, 0xAA] {
+            return Err(FatError::NotFat32);
+        }
+
+        let sector_size = le16(&boot, 11) as usize;
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096) || sector_size % dev_size != 0 {
+            return Err(FatError::UnsupportedSectorSize);
+        }
+        let cluster_sectors = boot[13] as u64;
+        let reserved = le16(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = le16(&boot, 17);
+        let total16 = le16(&boot, 19) as u64;
+        let fat16_size = le16(&boot, 22);
+        let total = if total16 != 0 { total16 } else { le32(&boot, 32) as u64 };
+        let fat_sectors = le32(&boot, 36) as u64;
+        let root_cluster = le32(&boot, 44);
+        let fsinfo = le16(&boot, 48) as u64;
+
+        if cluster_sectors == 0 || !cluster_sectors.is_power_of_two() || reserved == 0 || fat_count == 0
+            || root_entries != 0 || fat16_size != 0 || fat_sectors == 0 || root_cluster < 2 {
+            return Err(FatError::NotFat32);
+        }
+        let data_start = reserved + fat_count * fat_sectors;
+        let cluster_count = (total.saturating_sub(data_start) / cluster_sectors) as u32;
+        if cluster_count == 0 || cluster_count as u64 + 2 > fat_sectors * sector_size as u64 / 4 {
+            return Err(FatError::NotFat32);
+        }
+
+        let mut fs = Fat32Fs {
+            dev,
+            scale: (sector_size / dev_size) as u64,
+            sector_size,
+            cluster_sectors,
+            fat_start: reserved,
+            fat_sectors,
+            fat_count,
+            data_start,
+            cluster_count,
+            fsinfo_sector: None,
+            fat_cache: Mutex::new(FatCache { sector: NO_SECTOR, data: alloc::vec![0u8; sector_size] }),
+            state: Mutex::new(FatState {
+                nodes: alloc::vec![Some(Node { parent: ROOT, offset: usize::MAX, cluster: root_cluster, size: 0, kind: FileType::Directory })],
+                free_count: FSINFO_UNKNOWN,
+                next_free: 2,
+            }),
+        };
+
+        if fsinfo != 0 && fsinfo != 0xFFFF && fsinfo < reserved {
+            let mut info = fs.sector_buf();
+            fs.read_sector(fsinfo, &mut info)?;
+            if le32(&info, 0) == FSINFO_LEAD && le32(&info, 484) == FSINFO_STRUCT {
+                fs.fsinfo_sector = Some(fsinfo);
+                let state = fs.state.get_mut();
+                state.free_count = le32(&info, 488);
+                state.next_free = le32(&info, 492);
+            }
+        }
+
+        let state = fs.state.get_mut();
+        if state.next_free < 2 || state.next_free >= cluster_count + 2 {
+            state.next_free = 2;
+        }
+        if state.free_count > cluster_count {
+            let mut free = 0;
+            for cluster in 2..cluster_count + 2 {
+                if fs.fat_entry(cluster)? == 0 {
+                    free += 1;
+                }
+            }
+            fs.state.get_mut().free_count = free;
+        }
+        Ok(fs)
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.state.lock().free_count as u64 * self.cluster_bytes() as u64
+    }
+
+    fn sector_buf(&self) -> Vec<u8> {
+        alloc::vec![0u8; self.sector_size]
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.cluster_sectors as usize * self.sector_size
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.dev.read_blocks(sector * self.scale, buf)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.dev.write_blocks(sector * self.scale, buf)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_sectors
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if !self.valid_cluster(cluster) {
+            return Err(FsError::Io);
+        }
+        Ok(self.read_sector(self.cluster_sector(cluster), buf)?)
+    }
+
+    fn write_cluster(&self, cluster: u32, buf: &[u8]) -> Result<(), FsError> {
+        if !self.valid_cluster(cluster) {
+            return Err(FsError::Io);
+        }
+        Ok(self.write_sector(self.cluster_sector(cluster), buf)?)
+    }
+
+    fn fat_location(&self, cluster: u32) -> (u64, usize) {
+        let byte = cluster as usize * 4;
+        ((byte / self.sector_size) as u64, byte % self.sector_size)
+    }
+
+    fn fat_sector(&self, sector: u64) -> Result<MutexGuard<'_, FatCache>, BlockError> {
+        let mut cache = self.fat_cache.lock();
+        if cache.sector != sector {
+            cache.sector = NO_SECTOR;
+            self.read_sector(self.fat_start + sector, &mut cache.data)?;
+            cache.sector = sector;
+        }
+        Ok(cache)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, BlockError> {
+        let (sector, offset) = self.fat_location(cluster);
+        Ok(le32(&self.fat_sector(sector)?.data, offset) & FAT_MASK)
+    }
+
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), BlockError> {
+        let (sector, offset) = self.fat_location(cluster);
+        let mut cache = self.fat_sector(sector)?;
+        let old = le32(&cache.data, offset);
+        cache.data[offset..offset + 4].copy_from_slice(&((old & !FAT_MASK) | (value & FAT_MASK)).to_le_bytes());
+        for fat in 0..self.fat_count {
+            if let Err(e) = self.write_sector(self.fat_start + fat * self.fat_sectors + sector, &cache.data) {
+                cache.sector = NO_SECTOR;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while self.valid_cluster(cluster) {
+            if clusters.len() > self.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+        if cluster == FAT_BAD || (cluster != 0 && cluster < FAT_EOC && !clusters.is_empty()) {
+            return Err(FsError::Io);
+        }
+        Ok(clusters)
+    }
+
+    fn write_fsinfo(&self, state: &FatState) -> Result<(), FsError> {
+        let Some(sector) = self.fsinfo_sector else { return Ok(()) };
+        let mut info = self.sector_buf();
+        self.read_sector(sector, &mut info)?;
+        info[488..492].copy_from_slice(&state.free_count.to_le_bytes());
+        info[492..496].copy_from_slice(&state.next_free.to_le_bytes());
+        Ok(self.write_sector(sector, &info)?)
+    }
+
+    fn alloc_cluster(&self, state: &mut FatState, prev: Option<u32>) -> Result<u32, FsError> {
+        if state.free_count == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let count = self.cluster_count;
+        let start = state.next_free;
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.set_fat_entry(cluster, FAT_END)?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster)?;
+            }
+            self.write_cluster(cluster, &alloc::vec![0u8; self.cluster_bytes()])?;
+            state.free_count = state.free_count.saturating_sub(1);
+            state.next_free = if cluster + 1 < count + 2 { cluster + 1 } else { 2 };
+            return Ok(cluster);
+        }
+        state.free_count = 0;
+        Err(FsError::NoSpace)
+    }
+
+    fn free_chain(&self, state: &mut FatState, clusters: &[u32]) -> Result<(), FsError> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+            if state.free_count != FSINFO_UNKNOWN {
+                state.free_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn node<'a>(&self, state: &'a FatState, inode: InodeId) -> Result<&'a Node, FsError> {
+        state.nodes.get(inode as usize).and_then(|n| n.as_ref()).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut<'a>(&self, state: &'a mut FatState, inode: InodeId) -> Result<&'a mut Node, FsError> {
+        state.nodes.get_mut(inode as usize).and_then(|n| n.as_mut()).ok_or(FsError::NotFound)
+    }
+
+    fn dir_cluster(&self, state: &FatState, inode: InodeId) -> Result<u32, FsError> {
+        let node = self.node(state, inode)?;
+        if node.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(node.cluster)
+    }
+
+    fn load_dir(&self, first: u32) -> Result<Directory, FsError> {
+        let clusters = self.chain(first)?;
+        let size = self.cluster_bytes();
+        let mut data = alloc::vec![0u8; clusters.len() * size];
+        for (i, &cluster) in clusters.iter().enumerate() {
+            self.read_cluster(cluster, &mut data[i * size..(i + 1) * size])?;
+        }
+        Ok(Directory { clusters, data })
+    }
+
+    fn store_dir(&self, dir: &Directory, from: usize, to: usize) -> Result<(), FsError> {
+        let size = self.cluster_bytes();
+        for i in from / size..(to + size - 1) / size {
+            self.write_cluster(dir.clusters[i], &dir.data[i * size..(i + 1) * size])?;
+        }
+        Ok(())
+    }
+
+    fn find_entry(&self, dir: &Directory, name: &str) -> Option<RawEntry> {
+        parse_directory(&dir.data).into_iter().find(|e| e.name.eq_ignore_ascii_case(name))
+    }
+
+    fn node_for(&self, state: &mut FatState, parent: InodeId, entry: &RawEntry) -> InodeId {
+        let existing = state.nodes.iter().position(|n| {
+            n.as_ref().map_or(false, |n| n.parent == parent && n.offset == entry.offset)
+        });
+        let kind = if entry.attr & ATTR_DIRECTORY != 0 { FileType::Directory } else { FileType::File };
+        let node = Node { parent, offset: entry.offset, cluster: entry.cluster, size: entry.size, kind };
+        match existing {
+            Some(index) => index as InodeId,
+            None => match state.nodes.iter().position(|n| n.is_none()) {
+                Some(free) => {
+                    state.nodes[free] = Some(node);
+                    free as InodeId
+                }
+                None => {
+                    state.nodes.push(Some(node));
+                    (state.nodes.len() - 1) as InodeId
+                }
+            },
+        }
+    }
+
+    fn write_entry_fields(&self, state: &FatState, inode: InodeId) -> Result<(), FsError> {
+        let node = self.node(state, inode)?;
+        if inode == ROOT {
+            return Ok(());
+        }
+        let mut dir = self.load_dir(self.dir_cluster(state, node.parent)?)?;
+        let e = &mut dir.data[node.offset..node.offset + ENTRY_SIZE];
+        e[20..22].copy_from_slice(&((node.cluster >> 16) as u16).to_le_bytes());
+        e[26..28].copy_from_slice(&(node.cluster as u16).to_le_bytes());
+        let size = if node.kind == FileType::Directory { 0 } else { node.size };
+        e[28..32].copy_from_slice(&size.to_le_bytes());
+        self.store_dir(&dir, node.offset, node.offset + ENTRY_SIZE)
+    }
+
+    fn insert_entry(&self, state: &mut FatState, parent: InodeId, name: &str, attr: u8, cluster: u32, size: u32) -> Result<usize, FsError> {
+        let first = self.dir_cluster(state, parent)?;
+        let mut dir = self.load_dir(first)?;
+        let entries = parse_directory(&dir.data);
+        if entries.iter().any(|e| e.name.eq_ignore_ascii_case(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (short, lfn) = match exact_short_name(name) {
+            Some(short) if !entries.iter().any(|e| e.short == short) => (short, false),
+            _ => {
+                let taken: Vec<[u8; 11]> = entries.iter().map(|e| e.short).collect();
+                (generate_short_name(name, &taken).ok_or(FsError::NoSpace)?, true)
+            }
+        };
+        let encoded = encode_entries(name, &short, lfn, attr, cluster, size);
+        let slots = encoded.len() / ENTRY_SIZE;
+
+        let mut run = 0;
+        let mut start = None;
+        for offset in (0..dir.data.len()).step_by(ENTRY_SIZE) {
+            let b = dir.data[offset];
+            if b == 0 || b == ENTRY_FREE {
+                run += 1;
+                if run == slots {
+                    start = Some(offset + ENTRY_SIZE - slots * ENTRY_SIZE);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None => {
+                let start = dir.data.len() - run * ENTRY_SIZE;
+                let mut last = *dir.clusters.last().ok_or(FsError::Io)?;
+                while (dir.data.len() - start) / ENTRY_SIZE < slots {
+                    last = self.alloc_cluster(state, Some(last))?;
+                    dir.clusters.push(last);
+                    dir.data.resize(dir.clusters.len() * self.cluster_bytes(), 0);
+                }
+                start
+            }
+        };
+        dir.data[start..start + encoded.len()].copy_from_slice(&encoded);
+        self.store_dir(&dir, start, start + encoded.len())?;
+        Ok(start + encoded.len() - ENTRY_SIZE)
+    }
+
+    fn erase_entry(&self, dir_cluster: u32, entry: &RawEntry) -> Result<(), FsError> {
+        let mut dir = self.load_dir(dir_cluster)?;
+        for slot in (entry.first_slot..=entry.offset).step_by(ENTRY_SIZE) {
+            dir.data[slot] = ENTRY_FREE;
+        }
+        self.store_dir(&dir, entry.first_slot, entry.offset + ENTRY_SIZE)
+    }
+
+    fn forget(&self, state: &mut FatState, parent: InodeId, offset: usize) {
+        for node in state.nodes.iter_mut() {
+            if node.as_ref().map_or(false, |n| n.parent == parent && n.offset == offset) {
+                *node = None;
+            }
+        }
+    }
+
+    fn delete(&self, state: &mut FatState, dir: InodeId, entry: &RawEntry) -> Result<(), FsError> {
+        if entry.attr & ATTR_DIRECTORY != 0 && !parse_directory(&self.load_dir(entry.cluster)?.data).is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        self.erase_entry(self.dir_cluster(state, dir)?, entry)?;
+        let chain = self.chain(entry.cluster)?;
+        self.free_chain(state, &chain)?;
+        self.forget(state, dir, entry.offset);
+        Ok(())
+    }
+
+    fn is_ancestor(&self, state: &FatState, ancestor: InodeId, mut inode: InodeId) -> bool {
+        loop {
+            if inode == ancestor {
+                return true;
+            }
+            match self.node(state, inode) {
+                Ok(node) if inode != ROOT => inode = node.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    fn parent_cluster(&self, state: &FatState, dir: InodeId) -> Result<u32, FsError> {
+        if dir == ROOT { Ok(0) } else { self.dir_cluster(state, dir) }
+    }
+
+    fn write_locked(&self, state: &mut FatState, inode: InodeId, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let node = self.node(state, inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset.checked_add(buf.len() as u64).filter(|&e| e <= u32::MAX as u64).ok_or(FsError::NoSpace)?;
+        let old_size = node.size as u64;
+        let size = self.cluster_bytes() as u64;
+        let mut chain = self.chain(node.cluster)?;
+        while (chain.len() as u64) * size < end {
+            let cluster = self.alloc_cluster(state, chain.last().copied())?;
+            if chain.is_empty() {
+                self.node_mut(state, inode)?.cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+
+        let start = offset.min(old_size);
+        let mut data = alloc::vec![0u8; size as usize];
+        let mut pos = start - start % size;
+        while pos < end {
+            let cluster = chain[(pos / size) as usize];
+            self.read_cluster(cluster, &mut data)?;
+            for i in 0..size {
+                let at = pos + i;
+                if at >= old_size && at < offset {
+                    data[i as usize] = 0;
+                } else if at >= offset && at < end {
+                    data[i as usize] = buf[(at - offset) as usize];
+                }
+            }
+            self.write_cluster(cluster, &data)?;
+            pos += size;
+        }
+
+        let node = self.node_mut(state, inode)?;
+        node.size = node.size.max(end as u32);
+        self.write_entry_fields(state, inode)?;
+        Ok(buf.len())
+    }
+
+    fn sync(&self, state: &FatState) -> Result<(), FsError> {
+        self.write_fsinfo(state)?;
+        Ok(self.dev.flush()?)
+    }
+}
+
+impl FileSystem for Fat32Fs {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+
+    fn root(&self) -> InodeId {
+        ROOT
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        let mut state = self.state.lock();
+        let entries = self.load_dir(self.dir_cluster(&state, dir)?)?;
+        let entry = self.find_entry(&entries, name).ok_or(FsError::NotFound)?;
+        Ok(self.node_for(&mut state, dir, &entry))
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError> {
+        let state = self.state.lock();
+        let node = self.node(&state, inode)?;
+        Ok(Stat { inode, kind: node.kind, size: node.size as u64 })
+    }
+
+    fn read(&self, inode: InodeId, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let state = self.state.lock();
+        let node = self.node(&state, inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset.saturating_add(buf.len() as u64).min(node.size as u64);
+        if offset >= end {
+            return Ok(0);
+        }
+        let size = self.cluster_bytes() as u64;
+        let chain = self.chain(node.cluster)?;
+        let mut data = alloc::vec![0u8; size as usize];
+        let mut pos = offset;
+        while pos < end {
+            let cluster = *chain.get((pos / size) as usize).ok_or(FsError::Io)?;
+            self.read_cluster(cluster, &mut data)?;
+            let from = (pos % size) as usize;
+            let chunk = (size as usize - from).min((end - pos) as usize);
+            let dst = (pos - offset) as usize;
+            buf[dst..dst + chunk].copy_from_slice(&data[from..from + chunk]);
+            pos += chunk as u64;
+        }
+        Ok((end - offset) as usize)
+    }
+
+    fn write(&self, inode: InodeId, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.state.lock();
+        let result = self.write_locked(&mut state, inode, offset, buf);
+        self.sync(&state)?;
+        result
+    }
+
+    fn truncate(&self, inode: InodeId, size: u64) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let node = self.node(&state, inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if size >= node.size as u64 {
+            self.write_locked(&mut state, inode, size, &[])?;
+            return self.sync(&state);
+        }
+        let chain = self.chain(node.cluster)?;
+        let keep = ((size + self.cluster_bytes() as u64 - 1) / self.cluster_bytes() as u64) as usize;
+        self.free_chain(&mut state, &chain[keep..])?;
+        match keep {
+            0 => self.node_mut(&mut state, inode)?.cluster = 0,
+            _ => self.set_fat_entry(chain[keep - 1], FAT_END)?,
+        }
+        self.node_mut(&mut state, inode)?.size = size as u32;
+        self.write_entry_fields(&state, inode)?;
+        self.sync(&state)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError> {
+        let state = self.state.lock();
+        let entries = self.load_dir(self.dir_cluster(&state, dir)?)?;
+        Ok(parse_directory(&entries.data).into_iter().map(|e| DirEntry {
+            name: e.name,
+            kind: if e.attr & ATTR_DIRECTORY != 0 { FileType::Directory } else { FileType::File },
+        }).collect())
+    }
+
+    fn create(&self, dir: InodeId, name: &str, kind: FileType) -> Result<InodeId, FsError> {
+        if !valid_name(name) {
+            return Err(FsError::InvalidPath);
+        }
+        let mut state = self.state.lock();
+        let entries = self.load_dir(self.dir_cluster(&state, dir)?)?;
+        if self.find_entry(&entries, name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (attr, cluster) = match kind {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                let cluster = self.alloc_cluster(&mut state, None)?;
+                let mut data = alloc::vec![0u8; self.cluster_bytes()];
+                let parent = self.parent_cluster(&state, dir)?;
+                data[..ENTRY_SIZE].copy_from_slice(&encode_entries(".", b".          ", false, ATTR_DIRECTORY, cluster, 0));
+                data[ENTRY_SIZE..2 * ENTRY_SIZE].copy_from_slice(&encode_entries("..", b"..         ", false, ATTR_DIRECTORY, parent, 0));
+                self.write_cluster(cluster, &data)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+        };
+        let offset = match self.insert_entry(&mut state, dir, name, attr, cluster, 0) {
+            Ok(offset) => offset,
+            Err(e) => {
+                if cluster != 0 {
+                    self.free_chain(&mut state, &[cluster])?;
+                }
+                self.sync(&state)?;
+                return Err(e);
+            }
+        };
+        let entries = self.load_dir(self.dir_cluster(&state, dir)?)?;
+        let entry = parse_directory(&entries.data).into_iter().find(|e| e.offset == offset).ok_or(FsError::Io)?;
+        let inode = self.node_for(&mut state, dir, &entry);
+        self.sync(&state)?;
+        Ok(inode)
+    }
+
+    fn remove(&self, dir: InodeId, name: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let entries = self.load_dir(self.dir_cluster(&state, dir)?)?;
+        let entry = self.find_entry(&entries, name).ok_or(FsError::NotFound)?;
+        self.delete(&mut state, dir, &entry)?;
+        self.sync(&state)
+    }
+
+    fn rename(&self, from_dir: InodeId, from: &str, to_dir: InodeId, to: &str) -> Result<(), FsError> {
+        if !valid_name(to) {
+            return Err(FsError::InvalidPath);
+        }
+        let mut state = self.state.lock();
+        let from_cluster = self.dir_cluster(&state, from_dir)?;
+        let source = self.find_entry(&self.load_dir(from_cluster)?, from).ok_or(FsError::NotFound)?;
+        let to_cluster = self.dir_cluster(&state, to_dir)?;
+        let is_dir = source.attr & ATTR_DIRECTORY != 0;
+        let inode = self.node_for(&mut state, from_dir, &source);
+        if is_dir && self.is_ancestor(&state, inode, to_dir) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if let Some(target) = self.find_entry(&self.load_dir(to_cluster)?, to) {
+            if from_dir == to_dir && target.offset == source.offset {
+                return Ok(());
+            }
+            match (is_dir, target.attr & ATTR_DIRECTORY != 0) {
+                (false, true) => return Err(FsError::IsADirectory),
+                (true, false) => return Err(FsError::NotADirectory),
+                _ => {}
+            }
+            self.delete(&mut state, to_dir, &target)?;
+        }
+
+        let offset = self.insert_entry(&mut state, to_dir, to, source.attr, source.cluster, source.size)?;
+        self.erase_entry(from_cluster, &source)?;
+        let node = self.node_mut(&mut state, inode)?;
+        node.parent = to_dir;
+        node.offset = offset;
+
+        if is_dir && from_dir != to_dir {
+            let parent = self.parent_cluster(&state, to_dir)?;
+            let mut dir = self.load_dir(source.cluster)?;
+            dir.data[ENTRY_SIZE + 20..ENTRY_SIZE + 22].copy_from_slice(&((parent >> 16) as u16).to_le_bytes());
+            dir.data[ENTRY_SIZE + 26..ENTRY_SIZE + 28].copy_from_slice(&(parent as u16).to_le_bytes());
+            self.store_dir(&dir, ENTRY_SIZE, 2 * ENTRY_SIZE)?;
+        }
+        self.sync(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::block::{RamDisk, SECTOR_SIZE};
+
+    const SECTORS: usize = 2048;
+    const RESERVED: usize = 32;
+    const FAT_SECTORS: usize = 16;
+
+    fn image() -> Arc<dyn BlockDevice> {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new("fat-test", alloc::vec![0u8; SECTORS * SECTOR_SIZE].leak()));
+        let mut boot = [0u8; SECTOR_SIZE];
+        boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[32..36].copy_from_slice(&(SECTORS as u32).to_le_bytes());
+        boot[36..40].copy_from_slice(&(FAT_SECTORS as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+        disk.write_blocks(0, &boot).unwrap();
+
+        let mut info = [0u8; SECTOR_SIZE];
+        info[0..4].copy_from_slice(&FSINFO_LEAD.to_le_bytes());
+        info[484..488].copy_from_slice(&FSINFO_STRUCT.to_le_bytes());
+        info[488..492].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+        info[492..496].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+        info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+        disk.write_blocks(1, &info).unwrap();
+
+        let mut fat = [0u8; SECTOR_SIZE];
+        fat[0..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+        fat[4..8].copy_from_slice(&FAT_END.to_le_bytes());
+        fat[8..12].copy_from_slice(&FAT_END.to_le_bytes());
+        disk.write_blocks(RESERVED as u64, &fat).unwrap();
+        disk.write_blocks((RESERVED + FAT_SECTORS) as u64, &fat).unwrap();
+        disk
+    }
+
+    #[test_case]
+    fn long_names_and_cluster_chains() {
+        let disk = image();
+        let fs = Fat32Fs::mount(disk.clone()).unwrap();
+        let clusters = (SECTORS - RESERVED - 2 * FAT_SECTORS) as u64;
+        assert_eq!(fs.free_bytes(), (clusters - 1) * SECTOR_SIZE as u64);
+
+        let docs = fs.create(fs.root(), "Documents", FileType::Directory).unwrap();
+        let file = fs.create(docs, "a rather long file name.txt", FileType::File).unwrap();
+        let data: Vec<u8> = (0..1500u32).map(|i| i as u8).collect();
+        assert_eq!(fs.write(file, 0, &data), Ok(data.len()));
+        assert_eq!(fs.write(file, 2000, b"end"), Ok(3));
+
+        let fs = Fat32Fs::mount(disk).unwrap();
+        assert_eq!(fs.free_bytes(), (clusters - 6) * SECTOR_SIZE as u64);
+        let docs = fs.lookup(fs.root(), "DOCUMENTS").unwrap();
+        let file = fs.lookup(docs, "A Rather Long File Name.TXT").unwrap();
+        assert_eq!(fs.stat(file).unwrap().size, 2003);
+        let mut buf = alloc::vec![0xFFu8; 2100];
+        assert_eq!(fs.read(file, 0, &mut buf), Ok(2003));
+        assert_eq!(&buf[..1500], &data[..]);
+        assert!(buf[1500..2000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[2000..2003], b"end");
+    }
+
+    #[test_case]
+    fn remove_and_rename_free_clusters() {
+        let fs = Fat32Fs::mount(image()).unwrap();
+        let free = fs.free_bytes();
+        let dir = fs.create(fs.root(), "dir", FileType::Directory).unwrap();
+        let file = fs.create(dir, "notes.txt", FileType::File).unwrap();
+        fs.write(file, 0, &[7u8; 1200]).unwrap();
+        assert_eq!(fs.remove(fs.root(), "dir"), Err(FsError::NotEmpty));
+
+        fs.rename(dir, "notes.txt", fs.root(), "NOTES.TXT").unwrap();
+        assert_eq!(fs.lookup(dir, "notes.txt"), Err(FsError::NotFound));
+        assert_eq!(fs.lookup(fs.root(), "notes.txt"), Ok(file));
+        fs.truncate(file, 10).unwrap();
+        assert_eq!(fs.free_bytes(), free - 2 * SECTOR_SIZE as u64);
+
+        fs.remove(fs.root(), "dir").unwrap();
+        fs.remove(fs.root(), "NOTES.TXT").unwrap();
+        assert_eq!(fs.free_bytes(), free);
+        assert!(fs.readdir(fs.root()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod vfs;
+pub mod ustar;
+pub mod tmpfs;
+pub mod fat32;
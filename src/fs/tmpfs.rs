@@ -0,0 +1,295 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::fs::vfs::{DirEntry, FileSystem, FileType, FsError, InodeId, Stat};
+use crate::system::memory::{with_mm, FRAME_SIZE};
+
+struct TmpNode {
+    kind: FileType,
+    parent: InodeId,
+    children: Vec<(String, InodeId)>,
+    pages: Vec<usize>,
+    size: u64,
+}
+
+struct TmpInner {
+    nodes: Vec<Option<TmpNode>>,
+}
+
+pub struct TmpFs {
+    inner: Mutex<TmpInner>,
+}
+
+fn alloc_page() -> Result<usize, FsError> {
+    let frame = with_mm(|mm| mm.alloc_frames(1)).flatten().ok_or(FsError::NoSpace)?;
+    unsafe { core::ptr::write_bytes(frame, 0, FRAME_SIZE); }
+    Ok(frame as usize)
+}
+
+fn free_pages(pages: &[usize]) {
+    with_mm(|mm| {
+        for &page in pages {
+            mm.free_frame(page as *mut u8);
+        }
+    });
+}
+
+impl TmpNode {
+    fn new(kind: FileType, parent: InodeId) -> Self {
+        TmpNode { kind, parent, children: Vec::new(), pages: Vec::new(), size: 0 }
+    }
+
+    fn child(&self, name: &str) -> Option<InodeId> {
+        self.children.iter().find(|(n, _)| n == name).map(|&(_, id)| id)
+    }
+
+    fn resize(&mut self, size: u64) -> Result<(), FsError> {
+        let pages = (size as usize + FRAME_SIZE - 1) / FRAME_SIZE;
+        while self.pages.len() < pages {
+            let page = alloc_page()?;
+            self.pages.push(page);
+        }
+        if pages < self.pages.len() {
+            free_pages(&self.pages[pages..]);
+            self.pages.truncate(pages);
+        }
+        let tail = size as usize % FRAME_SIZE;
+        if size < self.size && tail != 0 {
+            let page = self.pages[pages - 1];
+            unsafe { core::ptr::write_bytes((page + tail) as *mut u8, 0, FRAME_SIZE - tail); }
+        }
+        self.size = size;
+        Ok(())
+    }
+}
+
+impl TmpInner {
+    fn node(&self, inode: InodeId) -> Result<&TmpNode, FsError> {
+        self.nodes.get(inode as usize).and_then(|n| n.as_ref()).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, inode: InodeId) -> Result<&mut TmpNode, FsError> {
+        self.nodes.get_mut(inode as usize).and_then(|n| n.as_mut()).ok_or(FsError::NotFound)
+    }
+
+    fn dir(&self, inode: InodeId) -> Result<&TmpNode, FsError> {
+        let node = self.node(inode)?;
+        if node.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(node)
+    }
+
+    fn insert(&mut self, node: TmpNode) -> InodeId {
+        match self.nodes.iter().position(|n| n.is_none()) {
+            Some(free) => {
+                self.nodes[free] = Some(node);
+                free as InodeId
+            }
+            None => {
+                self.nodes.push(Some(node));
+                (self.nodes.len() - 1) as InodeId
+            }
+        }
+    }
+
+    fn release(&mut self, inode: InodeId) {
+        if let Some(node) = self.nodes.get_mut(inode as usize).and_then(|n| n.take()) {
+            free_pages(&node.pages);
+        }
+    }
+
+    fn is_ancestor(&self, ancestor: InodeId, mut inode: InodeId) -> bool {
+        loop {
+            if inode == ancestor {
+                return true;
+            }
+            match self.node(inode) {
+                Ok(node) if node.parent != inode => inode = node.parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        TmpFs {
+            inner: Mutex::new(TmpInner { nodes: alloc::vec![Some(TmpNode::new(FileType::Directory, 0))] }),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> InodeId {
+        0
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        self.inner.lock().dir(dir)?.child(name).ok_or(FsError::NotFound)
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError> {
+        let inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        Ok(Stat { inode, kind: node.kind, size: node.size })
+    }
+
+    fn read(&self, inode: InodeId, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset.saturating_add(buf.len() as u64).min(node.size) as usize;
+        let mut pos = offset as usize;
+        while pos < end {
+            let page = node.pages[pos / FRAME_SIZE];
+            let chunk = (FRAME_SIZE - pos % FRAME_SIZE).min(end - pos);
+            let dst = &mut buf[pos - offset as usize..][..chunk];
+            unsafe { core::ptr::copy_nonoverlapping((page + pos % FRAME_SIZE) as *const u8, dst.as_mut_ptr(), chunk); }
+            pos += chunk;
+        }
+        Ok(end.saturating_sub(offset as usize))
+    }
+
+    fn write(&self, inode: InodeId, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+        let node = inner.node_mut(inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(FsError::InvalidArgument)?;
+        if end > node.size {
+            node.resize(end)?;
+        }
+        let mut pos = offset as usize;
+        while pos < end as usize {
+            let page = node.pages[pos / FRAME_SIZE];
+            let chunk = (FRAME_SIZE - pos % FRAME_SIZE).min(end as usize - pos);
+            let src = &buf[pos - offset as usize..][..chunk];
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), (page + pos % FRAME_SIZE) as *mut u8, chunk); }
+            pos += chunk;
+        }
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, inode: InodeId, size: u64) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let node = inner.node_mut(inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        node.resize(size)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError> {
+        let inner = self.inner.lock();
+        let node = inner.dir(dir)?;
+        Ok(node.children.iter().map(|(name, id)| DirEntry {
+            name: name.clone(),
+            kind: inner.node(*id).map(|n| n.kind).unwrap_or(FileType::File),
+        }).collect())
+    }
+
+    fn create(&self, dir: InodeId, name: &str, kind: FileType) -> Result<InodeId, FsError> {
+        let mut inner = self.inner.lock();
+        if inner.dir(dir)?.child(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = inner.insert(TmpNode::new(kind, dir));
+        inner.node_mut(dir)?.children.push((String::from(name), inode));
+        Ok(inode)
+    }
+
+    fn remove(&self, dir: InodeId, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let inode = inner.dir(dir)?.child(name).ok_or(FsError::NotFound)?;
+        if !inner.node(inode)?.children.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        inner.node_mut(dir)?.children.retain(|(n, _)| n != name);
+        inner.release(inode);
+        Ok(())
+    }
+
+    fn rename(&self, from_dir: InodeId, from: &str, to_dir: InodeId, to: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let inode = inner.dir(from_dir)?.child(from).ok_or(FsError::NotFound)?;
+        let kind = inner.node(inode)?.kind;
+        inner.dir(to_dir)?;
+        if kind == FileType::Directory && inner.is_ancestor(inode, to_dir) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if let Some(existing) = inner.dir(to_dir)?.child(to) {
+            if existing == inode {
+                return Ok(());
+            }
+            let target = inner.node(existing)?;
+            match (kind, target.kind) {
+                (FileType::File, FileType::Directory) => return Err(FsError::IsADirectory),
+                (FileType::Directory, FileType::File) => return Err(FsError::NotADirectory),
+                (FileType::Directory, FileType::Directory) if !target.children.is_empty() => return Err(FsError::NotEmpty),
+                _ => {}
+            }
+            inner.node_mut(to_dir)?.children.retain(|(n, _)| n != to);
+            inner.release(existing);
+        }
+
+        inner.node_mut(from_dir)?.children.retain(|(n, _)| n != from);
+        inner.node_mut(to_dir)?.children.push((String::from(to), inode));
+        inner.node_mut(inode)?.parent = to_dir;
+        Ok(())
+    }
+}
+
+impl Drop for TmpFs {
+    fn drop(&mut self) {
+        for node in self.inner.lock().nodes.iter().flatten() {
+            free_pages(&node.pages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn write_grows_and_truncate_zeroes() {
+        let fs = TmpFs::new();
+        let file = fs.create(fs.root(), "data", FileType::File).unwrap();
+        let big = [0xAAu8; FRAME_SIZE + 100];
+        assert_eq!(fs.write(file, 10, &big), Ok(big.len()));
+        assert_eq!(fs.stat(file).unwrap().size, (FRAME_SIZE + 110) as u64);
+
+        fs.truncate(file, 12).unwrap();
+        fs.truncate(file, 20).unwrap();
+        let mut buf = [0xFFu8; 32];
+        assert_eq!(fs.read(file, 0, &mut buf), Ok(20));
+        assert_eq!(&buf[..12], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xAA, 0xAA]);
+        assert!(buf[12..20].iter().all(|&b| b == 0));
+    }
+
+    #[test_case]
+    fn rename_and_remove() {
+        let fs = TmpFs::new();
+        let dir = fs.create(fs.root(), "dir", FileType::Directory).unwrap();
+        let file = fs.create(dir, "a", FileType::File).unwrap();
+        fs.rename(dir, "a", fs.root(), "b").unwrap();
+        assert_eq!(fs.lookup(fs.root(), "b"), Ok(file));
+        assert_eq!(fs.lookup(dir, "a"), Err(FsError::NotFound));
+        assert_eq!(fs.rename(fs.root(), "dir", dir, "inner"), Err(FsError::InvalidArgument));
+
+        fs.create(dir, "c", FileType::File).unwrap();
+        assert_eq!(fs.remove(fs.root(), "dir"), Err(FsError::NotEmpty));
+        fs.remove(dir, "c").unwrap();
+        fs.remove(fs.root(), "dir").unwrap();
+        assert_eq!(fs.readdir(fs.root()).unwrap().len(), 1);
+    }
+}
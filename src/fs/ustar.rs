@@ -0,0 +1,140 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::fs::vfs::{DirEntry, FileSystem, FileType, FsError, InodeId, Stat};
+use crate::system::initrd::{EntryKind, Initrd};
+
+struct Node {
+    name: String,
+    kind: FileType,
+    data: &'static [u8],
+    children: Vec<usize>,
+}
+
+pub struct UstarFs {
+    nodes: Vec<Node>,
+}
+
+impl UstarFs {
+    pub fn new(initrd: &Initrd) -> Self {
+        let mut fs = UstarFs {
+            nodes: alloc::vec![Node { name: String::new(), kind: FileType::Directory, data: &[], children: Vec::new() }],
+        };
+        for entry in initrd.entries() {
+            let kind = match entry.kind {
+                EntryKind::File => FileType::File,
+                EntryKind::Directory => FileType::Directory,
+                EntryKind::Other => continue,
+            };
+            let path = entry.path();
+            let (parent, name) = match path.rsplit_once('/') {
+                Some((parent, name)) => (fs.make_dirs(parent), name),
+                None => (0, path.as_str()),
+            };
+            match fs.child(parent, name) {
+                Some(existing) if kind == FileType::File => fs.nodes[existing].data = entry.data,
+                Some(_) => {}
+                None => { fs.insert(parent, name, kind, entry.data); }
+            }
+        }
+        fs
+    }
+
+    fn child(&self, dir: usize, name: &str) -> Option<usize> {
+        self.nodes[dir].children.iter().copied().find(|&c| self.nodes[c].name == name)
+    }
+
+    fn insert(&mut self, dir: usize, name: &str, kind: FileType, data: &'static [u8]) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(Node { name: String::from(name), kind, data, children: Vec::new() });
+        self.nodes[dir].children.push(index);
+        index
+    }
+
+    fn make_dirs(&mut self, path: &str) -> usize {
+        let mut dir = 0;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            dir = match self.child(dir, part) {
+                Some(child) => child,
+                None => self.insert(dir, part, FileType::Directory, &[]),
+            };
+        }
+        dir
+    }
+
+    fn node(&self, inode: InodeId) -> Result<&Node, FsError> {
+        self.nodes.get(inode as usize).ok_or(FsError::NotFound)
+    }
+}
+
+impl FileSystem for UstarFs {
+    fn name(&self) -> &str {
+        "ustar"
+    }
+
+    fn root(&self) -> InodeId {
+        0
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        let node = self.node(dir)?;
+        if node.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        self.child(dir as usize, name).map(|c| c as InodeId).ok_or(FsError::NotFound)
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError> {
+        let node = self.node(inode)?;
+        Ok(Stat { inode, kind: node.kind, size: node.data.len() as u64 })
+    }
+
+    fn read(&self, inode: InodeId, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node(inode)?;
+        if node.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let start = (offset as usize).min(node.data.len());
+        let len = buf.len().min(node.data.len() - start);
+        buf[..len].copy_from_slice(&node.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError> {
+        let node = self.node(dir)?;
+        if node.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(node.children.iter().map(|&c| DirEntry {
+            name: self.nodes[c].name.clone(),
+            kind: self.nodes[c].kind,
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ustar_archive;
+
+    #[test_case]
+    fn builds_implicit_directories() {
+        let initrd = Initrd::new(ustar_archive(&[("bin/tools/hello", b"hi")]));
+        let fs = UstarFs::new(&initrd);
+        let bin = fs.lookup(fs.root(), "bin").unwrap();
+        let tools = fs.lookup(bin, "tools").unwrap();
+        let hello = fs.lookup(tools, "hello").unwrap();
+        assert_eq!(fs.stat(tools).unwrap().kind, FileType::Directory);
+        assert_eq!(fs.stat(hello).unwrap().size, 2);
+    }
+
+    #[test_case]
+    fn reads_at_offset() {
+        let initrd = Initrd::new(ustar_archive(&[("motd", b"hello world")]));
+        let fs = UstarFs::new(&initrd);
+        let motd = fs.lookup(fs.root(), "motd").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(fs.read(motd, 6, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(fs.write(motd, 0, b"x"), Err(FsError::ReadOnly));
+    }
+}
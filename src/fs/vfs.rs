@@ -0,0 +1,424 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::system::sched;
+
+pub type InodeId = u64;
+
+pub const O_READ: u32 = 1 << 0;
+pub const O_WRITE: u32 = 1 << 1;
+pub const O_CREATE: u32 = 1 << 2;
+pub const O_TRUNC: u32 = 1 << 3;
+pub const O_APPEND: u32 = 1 << 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    ReadOnly,
+    InvalidPath,
+    InvalidArgument,
+    BadDescriptor,
+    Busy,
+    CrossDevice,
+    NoSpace,
+    Io,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub inode: InodeId,
+    pub kind: FileType,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &str;
+    fn root(&self) -> InodeId;
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError>;
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError>;
+    fn read(&self, inode: InodeId, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError>;
+
+    fn write(&self, _inode: InodeId, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _inode: InodeId, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create(&self, _dir: InodeId, _name: &str, _kind: FileType) -> Result<InodeId, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove(&self, _dir: InodeId, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _from_dir: InodeId, _from: &str, _to_dir: InodeId, _to: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+#[derive(Clone)]
+pub struct Vnode {
+    pub fs: Arc<dyn FileSystem>,
+    pub inode: InodeId,
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+struct OpenFile {
+    owner: usize,
+    node: Vnode,
+    offset: u64,
+    flags: u32,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+static FILES: Mutex<Vec<Option<OpenFile>>> = Mutex::new(Vec::new());
+static CWD: Mutex<String> = Mutex::new(String::new());
+
+pub fn normalize(base: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    let start = if path.starts_with('/') { "" } else { base };
+    for part in start.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop(); }
+            _ => parts.push(part),
+        }
+    }
+    let mut out = String::new();
+    for part in parts {
+        out.push('/');
+        out.push_str(part);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+fn is_under(path: &str, mount: &str) -> bool {
+    mount == "/" || path == mount || (path.starts_with(mount) && path.as_bytes()[mount.len()] == b'/')
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize("/", path);
+    let has_root = interrupts::without_interrupts(|| !MOUNTS.lock().is_empty());
+    if !has_root && path != "/" {
+        return Err(FsError::NotFound);
+    }
+    if has_root && stat(&path)?.kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|m| m.path == path) {
+            return Err(FsError::Busy);
+        }
+        mounts.push(Mount { path, fs });
+        Ok(())
+    })
+}
+
+pub fn mounts() -> Vec<(String, String)> {
+    interrupts::without_interrupts(|| {
+        MOUNTS.lock().iter().map(|m| (m.path.clone(), String::from(m.fs.name()))).collect()
+    })
+}
+
+pub fn cwd() -> String {
+    let cwd = interrupts::without_interrupts(|| CWD.lock().clone());
+    if cwd.is_empty() { String::from("/") } else { cwd }
+}
+
+pub fn absolute(path: &str) -> String {
+    normalize(&cwd(), path)
+}
+
+pub fn chdir(path: &str) -> Result<(), FsError> {
+    let path = absolute(path);
+    if stat(&path)?.kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    interrupts::without_interrupts(|| *CWD.lock() = path);
+    Ok(())
+}
+
+pub fn resolve(path: &str) -> Result<Vnode, FsError> {
+    let path = absolute(path);
+    let (fs, rest) = interrupts::without_interrupts(|| {
+        let mounts = MOUNTS.lock();
+        let m = mounts.iter()
+            .filter(|m| is_under(&path, &m.path))
+            .max_by_key(|m| m.path.len())
+            .ok_or(FsError::NotFound)?;
+        let rest = if m.path == "/" { path.clone() } else { String::from(&path[m.path.len()..]) };
+        Ok::<_, FsError>((m.fs.clone(), rest))
+    })?;
+
+    let mut inode = fs.root();
+    for part in rest.split('/').filter(|p| !p.is_empty()) {
+        if fs.stat(inode)?.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        inode = fs.lookup(inode, part)?;
+    }
+    Ok(Vnode { fs, inode })
+}
+
+pub fn resolve_parent(path: &str) -> Result<(Vnode, String), FsError> {
+    let path = absolute(path);
+    let (parent, name) = path.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+    if name.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let dir = resolve(if parent.is_empty() { "/" } else { parent })?;
+    if dir.fs.stat(dir.inode)?.kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((dir, String::from(name)))
+}
+
+pub fn stat(path: &str) -> Result<Stat, FsError> {
+    let node = resolve(path)?;
+    node.fs.stat(node.inode)
+}
+
+pub fn readdir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let node = resolve(path)?;
+    let mut entries = node.fs.readdir(node.inode)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn is_mount_point(path: &str) -> bool {
+    interrupts::without_interrupts(|| MOUNTS.lock().iter().any(|m| m.path == path))
+}
+
+fn is_open(path: &str) -> bool {
+    let Ok(node) = resolve(path) else { return false };
+    interrupts::without_interrupts(|| {
+        FILES.lock().iter().flatten().any(|f| Arc::ptr_eq(&f.node.fs, &node.fs) && f.node.inode == node.inode)
+    })
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    let (dir, name) = resolve_parent(path)?;
+    dir.fs.create(dir.inode, &name, FileType::Directory).map(|_| ())
+}
+
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let path = absolute(path);
+    if is_mount_point(&path) || is_open(&path) {
+        return Err(FsError::Busy);
+    }
+    let (dir, name) = resolve_parent(&path)?;
+    dir.fs.remove(dir.inode, &name)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let (from, to) = (absolute(from), absolute(to));
+    if is_mount_point(&from) || is_mount_point(&to) || is_open(&to) {
+        return Err(FsError::Busy);
+    }
+    let (from_dir, from_name) = resolve_parent(&from)?;
+    let (to_dir, to_name) = resolve_parent(&to)?;
+    if !Arc::ptr_eq(&from_dir.fs, &to_dir.fs) {
+        return Err(FsError::CrossDevice);
+    }
+    from_dir.fs.rename(from_dir.inode, &from_name, to_dir.inode, &to_name)
+}
+
+pub fn open(path: &str, flags: u32) -> Result<usize, FsError> {
+    let node = match resolve(path) {
+        Ok(node) => node,
+        Err(FsError::NotFound) if flags & O_CREATE != 0 => {
+            let (dir, name) = resolve_parent(path)?;
+            let inode = dir.fs.create(dir.inode, &name, FileType::File)?;
+            Vnode { inode, ..dir }
+        }
+        Err(e) => return Err(e),
+    };
+
+    let stat = node.fs.stat(node.inode)?;
+    if stat.kind == FileType::Directory && flags & O_WRITE != 0 {
+        return Err(FsError::IsADirectory);
+    }
+    if flags & O_TRUNC != 0 && flags & O_WRITE != 0 {
+        node.fs.truncate(node.inode, 0)?;
+    }
+
+    let file = OpenFile { owner: sched::current_pid(), node, offset: 0, flags };
+    Ok(interrupts::without_interrupts(|| {
+        let mut files = FILES.lock();
+        match files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                files[fd] = Some(file);
+                fd
+            }
+            None => {
+                files.push(Some(file));
+                files.len() - 1
+            }
+        }
+    }))
+}
+
+fn with_file<R>(fd: usize, f: impl FnOnce(&mut OpenFile) -> R) -> Result<R, FsError> {
+    let owner = sched::current_pid();
+    interrupts::without_interrupts(|| {
+        let mut files = FILES.lock();
+        let file = files.get_mut(fd).and_then(|f| f.as_mut()).filter(|f| f.owner == owner);
+        file.map(f).ok_or(FsError::BadDescriptor)
+    })
+}
+
+pub fn close(fd: usize) -> Result<(), FsError> {
+    let owner = sched::current_pid();
+    interrupts::without_interrupts(|| {
+        let mut files = FILES.lock();
+        match files.get_mut(fd) {
+            Some(slot) if slot.as_ref().map_or(false, |f| f.owner == owner) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(FsError::BadDescriptor),
+        }
+    })
+}
+
+pub fn close_all(owner: usize) {
+    interrupts::without_interrupts(|| {
+        for slot in FILES.lock().iter_mut().filter(|f| f.as_ref().map_or(false, |f| f.owner == owner)) {
+            *slot = None;
+        }
+    })
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    let (node, offset, flags) = with_file(fd, |f| (f.node.clone(), f.offset, f.flags))?;
+    if flags & O_READ == 0 {
+        return Err(FsError::BadDescriptor);
+    }
+    let n = node.fs.read(node.inode, offset, buf)?;
+    with_file(fd, |f| f.offset = offset + n as u64)?;
+    Ok(n)
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+    let (node, mut offset, flags) = with_file(fd, |f| (f.node.clone(), f.offset, f.flags))?;
+    if flags & O_WRITE == 0 {
+        return Err(FsError::BadDescriptor);
+    }
+    if flags & O_APPEND != 0 {
+        offset = node.fs.stat(node.inode)?.size;
+    }
+    let n = node.fs.write(node.inode, offset, buf)?;
+    with_file(fd, |f| f.offset = offset + n as u64)?;
+    Ok(n)
+}
+
+pub fn seek(fd: usize, pos: SeekFrom) -> Result<u64, FsError> {
+    let (node, offset) = with_file(fd, |f| (f.node.clone(), f.offset))?;
+    let target = match pos {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::Current(n) => offset.checked_add_signed(n),
+        SeekFrom::End(n) => node.fs.stat(node.inode)?.size.checked_add_signed(n),
+    };
+    let target = target.ok_or(FsError::InvalidArgument)?;
+    with_file(fd, |f| f.offset = target)?;
+    Ok(target)
+}
+
+pub fn fstat(fd: usize) -> Result<Stat, FsError> {
+    let node = with_file(fd, |f| f.node.clone())?;
+    node.fs.stat(node.inode)
+}
+
+pub fn read_all(path: &str) -> Result<Vec<u8>, FsError> {
+    let node = resolve(path)?;
+    let stat = node.fs.stat(node.inode)?;
+    if stat.kind == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    let mut data = alloc::vec![0u8; stat.size as usize];
+    let mut done = 0;
+    while done < data.len() {
+        let n = node.fs.read(node.inode, done as u64, &mut data[done..])?;
+        if n == 0 {
+            break;
+        }
+        done += n;
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn descriptors_read_write_seek() {
+        let _ = mkdir("/vfs-test");
+        let fd = open("/vfs-test/file", O_READ | O_WRITE | O_CREATE | O_TRUNC).unwrap();
+        assert_eq!(write(fd, b"hello world"), Ok(11));
+        assert_eq!(seek(fd, SeekFrom::Start(6)), Ok(6));
+        let mut buf = [0u8; 16];
+        assert_eq!(read(fd, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(fstat(fd).unwrap().size, 11);
+        assert_eq!(remove("/vfs-test/file"), Err(FsError::Busy));
+        close(fd).unwrap();
+
+        rename("/vfs-test/file", "/vfs-test/moved").unwrap();
+        assert_eq!(read_all("/vfs-test/../vfs-test/moved").unwrap(), b"hello world");
+        remove("/vfs-test/moved").unwrap();
+        remove("/vfs-test").unwrap();
+        assert_eq!(stat("/vfs-test").err(), Some(FsError::NotFound));
+    }
+
+    #[test_case]
+    fn normalize_handles_dots() {
+        assert_eq!(normalize("/", "/a/./b/../c"), "/a/c");
+        assert_eq!(normalize("/a/b", "../.."), "/");
+        assert_eq!(normalize("/", ".."), "/");
+    }
+
+    #[test_case]
+    fn normalize_relative_to_base() {
+        assert_eq!(normalize("/home", "docs/file"), "/home/docs/file");
+        assert_eq!(normalize("/home", "/etc//motd/"), "/etc/motd");
+    }
+}
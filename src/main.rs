@@ -1,56 +1,107 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
 
 mod assets;
 mod drivers;
 mod system;
 mod commands;
+mod fs;
+#[cfg(test)]
+mod testing;
 
 use uefi::prelude::*;
 use spin::{Mutex, Once};
 
-use crate::system::gdt::Gdt;
+use crate::system::gdt::{Gdt, TaskStateSegment};
 use crate::system::idt::Idt;
 use crate::system::memory::MemoryManager;
 use crate::system::console::Console;
-use crate::system::apic::{init_lapic, IoApic};
+use crate::system::apic::{self, init_lapic};
+use crate::drivers::keyboard::Keyboard;
+use crate::drivers::serial;
 use crate::system::time;
+use crate::system::heap;
+use crate::system::paging::{self, AddressSpace, KernelLayout};
+use crate::system::smp;
+use crate::system::sched;
+use crate::system::acpi::{self, AcpiInfo};
+use crate::system::initrd::{self, Initrd};
 use crate::system::graphic::Backend;
+use crate::fs::{tmpfs::TmpFs, ustar::UstarFs, vfs};
+use crate::drivers::block::{self, RamDisk};
+use crate::drivers::{ahci, gpu_fb, nvme, pci, uefi_fb, virtio_blk};
+use alloc::sync::Arc;
 use uefi::boot::MemoryType;
+use uefi::proto::console::gop::PixelFormat;
 use uefi::mem::memory_map::MemoryMap;
 
 static GDT_INSTANCE: Once<Gdt> = Once::new();
-static IDT_INSTANCE: Once<Idt> = Once::new();
+pub static IDT_INSTANCE: Once<Idt> = Once::new();
 pub static MM_INSTANCE: Once<Mutex<MemoryManager>> = Once::new();
+pub static KERNEL_SPACE: Once<Mutex<AddressSpace>> = Once::new();
+pub static ACPI_INSTANCE: Once<AcpiInfo> = Once::new();
+pub static INITRD: Once<Initrd> = Once::new();
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+
+const SERIAL_BAUD: u32 = 115200;
+const CURSOR_BLINK_MS: u64 = 500;
 
 #[entry]
 fn main() -> Status {
     uefi::helpers::init().unwrap();
+    let serial_ok = serial::init(SERIAL_BAUD);
 
     let gop_handle = uefi::boot::get_handle_for_protocol::<uefi::proto::console::gop::GraphicsOutput>()
         .expect("Failed to get GOP handle");
     let mut gop = uefi::boot::open_protocol_exclusive::<uefi::proto::console::gop::GraphicsOutput>(gop_handle)
         .expect("Failed to open GOP");
 
-    let mut max_resolution = (0usize, 0usize);
-    let mut best_mode = None;
+    let mut modes = uefi_fb::GopModes::new();
     for mode in gop.modes() {
         let info = mode.info();
-        let (w, h) = info.resolution();
-        if w * h > max_resolution.0 * max_resolution.1 {
-            max_resolution = (w, h);
-            best_mode = Some(mode);
-        }
+        let (width, height) = info.resolution();
+        let format = match info.pixel_format() {
+            PixelFormat::Rgb => "RGB",
+            PixelFormat::Bgr => "BGR",
+            PixelFormat::Bitmask => "mask",
+            PixelFormat::BltOnly => "blt",
+        };
+        modes.push(uefi_fb::GopMode { width, height, stride: info.stride(), format });
     }
-    if let Some(mode) = best_mode {
-        let _ = gop.set_mode(&mode);
+    let preferred = initrd::load_esp_file(cstr16!("mode.cfg")).ok()
+        .and_then(|cfg| core::str::from_utf8(cfg).ok().and_then(uefi_fb::parse_resolution));
+    let selected = preferred.and_then(|(w, h)| modes.find(w, h)).or_else(|| modes.largest());
+    if let Some(target) = selected.map(|i| modes.as_slice()[i]) {
+        if let Some(mode) = gop.modes().find(|m| m.info().resolution() == (target.width, target.height)) {
+            if gop.set_mode(&mode).is_ok() {
+                modes.current = selected;
+            }
+        }
     }
 
     let mode_info = gop.current_mode_info();
     let (width, height) = mode_info.resolution();
+    if modes.current.is_none() {
+        modes.current = modes.as_slice().iter().position(|m| (m.width, m.height) == (width, height));
+    }
+    uefi_fb::set_modes(modes);
     let stride = mode_info.stride();
     let fb_ptr = gop.frame_buffer().as_mut_ptr();
+    let fb_size = gop.frame_buffer().size();
+
+    let (image_base, image_size) = {
+        let loaded_image = uefi::boot::open_protocol_exclusive::<uefi::proto::loaded_image::LoadedImage>(
+            uefi::boot::image_handle(),
+        ).expect("Failed to open LoadedImage");
+        let (base, size) = loaded_image.info();
+        (base as usize, size as usize)
+    };
+    system::symbols::set_image_base(image_base);
 
     unsafe {
         system::GLOBAL_CONSOLE = Some(Console::new(
@@ -68,11 +119,26 @@ fn main() -> Status {
 
     log!("OK", "FigOS Kernel booting");
     log!("INFO", "Screen Resolution set to : {}x{}", width, height);
+    if serial_ok {
+        log!("OK", "Serial console on COM1 at {} baud", SERIAL_BAUD);
+    } else {
+        log!("WARN", "Serial port COM1 not detected");
+    }
+
+    log!("INFO", "Loading initrd from ESP...");
+    match initrd::load_from_esp() {
+        Ok(data) => {
+            let initrd = INITRD.call_once(|| Initrd::new(data));
+            log!("OK", "Initrd loaded ({} bytes, {} entries)", initrd.size(), initrd.entries().count());
+        }
+        Err(e) => log!("WARN", "Initrd unavailable: {:?}", e),
+    }
+    let disk_image = initrd::load_esp_file(cstr16!("disk.img")).ok();
 
     unsafe { core::arch::asm!("cli"); }
 
     log!("INFO", "Initializing GDT...");
-    let gdt = GDT_INSTANCE.call_once(|| Gdt::new());
+    let gdt = GDT_INSTANCE.call_once(|| Gdt::new(unsafe { &*core::ptr::addr_of!(BSP_TSS) }));
     unsafe { gdt.load(); }
     log!("OK", "GDT loaded successfully");
 
@@ -81,21 +147,41 @@ fn main() -> Status {
     unsafe { idt.load(); }
     log!("OK", "IDT loaded successfully");
 
+    log!("INFO", "Parsing ACPI tables...");
+    match acpi::init(acpi::find_rsdp()) {
+        Ok(info) => {
+            log!("OK", "ACPI {} tables found (revision {})", info.tables.len(), info.revision);
+            ACPI_INSTANCE.call_once(|| info);
+        }
+        Err(e) => log!("WARN", "ACPI unavailable: {:?}", e),
+    }
+    let madt = ACPI_INSTANCE.get().and_then(|a| a.madt.as_ref());
+    let lapic_base = madt.map(|m| m.local_apic_address as usize).unwrap_or(apic::LAPIC_BASE);
+    let ioapic_base = madt
+        .and_then(|m| m.io_apics.first())
+        .map(|io| io.address as usize)
+        .unwrap_or(apic::IOAPIC_BASE);
+
     log!("INFO", "Initializing LAPIC...");
-    unsafe { init_lapic(); }
+    unsafe { init_lapic(lapic_base); }
     log!("OK", "LAPIC initialized successfully");
 
     log!("INFO", "Initializing IO APIC...");
-    let _ioapic = unsafe { IoApic::init() };
-    log!("OK", "IO APIC initialized successfully");
+    let ioapic_count = unsafe { apic::init_ioapics(madt, ioapic_base) };
+    log!("OK", "{} IO APIC(s) initialized successfully", ioapic_count);
+
+    apic::register_irq(1, Keyboard::handle_interrupt).expect("Failed to route keyboard IRQ");
+    if serial_ok {
+        apic::register_irq(serial::COM1_IRQ, serial::handle_interrupt).expect("Failed to route serial IRQ");
+    }
 
     log!("OK", "Enabling interrupts...");
     unsafe { core::arch::asm!("sti"); }
 
     log!("INFO", "Initializing time subsystem...");
-    time::init();
-    time::calibrate_uefi();
-    log!("OK", "Time subsystem ready");
+    time::init(ACPI_INSTANCE.get().and_then(|a| a.hpet.as_ref()));
+    log!("OK", "Time subsystem ready ({} at {} Hz, TSC {} MHz)",
+        time::timer_mode().name(), time::TICK_HZ, time::tsc_frequency() / 1_000_000);
 
     log!("OK", "Fetching memory map...");
     let memory_map = uefi::boot::memory_map(MemoryType::LOADER_DATA)
@@ -128,6 +214,9 @@ fn main() -> Status {
     log!("INFO", "Initializing Memory Manager...");
     let mut mm = unsafe { MemoryManager::new(bitmap_addr, max_addr) };
 
+    let stack_ptr: usize;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) stack_ptr); }
+
     for desc in memory_map.entries() {
         if desc.ty == MemoryType::CONVENTIONAL
             || desc.ty == MemoryType::BOOT_SERVICES_CODE
@@ -135,6 +224,9 @@ fn main() -> Status {
         {
             let phys = desc.phys_start as usize;
             let size = (desc.page_count as usize) * 4096;
+            if stack_ptr >= phys && stack_ptr < phys + size {
+                continue;
+            }
             mm.free_region(phys, size);
         }
     }
@@ -143,53 +235,131 @@ fn main() -> Status {
     for _ in 0..bitmap_frame_count {
         mm.alloc_frames(1);
     }
+    let trampoline = mm.alloc_frames_below(1, 0x100000);
 
-    unsafe {
+    let final_map = unsafe {
         core::arch::asm!("cli");
         core::arch::asm!("out 0x21, al", in("al") 0xFFu8);
         core::arch::asm!("out 0xA1, al", in("al") 0xFFu8);
-        let _ = uefi::boot::exit_boot_services(Some(MemoryType::LOADER_DATA));
+        let map = uefi::boot::exit_boot_services(Some(MemoryType::LOADER_DATA));
         core::arch::asm!("sti");
-    }
+        map
+    };
 
     MM_INSTANCE.call_once(|| Mutex::new(mm));
     log!("OK", "Memory Manager ready");
 
+    log!("INFO", "Initializing kernel heap...");
+    heap::init();
+    log!("OK", "Kernel heap ready");
+    unsafe { smp::init_bsp(gdt, core::ptr::addr_of_mut!(BSP_TSS), stack_ptr); }
+
+    log!("INFO", "Building kernel page tables...");
+    let mut mmio = alloc::vec![(lapic_base, 0x1000), (ioapic_base, 0x1000)];
+    if let Some(acpi) = ACPI_INSTANCE.get() {
+        if let Some(madt) = &acpi.madt {
+            mmio.extend(madt.io_apics.iter().map(|io| (io.address as usize, 0x1000)));
+        }
+        if let Some(hpet) = &acpi.hpet {
+            mmio.push((hpet.base_address as usize, 0x1000));
+        }
+    }
+    let layout = KernelLayout {
+        image_base,
+        image_size,
+        framebuffer: (fb_ptr as usize, fb_size),
+        bitmap: (bitmap_addr as usize, bitmap_size),
+        mmio,
+    };
+    let space = paging::init(&final_map, &layout).expect("Failed to build kernel page tables");
+    unsafe { space.activate(); }
+    let space = KERNEL_SPACE.call_once(|| Mutex::new(space));
+    log!("OK", "Paging enabled");
+
+    unsafe { (*core::ptr::addr_of_mut!(BSP_TSS)).alloc_ist_stacks(&mut space.lock()) }
+        .expect("Failed to allocate IST stacks");
+    log!("OK", "IST stacks ready");
+
+    system::syscall::init();
+    log!("OK", "System calls enabled");
+
+    if let (Some(madt), Some(trampoline)) = (madt, trampoline) {
+        log!("INFO", "Starting application processors...");
+        let cpus = unsafe { smp::start_aps(madt, trampoline as usize) };
+        log!("OK", "{} CPU(s) online", cpus);
+    }
+
+    log!("INFO", "Starting scheduler...");
+    sched::init();
+    log!("OK", "Scheduler running");
+
+    log!("INFO", "Scanning PCI bus...");
+    let mcfg = ACPI_INSTANCE.get().map(|a| a.mcfg.as_slice()).unwrap_or(&[]);
+    let pci_count = pci::init(mcfg);
+    log!("OK", "{} PCI device(s) found via {}", pci_count, if pci::uses_ecam() { "ECAM" } else { "port I/O" });
+    pci::register_driver(&gpu_fb::PCI_DRIVER);
+    if pci::register_driver(&uefi_fb::PCI_DRIVER) > 0 {
+        if let Some(dev) = uefi_fb::display_device() {
+            log!("OK", "GOP framebuffer provided by {} {:04x}:{:04x}", dev.address, dev.vendor_id, dev.device_id);
+        }
+    }
+    pci::register_driver(&ahci::PCI_DRIVER);
+    pci::register_driver(&nvme::PCI_DRIVER);
+    pci::register_driver(&virtio_blk::PCI_DRIVER);
+
+    log!("INFO", "Mounting filesystems...");
+    vfs::mount("/", Arc::new(TmpFs::new())).expect("Failed to mount root filesystem");
+    if let Some(initrd) = INITRD.get() {
+        vfs::mkdir("/initrd").and_then(|_| vfs::mount("/initrd", Arc::new(UstarFs::new(initrd))))
+            .expect("Failed to mount initrd");
+    }
+    log!("OK", "Root tmpfs mounted{}", if INITRD.get().is_some() { ", initrd on /initrd" } else { "" });
+    if let Some(image) = disk_image {
+        let size = image.len();
+        let partitions = block::register(Arc::new(RamDisk::new("ram0", image)));
+        log!("OK", "Disk image registered as ram0 ({} KiB, {} partition(s))", size / 1024, partitions);
+    }
+
     log!("OK", "Kernel ready");
+
+    #[cfg(test)]
+    test_main();
     log!("OK", "Keyboard subsystem ready");
 
     print!("> ");
-    unsafe {
-        if let Some(ref mut console) = system::GLOBAL_CONSOLE {
-            console.lock_prompt();
-        }
+    system::with_console(|console| console.lock_prompt());
+
+    sched::spawn("cursor", cursor_thread).expect("Failed to spawn cursor thread");
+    let shell = sched::spawn("shell", shell_thread).expect("Failed to spawn shell thread");
+    shell.join();
+
+    loop {
+        x86_64::instructions::hlt();
     }
+}
 
+fn cursor_thread() {
     loop {
-        unsafe {
-            if let Some(ref mut console) = system::GLOBAL_CONSOLE {
-                console.update();
-            }
-        }
+        sched::sleep_ms(CURSOR_BLINK_MS);
+        system::with_console(|console| console.blink_cursor());
+    }
+}
 
+fn shell_thread() {
+    loop {
         if let Some(c) = system::pop_key() {
             match c {
                 '\n' => {
                     commands::process_command();
                     print!("\n> ");
-                    unsafe {
-                        if let Some(ref mut console) = system::GLOBAL_CONSOLE {
-                            console.lock_prompt();
-                        }
-                    }
+                    system::with_console(|console| console.lock_prompt());
                 }
                 '\x08' => {
-                    unsafe {
-                        if let Some(ref mut console) = system::GLOBAL_CONSOLE {
-                            console.backspace();
-                            commands::delete_last_char();
-                        }
-                    }
+                    system::with_console(|console| {
+                        console.backspace();
+                        commands::delete_last_char();
+                        serial::write_str("\x08 \x08");
+                    });
                 }
                 _ => {
                     if (c >= ' ' && c <= '~') || (c as u8 >= 0x80) {
@@ -201,4 +371,4 @@ fn main() -> Status {
         }
         x86_64::instructions::hlt();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,343 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use uefi::table::cfg::ConfigTableEntry;
+
+#[derive(Clone, Copy, Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    BadRsdp,
+    BadRootTable,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApicEntry {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0x3 == 0x3
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0x3 == 0x3
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LapicNmi {
+    pub processor_uid: u32,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LapicNmi>,
+}
+
+impl Madt {
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub pm1a_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century: u8,
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub base_address: u64,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub tables: Vec<[u8; 4]>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Vec<McfgEntry>,
+}
+
+fn checksum_ok(addr: usize, len: usize) -> bool {
+    let mut sum = 0u8;
+    for i in 0..len {
+        sum = sum.wrapping_add(unsafe { *((addr + i) as *const u8) });
+    }
+    sum == 0
+}
+
+unsafe fn read<T: Copy>(addr: usize) -> T {
+    read_unaligned(addr as *const T)
+}
+
+pub fn find_rsdp() -> Option<usize> {
+    uefi::system::with_config_table(|tables| {
+        let find = |guid| tables.iter().find(|t| t.guid == guid).map(|t| t.address as usize);
+        find(ConfigTableEntry::ACPI2_GUID).or_else(|| find(ConfigTableEntry::ACPI_GUID))
+    })
+}
+
+pub fn init(rsdp_addr: Option<usize>) -> Result<AcpiInfo, AcpiError> {
+    let rsdp_addr = rsdp_addr.ok_or(AcpiError::NoRsdp)?;
+    let rsdp: Rsdp = unsafe { read(rsdp_addr) };
+
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(rsdp_addr, 20) {
+        return Err(AcpiError::BadRsdp);
+    }
+    if rsdp.revision >= 2 && !checksum_ok(rsdp_addr, rsdp.length as usize) {
+        return Err(AcpiError::BadRsdp);
+    }
+
+    let (root, entry_size, signature) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8, b"XSDT")
+    } else {
+        (rsdp.rsdt_address as usize, 4, b"RSDT")
+    };
+
+    let header: SdtHeader = unsafe { read(root) };
+    if &header.signature != signature || (header.length as usize) < size_of::<SdtHeader>()
+        || !checksum_ok(root, header.length as usize) {
+        return Err(AcpiError::BadRootTable);
+    }
+
+    let mut info = AcpiInfo {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        tables: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: Vec::new(),
+    };
+
+    let count = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for i in 0..count {
+        let entry = root + size_of::<SdtHeader>() + i * entry_size;
+        let table = unsafe {
+            if entry_size == 8 { read::<u64>(entry) as usize } else { read::<u32>(entry) as usize }
+        };
+        if table == 0 {
+            continue;
+        }
+
+        let header: SdtHeader = unsafe { read(table) };
+        if (header.length as usize) < size_of::<SdtHeader>() || !checksum_ok(table, header.length as usize) {
+            continue;
+        }
+        info.tables.push(header.signature);
+
+        unsafe {
+            match &header.signature {
+                b"APIC" => info.madt = Some(parse_madt(table, header.length as usize)),
+                b"FACP" => info.fadt = Some(parse_fadt(table, header.length as usize)),
+                b"HPET" => info.hpet = Some(parse_hpet(table)),
+                b"MCFG" => info.mcfg = parse_mcfg(table, header.length as usize),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(info)
+}
+
+unsafe fn parse_madt(table: usize, len: usize) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table + 36) as u64,
+        flags: read::<u32>(table + 40),
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+    };
+
+    let mut offset = 44;
+    while offset + 2 <= len {
+        let entry = table + offset;
+        let kind = read::<u8>(entry);
+        let entry_len = read::<u8>(entry + 1) as usize;
+        if entry_len < 2 || offset + entry_len > len {
+            break;
+        }
+
+        match kind {
+            0 => {
+                let flags = read::<u32>(entry + 4);
+                madt.local_apics.push(LocalApicEntry {
+                    processor_uid: read::<u8>(entry + 2) as u32,
+                    apic_id: read::<u8>(entry + 3) as u32,
+                    enabled: flags & 1 != 0,
+                    online_capable: flags & 2 != 0,
+                });
+            }
+            1 => madt.io_apics.push(IoApicEntry {
+                id: read::<u8>(entry + 2),
+                address: read::<u32>(entry + 4) as u64,
+                gsi_base: read::<u32>(entry + 8),
+            }),
+            2 => madt.overrides.push(InterruptOverride {
+                bus: read::<u8>(entry + 2),
+                source: read::<u8>(entry + 3),
+                gsi: read::<u32>(entry + 4),
+                flags: read::<u16>(entry + 8),
+            }),
+            4 => madt.nmis.push(LapicNmi {
+                processor_uid: read::<u8>(entry + 2) as u32,
+                flags: read::<u16>(entry + 3),
+                lint: read::<u8>(entry + 5),
+            }),
+            5 => madt.local_apic_address = read::<u64>(entry + 4),
+            9 => {
+                let flags = read::<u32>(entry + 8);
+                madt.local_apics.push(LocalApicEntry {
+                    processor_uid: read::<u32>(entry + 12),
+                    apic_id: read::<u32>(entry + 4),
+                    enabled: flags & 1 != 0,
+                    online_capable: flags & 2 != 0,
+                });
+            }
+            _ => {}
+        }
+        offset += entry_len;
+    }
+
+    madt
+}
+
+unsafe fn parse_fadt(table: usize, len: usize) -> Fadt {
+    let field = |offset: usize, size: usize| offset + size <= len;
+
+    let mut dsdt = read::<u32>(table + 40) as u64;
+    if field(140, 8) && read::<u64>(table + 140) != 0 {
+        dsdt = read::<u64>(table + 140);
+    }
+
+    let reset_register = if field(116, 12) && (read::<u32>(table + 112) & (1 << 10)) != 0 {
+        Some(read::<GenericAddress>(table + 116))
+    } else {
+        None
+    };
+
+    Fadt {
+        dsdt,
+        sci_interrupt: read::<u16>(table + 46),
+        smi_command: read::<u32>(table + 48),
+        pm1a_control_block: read::<u32>(table + 64),
+        pm_timer_block: read::<u32>(table + 76),
+        century: if field(108, 1) { read::<u8>(table + 108) } else { 0 },
+        boot_arch_flags: if field(109, 2) { read::<u16>(table + 109) } else { 0 },
+        flags: if field(112, 4) { read::<u32>(table + 112) } else { 0 },
+        reset_register,
+        reset_value: if field(128, 1) { read::<u8>(table + 128) } else { 0 },
+    }
+}
+
+unsafe fn parse_hpet(table: usize) -> Hpet {
+    let base: GenericAddress = read(table + 40);
+    Hpet {
+        event_timer_block_id: read::<u32>(table + 36),
+        base_address: base.address,
+        hpet_number: read::<u8>(table + 52),
+        minimum_tick: read::<u16>(table + 53),
+    }
+}
+
+unsafe fn parse_mcfg(table: usize, len: usize) -> Vec<McfgEntry> {
+    let mut entries = Vec::new();
+    let mut offset = 44;
+    while offset + 16 <= len {
+        let entry = table + offset;
+        entries.push(McfgEntry {
+            base_address: read::<u64>(entry),
+            segment: read::<u16>(entry + 8),
+            start_bus: read::<u8>(entry + 10),
+            end_bus: read::<u8>(entry + 11),
+        });
+        offset += 16;
+    }
+    entries
+}
@@ -1,4 +1,21 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Once;
+use crate::system::acpi::{InterruptOverride, Madt};
+
+pub const LAPIC_BASE: usize = 0xFEE00000;
+pub const IOAPIC_BASE: usize = 0xFEC00000;
+pub const TIMER_VECTOR: u8 = 0x40;
+
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_TSC_DEADLINE: u32 = 2 << 17;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
 
 pub struct LocalApic {
     base: usize,
@@ -8,14 +25,63 @@ static mut LAPIC: Option<LocalApic> = None;
 static LAPIC_READY: AtomicBool = AtomicBool::new(false);
 
 impl LocalApic {
-    pub unsafe fn init() -> Self {
-        let lapic = Self { base: 0xFEE00000 };
-        lapic.write(0xF0, lapic.read(0xF0) | 0x100 | 0xFF);
+    pub unsafe fn init(base: usize) -> Self {
+        let lapic = Self { base };
+        lapic.enable();
 
         LAPIC_READY.store(true, Ordering::SeqCst);
         lapic
     }
 
+    pub unsafe fn enable(&self) {
+        self.write(0xF0, self.read(0xF0) | 0x100 | 0xFF);
+    }
+
+    pub unsafe fn send_ipi(&self, apic_id: u8, command: u32) {
+        self.write(0x310, (apic_id as u32) << 24);
+        self.write(0x300, command);
+        while self.read(0x300) & (1 << 12) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read(0x20) >> 24) as u8 }
+    }
+
+    pub unsafe fn timer_periodic(&self, count: u32) {
+        self.write(TIMER_DIVIDE, 0x3);
+        self.write(LVT_TIMER, TIMER_VECTOR as u32 | TIMER_PERIODIC);
+        self.write(TIMER_INITIAL_COUNT, count);
+    }
+
+    pub unsafe fn timer_oneshot(&self, count: u32, masked: bool) {
+        self.write(TIMER_DIVIDE, 0x3);
+        let mask = if masked { TIMER_MASKED } else { 0 };
+        self.write(LVT_TIMER, TIMER_VECTOR as u32 | mask);
+        self.write(TIMER_INITIAL_COUNT, count);
+    }
+
+    pub unsafe fn timer_tsc_deadline(&self, deadline: u64) {
+        self.write(LVT_TIMER, TIMER_VECTOR as u32 | TIMER_TSC_DEADLINE);
+        core::arch::asm!("mfence");
+        core::arch::asm!(
+            "wrmsr",
+            in("ecx") IA32_TSC_DEADLINE,
+            in("eax") deadline as u32,
+            in("edx") (deadline >> 32) as u32,
+        );
+    }
+
+    pub unsafe fn timer_stop(&self) {
+        self.write(LVT_TIMER, TIMER_MASKED);
+        self.write(TIMER_INITIAL_COUNT, 0);
+    }
+
+    pub fn timer_current(&self) -> u32 {
+        unsafe { self.read(TIMER_CURRENT_COUNT) }
+    }
+
     pub unsafe fn end_of_interrupt(&self) {
         self.write(0xB0, 0);
     }
@@ -29,11 +95,19 @@ impl LocalApic {
     }
 }
 
-pub unsafe fn init_lapic() {
-    let lapic = LocalApic::init();
+pub unsafe fn init_lapic(base: usize) {
+    let lapic = LocalApic::init(base);
     LAPIC = Some(lapic);
 }
 
+pub fn local_apic() -> Option<&'static LocalApic> {
+    unsafe { (*core::ptr::addr_of!(LAPIC)).as_ref() }
+}
+
+pub fn lapic_id() -> u8 {
+    local_apic().map(|lapic| lapic.id()).unwrap_or(0)
+}
+
 pub unsafe fn lapic_eoi() {
     if LAPIC_READY.load(Ordering::SeqCst) {
         if let Some(ref lapic) = LAPIC {
@@ -42,26 +116,55 @@ pub unsafe fn lapic_eoi() {
     }
 }
 
+pub const IRQ_BASE_VECTOR: u8 = 32;
+pub const IRQ_COUNT: usize = 32;
+const MSI_FIRST_IRQ: usize = 24;
+
+static IRQ_HANDLERS: [AtomicUsize; IRQ_COUNT] = [const { AtomicUsize::new(0) }; IRQ_COUNT];
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+static OVERRIDES: Once<Vec<InterruptOverride>> = Once::new();
+
+#[derive(Debug)]
+pub enum IrqError {
+    OutOfRange,
+    NoIoApic,
+    AlreadyRegistered,
+    NoFreeVector,
+}
+
 pub struct IoApic {
     base: usize,
+    pub gsi_base: u32,
+    pub redirection_entries: u32,
 }
 
 impl IoApic {
-    pub unsafe fn init() -> Self {
-        let ioapic = Self { base: 0xFEC00000 };
-        ioapic.write_redirection(2, 32); 
-        ioapic.write_redirection(1, 33);
-        
+    pub unsafe fn new(base: usize, gsi_base: u32) -> Self {
+        let mut ioapic = Self { base, gsi_base, redirection_entries: 0 };
+        ioapic.redirection_entries = ((ioapic.read(0x01) >> 16) & 0xFF) + 1;
+        for pin in 0..ioapic.redirection_entries {
+            ioapic.write_redirection(pin, 1 << 16, 0);
+        }
         ioapic
     }
 
-    unsafe fn write_redirection(&self, irq: u8, vector: u8) {
-        let low_reg = 0x10 + (irq as u32) * 2;
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    unsafe fn write_redirection(&self, pin: u32, low: u32, high: u32) {
+        let low_reg = 0x10 + pin * 2;
         let high_reg = low_reg + 1;
-        
-        
-        self.write(low_reg, vector as u32);
-        self.write(high_reg, 0);
+        self.write(low_reg, 1 << 16);
+        self.write(high_reg, high);
+        self.write(low_reg, low);
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        let ioregsel = self.base as *mut u32;
+        let iowin = (self.base + 0x10) as *mut u32;
+        core::ptr::write_volatile(ioregsel, reg);
+        core::ptr::read_volatile(iowin)
     }
 
     unsafe fn write(&self, reg: u32, val: u32) {
@@ -70,4 +173,91 @@ impl IoApic {
         core::ptr::write_volatile(ioregsel, reg);
         core::ptr::write_volatile(iowin, val);
     }
-}
\ No newline at end of file
+}
+
+pub unsafe fn init_ioapics(madt: Option<&Madt>, fallback_base: usize) -> usize {
+    let ioapics = IO_APICS.call_once(|| match madt {
+        Some(madt) if !madt.io_apics.is_empty() => madt
+            .io_apics
+            .iter()
+            .map(|io| IoApic::new(io.address as usize, io.gsi_base))
+            .collect(),
+        _ => vec![IoApic::new(fallback_base, 0)],
+    });
+    OVERRIDES.call_once(|| madt.map(|m| m.overrides.clone()).unwrap_or_default());
+    ioapics.len()
+}
+
+fn resolve_irq(irq: u8) -> (u32, bool, bool) {
+    if irq >= 16 {
+        return (irq as u32, true, true);
+    }
+    let iso = OVERRIDES.get().and_then(|o| o.iter().find(|iso| iso.bus == 0 && iso.source == irq));
+    match iso {
+        Some(iso) => (iso.gsi, iso.active_low(), iso.level_triggered()),
+        None => (irq as u32, false, false),
+    }
+}
+
+pub fn register_irq(irq: u8, handler: fn()) -> Result<u8, IrqError> {
+    if irq as usize >= MSI_FIRST_IRQ {
+        return Err(IrqError::OutOfRange);
+    }
+    let slot = &IRQ_HANDLERS[irq as usize];
+    if slot.compare_exchange(0, handler as usize, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Err(IrqError::AlreadyRegistered);
+    }
+
+    let (gsi, active_low, level) = resolve_irq(irq);
+    let vector = IRQ_BASE_VECTOR + irq;
+    let ioapic = IO_APICS
+        .get()
+        .and_then(|list| list.iter().find(|io| io.handles(gsi)));
+    let Some(ioapic) = ioapic else {
+        slot.store(0, Ordering::SeqCst);
+        return Err(IrqError::NoIoApic);
+    };
+
+    let mut low = vector as u32;
+    if active_low { low |= 1 << 13; }
+    if level { low |= 1 << 15; }
+    let high = (lapic_id() as u32) << 24;
+    unsafe { ioapic.write_redirection(gsi - ioapic.gsi_base, low, high); }
+    Ok(vector)
+}
+
+pub fn register_msi(handler: fn()) -> Result<u8, IrqError> {
+    for irq in (MSI_FIRST_IRQ..IRQ_COUNT).rev() {
+        if IRQ_HANDLERS[irq].compare_exchange(0, handler as usize, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return Ok(IRQ_BASE_VECTOR + irq as u8);
+        }
+    }
+    Err(IrqError::NoFreeVector)
+}
+
+pub fn unregister_irq(irq: u8) {
+    if irq as usize >= MSI_FIRST_IRQ {
+        return;
+    }
+    let (gsi, _, _) = resolve_irq(irq);
+    if let Some(ioapic) = IO_APICS.get().and_then(|list| list.iter().find(|io| io.handles(gsi))) {
+        unsafe { ioapic.write_redirection(gsi - ioapic.gsi_base, 1 << 16, 0); }
+    }
+    IRQ_HANDLERS[irq as usize].store(0, Ordering::SeqCst);
+}
+
+pub fn unregister_msi(vector: u8) {
+    let irq = vector.wrapping_sub(IRQ_BASE_VECTOR) as usize;
+    if (MSI_FIRST_IRQ..IRQ_COUNT).contains(&irq) {
+        IRQ_HANDLERS[irq].store(0, Ordering::SeqCst);
+    }
+}
+
+pub fn dispatch_irq(irq: usize) {
+    let handler = IRQ_HANDLERS[irq].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    unsafe { lapic_eoi(); }
+}
@@ -7,7 +7,6 @@ pub struct Console {
     pub color: u32,
     pub bg_color: u32,
     pub line_start_x: usize,
-    pub ticks: u64,
     pub cursor_visible: bool,
 }
 
@@ -20,7 +19,6 @@ impl Console {
             color: 0xFFFFFF, 
             bg_color: 0x000000,
             line_start_x: 20,
-            ticks: 0,
             cursor_visible: true,
         }
     }
@@ -46,34 +44,37 @@ impl Console {
         }
     }
 
-    pub fn update(&mut self) {
-        self.ticks += 1;
-        if self.ticks % 80 == 0 {
-            self.cursor_visible = !self.cursor_visible;
-            let color = if self.cursor_visible { self.color } else { self.bg_color };
-            self.draw_cursor(color);
-            self.backend.swap_rect(self.cursor_x, self.cursor_y + 17, 8, 2);
-        }
+    pub fn blink_cursor(&mut self) {
+        self.cursor_visible = !self.cursor_visible;
+        let color = if self.cursor_visible { self.color } else { self.bg_color };
+        self.draw_cursor(color);
+        self.backend.swap_rect(self.cursor_x, self.cursor_y + 17, 8, 2);
     }
     
-    fn internal_write_char(&mut self, c: char) {
+    fn internal_write_char(&mut self, c: char) -> bool {
         if c == '\n' {
             self.new_line_no_swap();
-        } else {
-            self.backend.draw_char(c, self.cursor_x, self.cursor_y, self.color, Some(self.bg_color));
-            self.cursor_x += 9;
-            if self.cursor_x + 9 >= self.backend.width() {
-                self.new_line_no_swap();
-            }
+            return true;
+        }
+        self.backend.draw_char(c, self.cursor_x, self.cursor_y, self.color, Some(self.bg_color));
+        self.cursor_x += 9;
+        if self.cursor_x + 9 >= self.backend.width() {
+            self.new_line_no_swap();
+            return true;
         }
+        false
     }
 
     pub fn write_char(&mut self, c: char) {
         self.draw_cursor(self.bg_color); 
-        self.internal_write_char(c);
+        let scrolled = self.internal_write_char(c);
         self.cursor_visible = true;
         self.draw_cursor(self.color);
-        self.backend.swap_rect(0, self.cursor_y, self.backend.width(), 20);
+        if scrolled {
+            self.backend.swap_buffers();
+        } else {
+            self.backend.swap_rect(0, self.cursor_y, self.backend.width(), 20);
+        }
     }
 
     pub fn write_str(&mut self, s: &str) {
@@ -81,17 +82,7 @@ impl Console {
         let start_y = self.cursor_y;
         self.draw_cursor(self.bg_color);
         for c in s.chars() {
-            if c == '\n' {
-                self.new_line_no_swap();
-                scrolled = true;
-            } else {
-                self.backend.draw_char(c, self.cursor_x, self.cursor_y, self.color, Some(self.bg_color));
-                self.cursor_x += 9;
-                if self.cursor_x + 9 >= self.backend.width() {
-                    self.new_line_no_swap();
-                    scrolled = true;
-                }
-            }
+            scrolled |= self.internal_write_char(c);
         }
         self.cursor_visible = true;
         self.draw_cursor(self.color);
@@ -152,7 +143,93 @@ impl Console {
         self.backend.swap_buffers();
     }
 
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.backend.clear(self.bg_color);
+        self.backend.swap_buffers();
+        self.backend.set_virtual_res(width, height);
+        self.line_start_x = 20;
+        self.clear(self.bg_color);
+    }
+
     pub fn flush(&self) {
         self.backend.swap_buffers();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Console;
+    use crate::drivers::uefi_fb::Framebuffer;
+    use crate::system::graphic::Backend;
+
+    const WIDTH: usize = 200;
+    const HEIGHT: usize = 100;
+    static mut PIXELS: [u32; WIDTH * HEIGHT] = [0; WIDTH * HEIGHT];
+
+    fn console() -> Console {
+        let fb = Framebuffer {
+            addr: core::ptr::addr_of_mut!(PIXELS) as *mut u32,
+            width: WIDTH,
+            height: HEIGHT,
+            pitch: WIDTH,
+        };
+        let mut c = Console::new(Backend::Uefi(fb));
+        c.clear(0x000000);
+        c
+    }
+
+    #[test_case]
+    fn write_advances_cursor() {
+        let mut c = console();
+        c.write_str("abc");
+        assert_eq!(c.cursor_x, 20 + 3 * 9);
+        assert_eq!(c.cursor_y, 20);
+    }
+
+    #[test_case]
+    fn newline_moves_to_next_row() {
+        let mut c = console();
+        c.write_str("ab\ncd");
+        assert_eq!(c.cursor_x, 20 + 2 * 9);
+        assert_eq!(c.cursor_y, 40);
+    }
+
+    #[test_case]
+    fn scrolls_at_bottom() {
+        let mut c = console();
+        c.write_str("\n\n\n\n\n\n");
+        assert_eq!(c.cursor_y, HEIGHT - 20);
+    }
+
+    #[test_case]
+    fn backspace_stops_at_prompt() {
+        let mut c = console();
+        c.write_str("> ");
+        c.lock_prompt();
+        c.write_str("x");
+        c.backspace();
+        c.backspace();
+        assert_eq!(c.cursor_x, c.line_start_x);
+    }
+
+    #[test_case]
+    fn resize_resets_the_text_grid() {
+        let mut c = console();
+        c.resize(120, 60);
+        c.write_str("0123456789a");
+        assert_eq!(c.cursor_y, 40);
+        c.write_str("\n\n");
+        assert_eq!(c.cursor_y, 60 - 20);
+        c.resize(WIDTH, HEIGHT);
+        assert_eq!((c.cursor_x, c.cursor_y), (20, 20));
+    }
+
+    #[test_case]
+    fn glyphs_reach_the_framebuffer() {
+        let mut c = console();
+        c.write_str("#");
+        let pixels = unsafe { &*core::ptr::addr_of!(PIXELS) };
+        let lit = (20..36).any(|y| (20..28).any(|x| pixels[y * WIDTH + x] == 0xFFFFFF));
+        assert!(lit);
+    }
+}
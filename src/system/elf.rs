@@ -0,0 +1,401 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::Range;
+use core::ptr::read_unaligned;
+use crate::system::memory::FRAME_SIZE;
+use crate::system::paging::{AddressSpace, MapError, NO_EXECUTE, USER, USER_BASE, WRITABLE};
+use crate::system::process::{self, ProcessError, ProcessHandle, USER_STACK_BOTTOM};
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 0x3E;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_SYMTAB: i64 = 6;
+const DT_JMPREL: i64 = 23;
+const DT_PLTRELSZ: i64 = 2;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    UnsupportedClass,
+    UnsupportedMachine,
+    UnsupportedType,
+    BadSegment,
+    BadRelocation,
+    StackOverflow,
+    MapFailed,
+    SpawnFailed,
+}
+
+impl From<MapError> for ElfError {
+    fn from(_: MapError) -> Self {
+        ElfError::MapFailed
+    }
+}
+
+impl From<ProcessError> for ElfError {
+    fn from(e: ProcessError) -> Self {
+        match e {
+            ProcessError::MapFailed => ElfError::MapFailed,
+            _ => ElfError::SpawnFailed,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+struct LoadedImage {
+    entry: usize,
+    base: usize,
+    phdr: usize,
+    phnum: usize,
+}
+
+fn read<T: Copy>(data: &[u8], offset: usize) -> Result<T, ElfError> {
+    let end = offset.checked_add(size_of::<T>()).ok_or(ElfError::Truncated)?;
+    if end > data.len() {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { read_unaligned(data[offset..].as_ptr() as *const T) })
+}
+
+// Relocation data comes from the image, so it may only point into the
+// user pages the image itself was loaded to.
+fn check_image(space: &AddressSpace, image: &Range<usize>, addr: usize, len: usize) -> Result<(), ElfError> {
+    let end = addr.checked_add(len).ok_or(ElfError::BadRelocation)?;
+    if addr < image.start || end > image.end {
+        return Err(ElfError::BadRelocation);
+    }
+    let user = (addr & !(FRAME_SIZE - 1)..end)
+        .step_by(FRAME_SIZE)
+        .all(|page| space.flags(page).map_or(false, |f| f & USER != 0));
+    if !user {
+        return Err(ElfError::BadRelocation);
+    }
+    Ok(())
+}
+
+fn read_user<T: Copy + Default>(space: &AddressSpace, image: &Range<usize>, addr: usize) -> Result<T, ElfError> {
+    check_image(space, image, addr, size_of::<T>())?;
+    let mut value = T::default();
+    let bytes = unsafe { core::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, size_of::<T>()) };
+    process::copy_from_user(space, addr, bytes).map_err(|_| ElfError::BadRelocation)?;
+    Ok(value)
+}
+
+fn parse_header(image: &[u8]) -> Result<ElfHeader, ElfError> {
+    let header: ElfHeader = read(image, 0)?;
+    if &header.ident[..4] != b"\x7fELF" {
+        return Err(ElfError::BadMagic);
+    }
+    if header.ident[4] != 2 || header.ident[5] != 1 {
+        return Err(ElfError::UnsupportedClass);
+    }
+    if header.machine != EM_X86_64 {
+        return Err(ElfError::UnsupportedMachine);
+    }
+    if header.kind != ET_EXEC && header.kind != ET_DYN {
+        return Err(ElfError::UnsupportedType);
+    }
+    if header.phentsize as usize != size_of::<ProgramHeader>() {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(header)
+}
+
+fn load_segments(space: &mut AddressSpace, image: &[u8], header: &ElfHeader) -> Result<LoadedImage, ElfError> {
+    let base = if header.kind == ET_DYN { USER_BASE } else { 0 };
+    let mut phdr = 0;
+    let mut dynamic = None;
+    let mut loaded = usize::MAX..0;
+
+    for i in 0..header.phnum as usize {
+        let offset = i.checked_mul(size_of::<ProgramHeader>()).and_then(|off| off.checked_add(header.phoff as usize));
+        let ph: ProgramHeader = read(image, offset.ok_or(ElfError::Truncated)?)?;
+        match ph.kind {
+            PT_LOAD => {
+                let vaddr = base.checked_add(ph.vaddr as usize).ok_or(ElfError::BadSegment)?;
+                let end = vaddr.checked_add(ph.memsz as usize).ok_or(ElfError::BadSegment)?;
+                let file_end = (ph.offset as usize).checked_add(ph.filesz as usize).ok_or(ElfError::BadSegment)?;
+                if vaddr < USER_BASE || end > USER_STACK_BOTTOM - FRAME_SIZE || ph.filesz > ph.memsz || file_end > image.len() {
+                    return Err(ElfError::BadSegment);
+                }
+
+                let mut flags = 0;
+                if ph.flags & PF_W != 0 { flags |= WRITABLE; }
+                if ph.flags & PF_X == 0 { flags |= NO_EXECUTE; }
+                space.alloc_user(vaddr, ph.memsz as usize, flags)?;
+                loaded = loaded.start.min(vaddr)..loaded.end.max(end);
+                process::copy_to_user(space, vaddr, &image[ph.offset as usize..file_end])?;
+
+                if ph.offset == 0 && phdr == 0 {
+                    phdr = vaddr.checked_add(header.phoff as usize).ok_or(ElfError::BadSegment)?;
+                }
+            }
+            PT_DYNAMIC => dynamic = Some(base.checked_add(ph.vaddr as usize).ok_or(ElfError::BadSegment)?),
+            PT_PHDR => phdr = base.checked_add(ph.vaddr as usize).ok_or(ElfError::BadSegment)?,
+            _ => {}
+        }
+    }
+
+    if let Some(dynamic) = dynamic {
+        relocate(space, &loaded, base, dynamic)?;
+    }
+
+    Ok(LoadedImage {
+        entry: base.checked_add(header.entry as usize).ok_or(ElfError::BadSegment)?,
+        base,
+        phdr,
+        phnum: header.phnum as usize,
+    })
+}
+
+fn relocate(space: &mut AddressSpace, image: &Range<usize>, base: usize, dynamic: usize) -> Result<(), ElfError> {
+    let offset = |value: u64| base.checked_add(value as usize).ok_or(ElfError::BadRelocation);
+    let mut tables = [(0usize, 0usize); 2];
+    let mut rela_ent = size_of::<Rela>();
+    let mut symtab = 0;
+
+    let mut addr = dynamic;
+    loop {
+        let tag: i64 = read_user(space, image, addr)?;
+        let value: u64 = read_user(space, image, addr.checked_add(8).ok_or(ElfError::BadRelocation)?)?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => tables[0].0 = offset(value)?,
+            DT_RELASZ => tables[0].1 = value as usize,
+            DT_JMPREL => tables[1].0 = offset(value)?,
+            DT_PLTRELSZ => tables[1].1 = value as usize,
+            DT_RELAENT => rela_ent = value as usize,
+            DT_SYMTAB => symtab = offset(value)?,
+            _ => {}
+        }
+        addr = addr.checked_add(16).ok_or(ElfError::BadRelocation)?;
+    }
+    if rela_ent != size_of::<Rela>() {
+        return Err(ElfError::BadRelocation);
+    }
+
+    for &(table, size) in tables.iter().filter(|t| t.0 != 0) {
+        check_image(space, image, table, size)?;
+        for i in 0..size / rela_ent {
+            let rela: Rela = read_user(space, image, table + i * rela_ent)?;
+            let target = offset(rela.offset)?;
+            let value = match (rela.info & 0xFFFF_FFFF) as u32 {
+                R_X86_64_NONE => continue,
+                R_X86_64_RELATIVE => (base as u64).wrapping_add(rela.addend as u64),
+                kind @ (R_X86_64_64 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT) => {
+                    if symtab == 0 {
+                        return Err(ElfError::BadRelocation);
+                    }
+                    let index = (rela.info >> 32) as usize;
+                    let sym_addr = index
+                        .checked_mul(size_of::<Symbol>())
+                        .and_then(|off| symtab.checked_add(off))
+                        .ok_or(ElfError::BadRelocation)?;
+                    let sym: Symbol = read_user(space, image, sym_addr)?;
+                    if sym.shndx == 0 {
+                        return Err(ElfError::BadRelocation);
+                    }
+                    let addend = if kind == R_X86_64_64 { rela.addend } else { 0 };
+                    (base as u64).wrapping_add(sym.value).wrapping_add(addend as u64)
+                }
+                _ => return Err(ElfError::BadRelocation),
+            };
+            check_image(space, image, target, size_of::<u64>())?;
+            process::copy_to_user(space, target, &value.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn setup_stack(space: &mut AddressSpace, loaded: &LoadedImage, args: &[&str], env: &[&str]) -> Result<usize, ElfError> {
+    let top = process::alloc_stack(space)?;
+    let mut strings = top;
+    let mut push_strings = |list: &[&str]| -> Result<Vec<u64>, ElfError> {
+        let mut ptrs = Vec::new();
+        for s in list {
+            strings -= s.len() + 1;
+            if strings < USER_STACK_BOTTOM + FRAME_SIZE {
+                return Err(ElfError::StackOverflow);
+            }
+            process::copy_to_user(space, strings, s.as_bytes())?;
+            process::copy_to_user(space, strings + s.len(), &[0])?;
+            ptrs.push(strings as u64);
+        }
+        Ok(ptrs)
+    };
+    let argv = push_strings(args)?;
+    let envp = push_strings(env)?;
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv);
+    words.push(0);
+    words.extend_from_slice(&envp);
+    words.push(0);
+    for (key, value) in [
+        (AT_PHDR, loaded.phdr as u64),
+        (AT_PHENT, size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM, loaded.phnum as u64),
+        (AT_PAGESZ, FRAME_SIZE as u64),
+        (AT_BASE, loaded.base as u64),
+        (AT_ENTRY, loaded.entry as u64),
+        (AT_NULL, 0),
+    ] {
+        words.push(key);
+        words.push(value);
+    }
+
+    let rsp = (strings - words.len() * 8) & !0xF;
+    if rsp < USER_STACK_BOTTOM + FRAME_SIZE {
+        return Err(ElfError::StackOverflow);
+    }
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    process::copy_to_user(space, rsp, &bytes)?;
+    Ok(rsp)
+}
+
+pub fn exec(image: &[u8], args: &[&str], env: &[&str]) -> Result<ProcessHandle, ElfError> {
+    let header = parse_header(image)?;
+    let mut space = process::new_address_space()?;
+
+    let prepared = load_segments(&mut space, image, &header)
+        .and_then(|loaded| setup_stack(&mut space, &loaded, args, env).map(|rsp| (loaded.entry, rsp)));
+    let (entry, rsp) = match prepared {
+        Ok(start) => start,
+        Err(e) => {
+            unsafe { space.destroy_user(); }
+            return Err(e);
+        }
+    };
+
+    let name = args.first().copied().unwrap_or("user");
+    Ok(process::spawn(name, space, entry, rsp)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn rejects_bad_magic() {
+        assert!(matches!(parse_header(&[0u8; 64]), Err(ElfError::BadMagic)));
+    }
+
+    #[test_case]
+    fn rejects_truncated_header() {
+        assert!(matches!(parse_header(b"\x7fELF"), Err(ElfError::Truncated)));
+    }
+
+    #[test_case]
+    fn rejects_overflowing_phoff() {
+        let image = crate::assets::find_program("/bin/hello").unwrap();
+        let mut header = parse_header(image).unwrap();
+        header.phoff = u64::MAX - 8;
+        let mut space = process::new_address_space().unwrap();
+        assert!(matches!(load_segments(&mut space, image, &header), Err(ElfError::Truncated)));
+        unsafe { space.destroy_user(); }
+    }
+
+    #[test_case]
+    fn accepts_embedded_hello() {
+        let header = parse_header(crate::assets::find_program("/bin/hello").unwrap()).unwrap();
+        assert_eq!(header.kind, ET_DYN);
+        assert_eq!(header.phnum, 3);
+    }
+
+    #[test_case]
+    fn relocations_stay_inside_the_image() {
+        let mut space = process::new_address_space().unwrap();
+        space.alloc_user(USER_BASE, FRAME_SIZE, WRITABLE).unwrap();
+        let image = USER_BASE..USER_BASE + FRAME_SIZE;
+        assert!(check_image(&space, &image, USER_BASE, 8).is_ok());
+        assert!(check_image(&space, &image, USER_BASE + FRAME_SIZE - 4, 8).is_err());
+        assert!(check_image(&space, &image, usize::MAX - 4, 8).is_err());
+        assert!(check_image(&space, &(0..image.end), 0x10_0000, 8).is_err());
+        unsafe { space.destroy_user(); }
+    }
+
+    #[test_case]
+    fn hello_exits_with_argc() {
+        let image = crate::assets::find_program("/bin/hello").unwrap();
+        let handle = exec(image, &["/bin/hello", "a", "b"], &[]).unwrap();
+        assert_eq!(handle.wait(), 3);
+    }
+}
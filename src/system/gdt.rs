@@ -1,48 +1,110 @@
 use core::mem::size_of;
+use crate::system::paging::{AddressSpace, MapError};
 
-#[repr(C, packed(2))] 
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+pub const IST_STACK_FRAMES: usize = 8;
+
+const GDT_ENTRIES: usize = 7;
+
+#[repr(C, packed(2))]
 struct GdtDescriptor {
     size: u16,
     offset: u64,
 }
 
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    pub const fn new() -> Self {
+        Self {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+
+    pub fn alloc_ist_stacks(&mut self, space: &mut AddressSpace) -> Result<(), MapError> {
+        for index in [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST] {
+            let top = space.alloc_stack(IST_STACK_FRAMES)?;
+            self.ist[index as usize - 1] = top as u64;
+        }
+        Ok(())
+    }
+}
+
 #[repr(C, align(16))]
 pub struct Gdt {
-    entries: [u64; 3],
+    entries: [u64; GDT_ENTRIES],
 }
 
 impl Gdt {
-    pub fn new() -> Self {
-        let mut entries = [0; 3];
+    pub fn new(tss: &'static TaskStateSegment) -> Self {
+        let mut entries = [0; GDT_ENTRIES];
         entries[0] = 0;
         entries[1] = 0x00af9a000000ffff;
         entries[2] = 0x00cf92000000ffff;
+        entries[3] = 0x00cff2000000ffff;
+        entries[4] = 0x00affa000000ffff;
+
+        let base = tss as *const _ as u64;
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+        entries[5] = (limit & 0xFFFF)
+            | ((base & 0xFF_FFFF) << 16)
+            | (0x89 << 40)
+            | (((limit >> 16) & 0xF) << 48)
+            | (((base >> 24) & 0xFF) << 56);
+        entries[6] = base >> 32;
+
         Self { entries }
     }
 
     pub unsafe fn load(&'static self) {
-        static mut GDT_DESC: GdtDescriptor = GdtDescriptor { size: 0, offset: 0 };
-        
-        GDT_DESC = GdtDescriptor {
-            size: (size_of::<[u64; 3]>() - 1) as u16,
+        let desc = GdtDescriptor {
+            size: (size_of::<[u64; GDT_ENTRIES]>() - 1) as u16,
             offset: self.entries.as_ptr() as u64,
         };
 
         core::arch::asm!(
             "lgdt [{desc}]",
-            "push 0x08",
+            "push {code}",
             "lea {tmp}, [2f + rip]",
             "push {tmp}",
             "retfq",
             "2:",
-            "mov ax, 0x10",
+            "mov ax, {data}",
             "mov ds, ax",
             "mov es, ax",
             "mov ss, ax",
             "mov fs, ax",
             "mov gs, ax",
-            desc = in(reg) &GDT_DESC,
+            "mov ax, {tss}",
+            "ltr ax",
+            desc = in(reg) &desc,
             tmp = out(reg) _,
+            code = const KERNEL_CODE_SELECTOR,
+            data = const KERNEL_DATA_SELECTOR,
+            tss = const TSS_SELECTOR,
+            out("ax") _,
         );
     }
-}
\ No newline at end of file
+}
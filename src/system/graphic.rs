@@ -1,5 +1,5 @@
 use crate::drivers::uefi_fb::Framebuffer as UefiFb;
-use crate::drivers::gpu_fb::Framebuffer as GpuFb;
+use crate::drivers::gpu_fb::{DirtyRegion, Framebuffer as GpuFb};
 
 pub enum Backend {
     Uefi(UefiFb),
@@ -69,6 +69,8 @@ impl GraphicBackend for Backend {
                     width: fb.width,
                     height: fb.height,
                     pitch: fb.pitch,
+                    gpu: None,
+                    dirty: DirtyRegion::new(),
                 };
                 temp_fb.draw_char_ex(c, x, y, color, bg_color);
             }
@@ -85,6 +87,8 @@ impl GraphicBackend for Backend {
                     width: fb.width,
                     height: fb.height,
                     pitch: fb.pitch,
+                    gpu: None,
+                    dirty: DirtyRegion::new(),
                 };
                 temp_fb.scroll(lines, char_height, bg_color);
             }
@@ -118,7 +122,7 @@ impl GraphicBackend for Backend {
     fn set_virtual_res(&mut self, width: usize, height: usize) {
         match self {
             Backend::Uefi(fb) => { fb.width = width; fb.height = height; }
-            Backend::Gpu(fb) => { fb.width = width; fb.height = height; }
+            Backend::Gpu(fb) => { fb.width = width; fb.height = height; fb.dirty = DirtyRegion::new(); }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,201 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::system::memory::{with_mm, FRAME_SIZE};
+
+const HEAP_INITIAL_FRAMES: usize = 1024;
+const HEAP_GROW_FRAMES: usize = 256;
+const MAX_REGIONS: usize = 64;
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    head: *mut FreeBlock,
+    regions: [(usize, usize); MAX_REGIONS],
+    region_count: usize,
+    total: usize,
+    used: usize,
+}
+
+unsafe impl Send for Heap {}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    head: ptr::null_mut(),
+    regions: [(0, 0); MAX_REGIONS],
+    region_count: 0,
+    total: 0,
+    used: 0,
+});
+static HEAP_READY: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn block_layout(layout: &Layout) -> (usize, usize) {
+    let align = layout.align().max(MIN_BLOCK);
+    let size = align_up(layout.size().max(MIN_BLOCK), MIN_BLOCK);
+    (size, align)
+}
+
+impl Heap {
+    fn contains(&self, addr: usize) -> bool {
+        self.regions[..self.region_count].iter().any(|&(s, e)| addr >= s && addr < e)
+    }
+
+    unsafe fn add_region(&mut self, start: usize, size: usize) -> bool {
+        if self.region_count == MAX_REGIONS {
+            return false;
+        }
+        self.regions[self.region_count] = (start, start + size);
+        self.region_count += 1;
+        self.total += size;
+        self.insert_free(start, size);
+        true
+    }
+
+    unsafe fn insert_free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() && (cur as usize) < addr {
+            prev = cur;
+            cur = (*cur).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next: cur });
+
+        if !cur.is_null() && addr + size == cur as usize {
+            (*block).size += (*cur).size;
+            (*block).next = (*cur).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    unsafe fn alloc_first_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        while !cur.is_null() {
+            let start = cur as usize;
+            let end = start + (*cur).size;
+            let mut alloc_start = align_up(start, align);
+            if alloc_start != start && alloc_start - start < MIN_BLOCK {
+                alloc_start = align_up(start + MIN_BLOCK, align);
+            }
+            let alloc_end = alloc_start + size;
+
+            if alloc_end <= end {
+                let next = (*cur).next;
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if alloc_start > start {
+                    self.insert_free(start, alloc_start - start);
+                }
+                if end > alloc_end {
+                    self.insert_free(alloc_end, end - alloc_end);
+                }
+                self.used += size;
+                return alloc_start as *mut u8;
+            }
+
+            prev = cur;
+            cur = (*cur).next;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        let needed = (size + align + FRAME_SIZE - 1) / FRAME_SIZE;
+        let frames = needed.max(HEAP_GROW_FRAMES);
+        if self.region_count == MAX_REGIONS {
+            return false;
+        }
+        with_mm(|mm| {
+            let Some(base) = mm.alloc_frames(frames) else { return false };
+            if !self.add_region(base as usize, frames * FRAME_SIZE) {
+                mm.free_region(base as usize, frames * FRAME_SIZE);
+                return false;
+            }
+            true
+        })
+        .unwrap_or(false)
+    }
+}
+
+pub fn init() {
+    let base = with_mm(|mm| mm.alloc_frames(HEAP_INITIAL_FRAMES))
+        .flatten()
+        .expect("Kernel heap allocation failed");
+
+    interrupts::without_interrupts(|| unsafe {
+        HEAP.lock().add_region(base as usize, HEAP_INITIAL_FRAMES * FRAME_SIZE);
+    });
+    HEAP_READY.store(true, Ordering::SeqCst);
+}
+
+pub fn get_heap_usage_kb() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let heap = HEAP.lock();
+        (heap.used / 1024, heap.total / 1024)
+    })
+}
+
+pub struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !HEAP_READY.load(Ordering::Acquire) {
+            return uefi::allocator::Allocator.alloc(layout);
+        }
+
+        let (size, align) = block_layout(&layout);
+        interrupts::without_interrupts(|| {
+            let mut heap = HEAP.lock();
+            let mut ptr = heap.alloc_first_fit(size, align);
+            if ptr.is_null() && heap.grow(size, align) {
+                ptr = heap.alloc_first_fit(size, align);
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !HEAP_READY.load(Ordering::Acquire) {
+            return uefi::allocator::Allocator.dealloc(ptr, layout);
+        }
+
+        let (size, _) = block_layout(&layout);
+        interrupts::without_interrupts(|| {
+            let mut heap = HEAP.lock();
+            // Blocks handed out by the UEFI pool before the heap existed are
+            // simply leaked: boot services are gone and cannot take them back.
+            if heap.contains(ptr as usize) {
+                heap.insert_free(ptr as usize, size);
+                heap.used -= size;
+            }
+        });
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
@@ -1,15 +1,42 @@
 use core::mem::{size_of, MaybeUninit};
-use crate::drivers::keyboard::Keyboard;
-use crate::system::apic::lapic_eoi;
+use crate::system::apic::{self, lapic_eoi, IRQ_BASE_VECTOR, IRQ_COUNT, TIMER_VECTOR};
+use crate::system::gdt::{DOUBLE_FAULT_IST, MACHINE_CHECK_IST, NMI_IST};
+use crate::system::{process, sched, smp};
 use crate::system::time;
+use crate::system::panic;
 
 #[repr(C)]
-pub struct InterruptStackFrame {
-    pub instruction_pointer: u64,
-    pub code_segment: u64,
-    pub cpu_flags: u64,
-    pub stack_pointer: u64,
-    pub stack_segment: u64,
+#[derive(Clone, Copy)]
+pub struct TrapFrame {
+    pub rax: u64, pub rbx: u64, pub rcx: u64, pub rdx: u64,
+    pub rsi: u64, pub rdi: u64, pub rbp: u64,
+    pub r8: u64, pub r9: u64, pub r10: u64, pub r11: u64,
+    pub r12: u64, pub r13: u64, pub r14: u64, pub r15: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct PageFaultInfo {
+    pub address: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rsp: u64,
+}
+
+impl PageFaultInfo {
+    pub fn present(&self) -> bool { self.error_code & (1 << 0) != 0 }
+    pub fn write(&self) -> bool { self.error_code & (1 << 1) != 0 }
+    pub fn user(&self) -> bool { self.error_code & (1 << 2) != 0 }
+    pub fn reserved(&self) -> bool { self.error_code & (1 << 3) != 0 }
+    pub fn instruction_fetch(&self) -> bool { self.error_code & (1 << 4) != 0 }
+    pub fn protection_key(&self) -> bool { self.error_code & (1 << 5) != 0 }
 }
 
 #[repr(C, packed(2))]
@@ -50,17 +77,21 @@ pub struct Idt([IdtEntry; 256]);
 
 impl Idt {
     pub fn new() -> Self {
-        let mut idt = Self([IdtEntry::new(generic_handler as *const (), 0x08, 0); 256]);
+        let mut idt = Self([IdtEntry::new(generic_stub as *const (), 0x08, 0); 256]);
 
-        idt.set_handler(3, breakpoint_handler as *const (), 0);
-        idt.set_handler(8, double_fault_handler as *const (), 1);
-        idt.set_handler(13, general_protection_fault_handler as *const (), 0);
-        idt.set_handler(14, page_fault_handler as *const (), 0);
+        idt.set_handler(3, breakpoint_stub as *const (), 0);
+        idt.set_handler(2, nmi_stub as *const (), NMI_IST);
+        idt.set_handler(8, double_fault_stub as *const (), DOUBLE_FAULT_IST);
+        idt.set_handler(13, general_protection_fault_stub as *const (), 0);
+        idt.set_handler(14, page_fault_stub as *const (), 0);
+        idt.set_handler(18, machine_check_stub as *const (), MACHINE_CHECK_IST);
 
-        idt.set_handler(32, timer_handler as *const (), 0);
-        idt.set_handler(33, keyboard_handler as *const (), 0);
+        for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+            idt.set_handler(IRQ_BASE_VECTOR + irq as u8, *stub as *const (), 0);
+        }
 
-        idt.set_handler(255, spurious_handler as *const (), 0);
+        idt.set_handler(TIMER_VECTOR, timer_stub as *const (), 0);
+        idt.set_handler(SPURIOUS_VECTOR, spurious_stub as *const (), 0);
 
         idt
     }
@@ -79,37 +110,171 @@ impl Idt {
     }
 }
 
-pub extern "x86-interrupt" fn generic_handler(_frame: InterruptStackFrame) {
-    unsafe { lapic_eoi(); }
+macro_rules! interrupt_stub {
+    ($name:ident, $vector:expr) => {
+        interrupt_stub!(@asm $name, $vector, "    push 0");
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        interrupt_stub!(@asm $name, $vector, "");
+    };
+    (@asm $name:ident, $vector:expr, $error_code:literal) => {
+        extern "sysv64" {
+            fn $name();
+        }
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            $error_code,
+            "    push {vector}",
+            "    jmp interrupt_common",
+            vector = const $vector,
+        );
+    };
 }
 
-pub extern "x86-interrupt" fn timer_handler(_frame: InterruptStackFrame) {
-    time::tick();
-    unsafe { lapic_eoi(); }
+macro_rules! irq_stubs {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(interrupt_stub!($name, IRQ_BASE_VECTOR as usize + $irq);)*
+        static IRQ_STUBS: [unsafe extern "sysv64" fn(); IRQ_COUNT] = [$($name),*];
+    };
 }
 
-pub extern "x86-interrupt" fn keyboard_handler(_frame: InterruptStackFrame) {
-    let scancode = unsafe { Keyboard::read_scancode() };
-    Keyboard::handle_scancode(scancode);
-    unsafe { lapic_eoi(); }
-}
+irq_stubs!(
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3, irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11, irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+    irq16 => 16, irq17 => 17, irq18 => 18, irq19 => 19, irq20 => 20, irq21 => 21, irq22 => 22, irq23 => 23,
+    irq24 => 24, irq25 => 25, irq26 => 26, irq27 => 27, irq28 => 28, irq29 => 29, irq30 => 30, irq31 => 31,
+);
+
+interrupt_stub!(nmi_stub, 2);
+interrupt_stub!(breakpoint_stub, 3);
+interrupt_stub!(double_fault_stub, 8, error_code);
+interrupt_stub!(general_protection_fault_stub, 13, error_code);
+interrupt_stub!(page_fault_stub, 14, error_code);
+interrupt_stub!(machine_check_stub, 18);
+interrupt_stub!(timer_stub, TIMER_VECTOR);
+interrupt_stub!(spurious_stub, SPURIOUS_VECTOR);
+interrupt_stub!(generic_stub, GENERIC_VECTOR);
 
-pub extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame) {}
+// Entered from ring 3, GS still holds the user base: swap in the per-CPU
+// pointer for the kernel and swap it back out on the way back.
+core::arch::global_asm!(
+    ".global interrupt_common",
+    "interrupt_common:",
+    "    test byte ptr [rsp + 24], 3",
+    "    jz 1f",
+    "    swapgs",
+    "1:",
+    "    push r15",
+    "    push r14",
+    "    push r13",
+    "    push r12",
+    "    push r11",
+    "    push r10",
+    "    push r9",
+    "    push r8",
+    "    push rbp",
+    "    push rdi",
+    "    push rsi",
+    "    push rdx",
+    "    push rcx",
+    "    push rbx",
+    "    push rax",
+    "    cld",
+    "    mov rdi, rsp",
+    "    call {dispatch}",
+    "    pop rax",
+    "    pop rbx",
+    "    pop rcx",
+    "    pop rdx",
+    "    pop rsi",
+    "    pop rdi",
+    "    pop rbp",
+    "    pop r8",
+    "    pop r9",
+    "    pop r10",
+    "    pop r11",
+    "    pop r12",
+    "    pop r13",
+    "    pop r14",
+    "    pop r15",
+    "    add rsp, 16",
+    "    test byte ptr [rsp + 8], 3",
+    "    jz 2f",
+    "    swapgs",
+    "2:",
+    "    iretq",
+    dispatch = sym interrupt_dispatch,
+);
 
-pub extern "x86-interrupt" fn breakpoint_handler(_frame: InterruptStackFrame) {
-    panic!("BREAKPOINT Exception");
+const SPURIOUS_VECTOR: u8 = 255;
+const GENERIC_VECTOR: usize = 256;
+
+extern "sysv64" fn interrupt_dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as usize;
+    let irqs = IRQ_BASE_VECTOR as usize..IRQ_BASE_VECTOR as usize + IRQ_COUNT;
+    if vector == TIMER_VECTOR as usize {
+        time::tick();
+        unsafe { lapic_eoi(); }
+        sched::preempt();
+    } else if irqs.contains(&vector) {
+        apic::dispatch_irq(vector - IRQ_BASE_VECTOR as usize);
+    } else if vector == GENERIC_VECTOR {
+        unsafe { lapic_eoi(); }
+    } else if vector != SPURIOUS_VECTOR as usize {
+        exception_dispatch(frame);
+    }
 }
 
-pub extern "x86-interrupt" fn double_fault_handler(_frame: InterruptStackFrame, _err: u64) -> ! {
-    panic!("DOUBLE FAULT Exception");
+fn exception_dispatch(frame: &mut TrapFrame) {
+    // NMI, #DF and #MC can hit the syscall path between its swapgs and the
+    // stack switch, so the saved CS says nothing about GS there.
+    if matches!(frame.vector, 2 | 8 | 18) && unsafe { smp::read_msr(smp::IA32_GS_BASE) } == 0 {
+        unsafe { core::arch::asm!("swapgs", options(nomem, nostack, preserves_flags)); }
+    }
+    match frame.vector {
+        14 => page_fault_handler(frame),
+        13 => general_protection_fault_handler(frame),
+        _ => {}
+    }
+    panic::record_trap(frame);
+    match frame.vector {
+        2 => panic!("NON-MASKABLE INTERRUPT"),
+        3 => panic!("BREAKPOINT Exception"),
+        8 => panic!("DOUBLE FAULT Exception"),
+        13 => panic!("GENERAL PROTECTION FAULT"),
+        14 => panic!("PAGE FAULT at {:#018x}", read_cr2()),
+        18 => panic!("MACHINE CHECK Exception"),
+        v => panic!("EXCEPTION {}", v),
+    }
 }
 
-pub extern "x86-interrupt" fn general_protection_fault_handler(_frame: InterruptStackFrame, _err: u64) {
-    panic!("GENERAL PROTECTION FAULT");
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags)); }
+    cr2
 }
 
-pub extern "x86-interrupt" fn page_fault_handler(_frame: InterruptStackFrame, _err: u64) {
-    panic!("PAGE FAULT");
+fn general_protection_fault_handler(frame: &TrapFrame) {
+    if frame.cs & 3 == 3 {
+        process::kill_current("general protection fault", frame.error_code);
+    }
 }
+
+fn page_fault_handler(frame: &TrapFrame) {
+    let cr2 = read_cr2();
+    if frame.cs & 3 == 3 {
+        process::kill_current("page fault", cr2);
+    }
+
+    panic::record_page_fault(PageFaultInfo {
+        address: cr2,
+        error_code: frame.error_code,
+        rip: frame.rip,
+        cs: frame.cs,
+        rsp: frame.rsp,
+    });
+}
+
 #[allow(dead_code)]
 pub static mut IDT: MaybeUninit<Idt> = MaybeUninit::uninit();
\ No newline at end of file
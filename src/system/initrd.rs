@@ -0,0 +1,215 @@
+use alloc::format;
+use alloc::string::String;
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::{cstr16, CStr16};
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+use crate::system::memory::FRAME_SIZE;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug)]
+pub enum InitrdError {
+    NoFileSystem,
+    NotFound,
+    ReadFailed,
+    OutOfMemory,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TarEntry {
+    prefix: &'static str,
+    name: &'static str,
+    pub kind: EntryKind,
+    pub data: &'static [u8],
+}
+
+impl TarEntry {
+    pub fn path(&self) -> String {
+        if self.prefix.is_empty() {
+            String::from(self.name)
+        } else {
+            format!("{}/{}", self.prefix, self.name)
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = normalize(path);
+        if self.prefix.is_empty() {
+            return self.name == path;
+        }
+        path.strip_prefix(self.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .map_or(false, |rest| rest == self.name)
+    }
+}
+
+pub struct Initrd {
+    data: &'static [u8],
+}
+
+pub struct Entries {
+    data: &'static [u8],
+    offset: usize,
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for &b in field.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => value = value.checked_mul(8)?.checked_add((b - b'0') as usize)?,
+            0 | b' ' => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+fn field_str(field: &'static [u8]) -> Option<&'static str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+fn checksum_ok(header: &[u8]) -> bool {
+    let Some(expected) = parse_octal(&header[148..156]) else { return false };
+    let sum: usize = header.iter().enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as usize } else { b as usize })
+        .sum();
+    sum == expected
+}
+
+fn normalize(path: &str) -> &str {
+    path.trim_start_matches("./").trim_start_matches('/').trim_end_matches('/')
+}
+
+impl Iterator for Entries {
+    type Item = TarEntry;
+
+    fn next(&mut self) -> Option<TarEntry> {
+        loop {
+            let header: &'static [u8] = self.data.get(self.offset..self.offset + BLOCK_SIZE)?;
+            if header.iter().all(|&b| b == 0) || &header[257..262] != b"ustar" || !checksum_ok(header) {
+                return None;
+            }
+
+            let size = parse_octal(&header[124..136])?;
+            let start = self.offset + BLOCK_SIZE;
+            let data = self.data.get(start..start.checked_add(size)?)?;
+            self.offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+            let kind = match header[156] {
+                b'0' | 0 => EntryKind::File,
+                b'5' => EntryKind::Directory,
+                _ => EntryKind::Other,
+            };
+            let name = normalize(field_str(&header[0..100])?);
+            let prefix = normalize(field_str(&header[345..500])?);
+            if name.is_empty() && prefix.is_empty() {
+                continue;
+            }
+            return Some(TarEntry { prefix, name, kind, data });
+        }
+    }
+}
+
+impl Initrd {
+    pub fn new(data: &'static [u8]) -> Self {
+        Initrd { data }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries { data: self.data, offset: 0 }
+    }
+
+    pub fn find(&self, path: &str) -> Option<TarEntry> {
+        self.entries().find(|e| e.matches(path))
+    }
+
+    pub fn read(&self, path: &str) -> Option<&'static [u8]> {
+        self.find(path).filter(|e| e.kind == EntryKind::File).map(|e| e.data)
+    }
+}
+
+pub fn load_from_esp() -> Result<&'static [u8], InitrdError> {
+    load_esp_file(cstr16!("initrd.tar")).map(|data| &*data)
+}
+
+pub fn load_esp_file(name: &CStr16) -> Result<&'static mut [u8], InitrdError> {
+    let mut fs = boot::get_image_file_system(boot::image_handle()).map_err(|_| InitrdError::NoFileSystem)?;
+    let mut root = fs.open_volume().map_err(|_| InitrdError::NoFileSystem)?;
+    let mut file: RegularFile = root
+        .open(name, FileMode::Read, FileAttribute::empty())
+        .ok()
+        .and_then(|handle| handle.into_regular_file())
+        .ok_or(InitrdError::NotFound)?;
+
+    file.set_position(RegularFile::END_OF_FILE).map_err(|_| InitrdError::ReadFailed)?;
+    let size = file.get_position().map_err(|_| InitrdError::ReadFailed)? as usize;
+    file.set_position(0).map_err(|_| InitrdError::ReadFailed)?;
+    if size == 0 {
+        return Err(InitrdError::NotFound);
+    }
+
+    let pages = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+    let buffer = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .map_err(|_| InitrdError::OutOfMemory)?;
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer.as_ptr(), size) };
+
+    let mut read = 0;
+    while read < size {
+        match file.read(&mut data[read..]) {
+            Ok(0) | Err(_) => {
+                let _ = unsafe { boot::free_pages(buffer, pages) };
+                return Err(InitrdError::ReadFailed);
+            }
+            Ok(n) => read += n,
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::testing::ustar_archive;
+
+    fn archive() -> &'static [u8] {
+        ustar_archive(&[("etc/", b""), ("etc/motd", b"hello")])
+    }
+
+    #[test_case]
+    fn lists_entries() {
+        let initrd = Initrd::new(archive());
+        let kinds: Vec<(String, EntryKind)> = initrd.entries().map(|e| (e.path(), e.kind)).collect();
+        assert_eq!(kinds.len(), 2);
+        assert_eq!(kinds[0], (String::from("etc"), EntryKind::Directory));
+        assert_eq!(kinds[1], (String::from("etc/motd"), EntryKind::File));
+    }
+
+    #[test_case]
+    fn reads_file_contents() {
+        let initrd = Initrd::new(archive());
+        assert_eq!(initrd.read("/etc/motd"), Some(&b"hello"[..]));
+        assert_eq!(initrd.read("/etc"), None);
+        assert_eq!(initrd.read("/missing"), None);
+    }
+
+    #[test_case]
+    fn stops_at_bad_checksum() {
+        let mut copy = archive().to_vec();
+        copy[BLOCK_SIZE] ^= 1;
+        let initrd = Initrd::new(alloc::boxed::Box::leak(copy.into_boxed_slice()));
+        assert_eq!(initrd.entries().count(), 1);
+    }
+}
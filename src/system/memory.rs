@@ -1,5 +1,13 @@
+use x86_64::instructions::interrupts;
+
 pub const FRAME_SIZE: usize = 4096;
 
+// The heap grows with interrupts off, so the MM lock must never be held
+// by a thread that can be preempted.
+pub fn with_mm<R>(f: impl FnOnce(&mut MemoryManager) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| crate::MM_INSTANCE.get().map(|mm| f(&mut mm.lock())))
+}
+
 pub struct MemoryManager {
     bitmap: *mut u8,
     total_frames: usize,
@@ -41,10 +49,14 @@ impl MemoryManager {
     }
 
     pub fn alloc_frames(&mut self, count: usize) -> Option<*mut u8> {
+        self.alloc_frames_below(count, self.total_frames * FRAME_SIZE)
+    }
+
+    pub fn alloc_frames_below(&mut self, count: usize, limit: usize) -> Option<*mut u8> {
         let mut run = 0;
         let mut start = 0;
 
-        for i in 0..self.total_frames {
+        for i in 0..self.total_frames.min(limit / FRAME_SIZE) {
             if !self.is_used(i) {
                 if run == 0 { start = i; }
                 run += 1;
@@ -99,4 +111,63 @@ impl MemoryManager {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryManager, FRAME_SIZE};
+
+    const FRAMES: usize = 256;
+    static mut BITMAP: [u8; FRAMES / 8] = [0; FRAMES / 8];
+
+    fn manager() -> MemoryManager {
+        unsafe { MemoryManager::new(core::ptr::addr_of_mut!(BITMAP) as *mut u8, FRAMES * FRAME_SIZE) }
+    }
+
+    #[test_case]
+    fn starts_fully_used() {
+        let mut mm = manager();
+        assert_eq!(mm.get_total_memory_kb(), 0);
+        assert!(mm.alloc_frames(1).is_none());
+    }
+
+    #[test_case]
+    fn alloc_from_freed_region() {
+        let mut mm = manager();
+        mm.free_region(16 * FRAME_SIZE, 8 * FRAME_SIZE);
+        assert_eq!(mm.get_total_memory_kb(), 32);
+
+        assert_eq!(mm.alloc_frames(4), Some((16 * FRAME_SIZE) as *mut u8));
+        assert_eq!(mm.alloc_frames(4), Some((20 * FRAME_SIZE) as *mut u8));
+        assert!(mm.alloc_frames(1).is_none());
+        assert_eq!(mm.get_used_memory_kb(), 32);
+    }
+
+    #[test_case]
+    fn alloc_needs_contiguous_run() {
+        let mut mm = manager();
+        mm.free_region(0, 2 * FRAME_SIZE);
+        mm.free_region(4 * FRAME_SIZE, 3 * FRAME_SIZE);
+        assert_eq!(mm.alloc_frames(3), Some((4 * FRAME_SIZE) as *mut u8));
+    }
+
+    #[test_case]
+    fn alloc_below_respects_limit() {
+        let mut mm = manager();
+        mm.free_region(8 * FRAME_SIZE, 8 * FRAME_SIZE);
+        assert!(mm.alloc_frames_below(1, 8 * FRAME_SIZE).is_none());
+        assert_eq!(mm.alloc_frames_below(1, 9 * FRAME_SIZE), Some((8 * FRAME_SIZE) as *mut u8));
+        assert!(mm.alloc_frames_below(1, 9 * FRAME_SIZE).is_none());
+    }
+
+    #[test_case]
+    fn free_frame_makes_it_reusable() {
+        let mut mm = manager();
+        mm.free_region(0, FRAME_SIZE);
+        let frame = mm.alloc_frames(1).unwrap();
+        assert!(mm.alloc_frames(1).is_none());
+        mm.free_frame(frame);
+        assert_eq!(mm.get_used_memory_kb(), 0);
+        assert_eq!(mm.alloc_frames(1), Some(frame));
+    }
+}
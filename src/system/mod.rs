@@ -3,15 +3,28 @@ pub mod console;
 pub mod gdt;
 pub mod idt;
 pub mod memory;
+pub mod heap;
+pub mod paging;
 pub mod apic;
+pub mod acpi;
+pub mod smp;
+pub mod sched;
+pub mod syscall;
+pub mod process;
+pub mod elf;
+pub mod initrd;
 pub mod panic;
+pub mod symbols;
 pub mod graphic;
 
+use alloc::string::String;
 use console::Console;
 use core::fmt;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 
 pub static mut GLOBAL_CONSOLE: Option<Console> = None;
+static CAPTURE: Mutex<Option<String>> = Mutex::new(None);
 
 struct KeyQueue {
     buffer: [char; 128],
@@ -42,13 +55,29 @@ pub fn pop_key() -> Option<char> {
     Some(c)
 }
 
+pub fn start_capture() {
+    interrupts::without_interrupts(|| *CAPTURE.lock() = Some(String::new()));
+}
+
+pub fn end_capture() -> Option<String> {
+    interrupts::without_interrupts(|| CAPTURE.lock().take())
+}
+
+pub fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| unsafe { (*core::ptr::addr_of_mut!(GLOBAL_CONSOLE)).as_mut().map(f) })
+}
+
 pub fn print_fmt(args: fmt::Arguments) {
     use core::fmt::Write;
-    unsafe {
-        if let Some(ref mut c) = GLOBAL_CONSOLE {
-            let _ = c.write_fmt(args);
-        }
+    let captured = interrupts::without_interrupts(|| match CAPTURE.lock().as_mut() {
+        Some(out) => out.write_fmt(args).is_ok(),
+        None => false,
+    });
+    if captured {
+        return;
     }
+    crate::drivers::serial::write_fmt(args);
+    with_console(|c| c.write_fmt(args));
 }
 
 pub fn get_status_color(status: &str) -> u32 {
@@ -74,37 +103,60 @@ macro_rules! print {
 
 #[macro_export]
 macro_rules! clear_screen {
-    ($color:expr) => {
-        if let Some(ref mut c) = unsafe { $crate::system::GLOBAL_CONSOLE.as_mut() } {
-            c.clear($color);
-        }
-    };
+    ($color:expr) => {{
+        $crate::system::with_console(|c| c.clear($color));
+    }};
 }
 
 #[macro_export]
 macro_rules! log {
-    ($status:expr, $($arg:tt)*) => {
-        unsafe {
-            if let Some(ref mut c) = $crate::system::GLOBAL_CONSOLE {
-                let s: &str = $status;
-                let color = $crate::system::get_status_color(s);
-                c.set_color(color);
-                $crate::print!("[ {} ] ", s);
-                c.set_color(0xFFFFFF);
-                $crate::print!($($arg)*);
-                $crate::print!("\n");
-            }
-        }
-    };
+    ($status:expr, $($arg:tt)*) => {{
+        $crate::system::with_console(|c| {
+            let s: &str = $status;
+            let color = $crate::system::get_status_color(s);
+            c.set_color(color);
+            $crate::print!("[ {} ] ", s);
+            c.set_color(0xFFFFFF);
+            $crate::print!($($arg)*);
+            $crate::print!("\n");
+        });
+    }};
 }
 
 #[macro_export]
 macro_rules! set_color_text {
-    ($fg:expr, $bg:expr) => {
-        unsafe {
-            if let Some(ref mut c) = $crate::system::GLOBAL_CONSOLE {
-                c.set_colors($fg, $bg);
+    ($fg:expr, $bg:expr) => {{
+        $crate::system::with_console(|c| c.set_colors($fg, $bg));
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pop_key, push_key};
+
+    #[test_case]
+    fn key_queue_is_fifo() {
+        while pop_key().is_some() {}
+        push_key('a');
+        push_key('b');
+        push_key('\n');
+        assert_eq!(pop_key(), Some('a'));
+        assert_eq!(pop_key(), Some('b'));
+        assert_eq!(pop_key(), Some('\n'));
+        assert_eq!(pop_key(), None);
+    }
+
+    #[test_case]
+    fn key_queue_wraps_around() {
+        while pop_key().is_some() {}
+        for round in 0..3 {
+            for i in 0..100u8 {
+                push_key((b'0' + (i + round) % 10) as char);
+            }
+            for i in 0..100u8 {
+                assert_eq!(pop_key(), Some((b'0' + (i + round) % 10) as char));
             }
         }
-    };
-}
\ No newline at end of file
+        assert_eq!(pop_key(), None);
+    }
+}
@@ -0,0 +1,415 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::boot::MemoryType;
+use uefi::mem::memory_map::MemoryMap;
+use crate::system::memory::{with_mm, MemoryManager, FRAME_SIZE};
+
+pub const PAGE_SIZE_2M: usize = 0x20_0000;
+pub const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+pub const USER_BASE: usize = 0x0000_4000_0000_0000;
+pub const USER_STACK_TOP: usize = 0x0000_7FFF_FFFF_0000;
+pub const USER_TOP: usize = 0x0000_8000_0000_0000;
+
+pub const PRESENT: u64 = 1 << 0;
+pub const WRITABLE: u64 = 1 << 1;
+pub const USER: u64 = 1 << 2;
+pub const WRITE_THROUGH: u64 = 1 << 3;
+pub const NO_CACHE: u64 = 1 << 4;
+pub const HUGE: u64 = 1 << 7;
+pub const GLOBAL: u64 = 1 << 8;
+pub const NO_EXECUTE: u64 = 1 << 63;
+
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const FLAGS_MASK: u64 = !ADDR_MASK;
+
+static NX_MASK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            PageSize::Size4K => FRAME_SIZE,
+            PageSize::Size2M => PAGE_SIZE_2M,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapError {
+    OutOfFrames,
+    AlreadyMapped,
+    NotMapped,
+    Misaligned,
+}
+
+pub struct KernelLayout {
+    pub image_base: usize,
+    pub image_size: usize,
+    pub framebuffer: (usize, usize),
+    pub bitmap: (usize, usize),
+    pub mmio: Vec<(usize, usize)>,
+}
+
+pub struct AddressSpace {
+    pml4: usize,
+}
+
+unsafe impl Send for AddressSpace {}
+
+#[inline]
+fn table(phys: usize) -> *mut u64 {
+    phys as *mut u64
+}
+
+#[inline]
+fn index(virt: usize, level: usize) -> usize {
+    (virt >> (12 + 9 * level)) & 0x1FF
+}
+
+#[inline]
+pub unsafe fn flush_page(virt: usize) {
+    core::arch::asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+}
+
+pub fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe { core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)); }
+    cr3
+}
+
+pub unsafe fn write_cr3(pml4: usize) {
+    core::arch::asm!("mov cr3, {}", in(reg) pml4, options(nostack, preserves_flags));
+}
+
+pub const fn phys_to_virt(phys: usize) -> usize {
+    phys + PHYS_OFFSET
+}
+
+fn alloc_table() -> Result<usize, MapError> {
+    let frame = with_mm(|mm| mm.alloc_frames(1)).flatten().ok_or(MapError::OutOfFrames)?;
+    unsafe { core::ptr::write_bytes(frame, 0, FRAME_SIZE); }
+    Ok(frame as usize)
+}
+
+unsafe fn free_user_table(mm: &mut MemoryManager, tbl: usize, level: usize) {
+    for i in 0..512 {
+        let val = *table(tbl).add(i);
+        if val & PRESENT == 0 || val & USER == 0 || val & HUGE != 0 {
+            continue;
+        }
+        let next = (val & ADDR_MASK) as usize;
+        if level > 0 {
+            free_user_table(mm, next, level - 1);
+        }
+        mm.free_frame(next as *mut u8);
+    }
+}
+
+fn leaf_flags(flags: u64) -> u64 {
+    (flags | PRESENT) & !(NO_EXECUTE & !NX_MASK.load(Ordering::Relaxed))
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapError> {
+        Ok(Self { pml4: alloc_table()? })
+    }
+
+    pub fn pml4_addr(&self) -> usize {
+        self.pml4
+    }
+
+    pub fn is_active(&self) -> bool {
+        read_cr3() & ADDR_MASK as usize == self.pml4
+    }
+
+    pub fn new_user(kernel: &AddressSpace) -> Result<Self, MapError> {
+        let space = Self::new()?;
+        unsafe { core::ptr::copy_nonoverlapping(table(kernel.pml4), table(space.pml4), 512); }
+        Ok(space)
+    }
+
+    pub unsafe fn activate(&self) {
+        write_cr3(self.pml4);
+    }
+
+    unsafe fn next_table(&mut self, entry: *mut u64, flags: u64) -> Result<usize, MapError> {
+        let val = *entry;
+        if val & PRESENT == 0 {
+            let new = alloc_table()?;
+            *entry = new as u64 | PRESENT | WRITABLE | (flags & USER);
+            return Ok(new);
+        }
+        if val & HUGE != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+        if flags & USER != 0 {
+            *entry = val | USER;
+        }
+        Ok((val & ADDR_MASK) as usize)
+    }
+
+    unsafe fn walk(&mut self, virt: usize, size: PageSize, flags: u64) -> Result<*mut u64, MapError> {
+        let pml4e = table(self.pml4).add(index(virt, 3));
+        let pdpt = self.next_table(pml4e, flags)?;
+        let pdpte = table(pdpt).add(index(virt, 2));
+        let pd = self.next_table(pdpte, flags)?;
+        let pde = table(pd).add(index(virt, 1));
+        if size == PageSize::Size2M {
+            return Ok(pde);
+        }
+        let pt = self.next_table(pde, flags)?;
+        Ok(table(pt).add(index(virt, 0)))
+    }
+
+    unsafe fn find(&self, virt: usize) -> Option<(*mut u64, PageSize)> {
+        let mut tbl = self.pml4;
+        for level in (1..4).rev() {
+            let entry = table(tbl).add(index(virt, level));
+            let val = *entry;
+            if val & PRESENT == 0 {
+                return None;
+            }
+            if level == 1 && val & HUGE != 0 {
+                return Some((entry, PageSize::Size2M));
+            }
+            tbl = (val & ADDR_MASK) as usize;
+        }
+        let entry = table(tbl).add(index(virt, 0));
+        if *entry & PRESENT == 0 { None } else { Some((entry, PageSize::Size4K)) }
+    }
+
+    unsafe fn split_huge(&mut self, pde: *mut u64, virt: usize) -> Result<(), MapError> {
+        let val = *pde;
+        let base = val & ADDR_MASK;
+        let flags = val & FLAGS_MASK & !HUGE;
+        let pt = alloc_table()?;
+        for i in 0..512u64 {
+            *table(pt).add(i as usize) = (base + i * FRAME_SIZE as u64) | flags;
+        }
+        *pde = pt as u64 | PRESENT | WRITABLE | (flags & USER);
+        if self.is_active() {
+            flush_page(virt & !(PAGE_SIZE_2M - 1));
+        }
+        Ok(())
+    }
+
+    pub fn map(&mut self, virt: usize, phys: usize, size: PageSize, flags: u64) -> Result<(), MapError> {
+        if virt % size.bytes() != 0 || phys % size.bytes() != 0 {
+            return Err(MapError::Misaligned);
+        }
+        unsafe {
+            let entry = self.walk(virt, size, flags)?;
+            if *entry & PRESENT != 0 {
+                return Err(MapError::AlreadyMapped);
+            }
+            let huge = if size == PageSize::Size2M { HUGE } else { 0 };
+            *entry = phys as u64 | leaf_flags(flags) | huge;
+            if self.is_active() {
+                flush_page(virt);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn map_range(&mut self, virt: usize, phys: usize, len: usize, flags: u64) -> Result<(), MapError> {
+        let mut offset = 0;
+        while offset < len {
+            let v = virt + offset;
+            let p = phys + offset;
+            let size = if v % PAGE_SIZE_2M == 0 && p % PAGE_SIZE_2M == 0 && len - offset >= PAGE_SIZE_2M {
+                PageSize::Size2M
+            } else {
+                PageSize::Size4K
+            };
+            self.map(v, p, size, flags)?;
+            offset += size.bytes();
+        }
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, virt: usize) -> Result<(usize, PageSize), MapError> {
+        unsafe {
+            let (entry, size) = self.find(virt).ok_or(MapError::NotMapped)?;
+            if virt % size.bytes() != 0 {
+                return Err(MapError::Misaligned);
+            }
+            let phys = (*entry & ADDR_MASK) as usize;
+            *entry = 0;
+            if self.is_active() {
+                flush_page(virt);
+            }
+            Ok((phys, size))
+        }
+    }
+
+    pub fn protect(&mut self, virt: usize, size: PageSize, flags: u64) -> Result<(), MapError> {
+        unsafe {
+            let (mut entry, mut current) = self.find(virt).ok_or(MapError::NotMapped)?;
+            if current == PageSize::Size2M && size == PageSize::Size4K {
+                self.split_huge(entry, virt)?;
+                (entry, current) = self.find(virt).ok_or(MapError::NotMapped)?;
+            }
+            if current != size || virt % size.bytes() != 0 {
+                return Err(MapError::Misaligned);
+            }
+            let huge = *entry & HUGE;
+            *entry = (*entry & ADDR_MASK) | leaf_flags(flags) | huge;
+            if self.is_active() {
+                flush_page(virt);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn translate(&self, virt: usize) -> Option<usize> {
+        unsafe {
+            let (entry, size) = self.find(virt)?;
+            let offset = virt & (size.bytes() - 1);
+            Some((*entry & ADDR_MASK) as usize + offset)
+        }
+    }
+
+    pub fn alloc_stack(&mut self, frames: usize) -> Result<usize, MapError> {
+        let base = with_mm(|mm| mm.alloc_frames(frames + 1)).flatten().ok_or(MapError::OutOfFrames)? as usize;
+        self.identity_map(base, (frames + 1) * FRAME_SIZE, WRITABLE | NO_EXECUTE)?;
+        self.unmap(base)?;
+        Ok(base + (frames + 1) * FRAME_SIZE)
+    }
+
+    pub fn alloc_user(&mut self, virt: usize, len: usize, flags: u64) -> Result<(), MapError> {
+        let first = virt & !(FRAME_SIZE - 1);
+        let end = (virt + len + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        for page in (first..end).step_by(FRAME_SIZE) {
+            if let Some(old) = self.flags(page) {
+                let no_exec = old & flags & NO_EXECUTE;
+                self.protect(page, PageSize::Size4K, ((old | flags) & !NO_EXECUTE) | no_exec | USER)?;
+                continue;
+            }
+            let frame = alloc_table()?;
+            self.map(page, frame, PageSize::Size4K, flags | USER)?;
+        }
+        Ok(())
+    }
+
+    pub unsafe fn destroy_user(self) {
+        with_mm(|mm| {
+            free_user_table(mm, self.pml4, 3);
+            mm.free_frame(self.pml4 as *mut u8);
+        });
+    }
+
+    pub fn free_stack(&mut self, top: usize, frames: usize) -> Result<(), MapError> {
+        let base = top - (frames + 1) * FRAME_SIZE;
+        self.identity_map(base, FRAME_SIZE, WRITABLE | NO_EXECUTE)?;
+        with_mm(|mm| {
+            for i in 0..=frames {
+                mm.free_frame((base + i * FRAME_SIZE) as *mut u8);
+            }
+        })
+        .ok_or(MapError::OutOfFrames)
+    }
+
+    pub fn flags(&self, virt: usize) -> Option<u64> {
+        unsafe { self.find(virt).map(|(entry, _)| *entry & FLAGS_MASK) }
+    }
+
+    pub fn identity_map(&mut self, start: usize, len: usize, flags: u64) -> Result<(), MapError> {
+        let first = start & !(FRAME_SIZE - 1);
+        let end = (start + len + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let mut addr = first;
+        while addr < end {
+            match self.map(addr, addr, PageSize::Size4K, flags) {
+                Ok(()) => {}
+                Err(MapError::AlreadyMapped) => self.protect(addr, PageSize::Size4K, flags)?,
+                Err(e) => return Err(e),
+            }
+            addr += FRAME_SIZE;
+        }
+        Ok(())
+    }
+}
+
+fn enable_nx() {
+    use core::arch::x86_64::__cpuid;
+    let ext = __cpuid(0x80000001);
+    if ext.edx & (1 << 20) == 0 {
+        return;
+    }
+    unsafe {
+        let (mut lo, hi): (u32, u32);
+        core::arch::asm!("rdmsr", in("ecx") 0xC000_0080u32, out("eax") lo, out("edx") hi);
+        lo |= 1 << 11;
+        core::arch::asm!("wrmsr", in("ecx") 0xC000_0080u32, in("eax") lo, in("edx") hi);
+    }
+    NX_MASK.store(NO_EXECUTE, Ordering::Relaxed);
+}
+
+unsafe fn map_kernel_image(space: &mut AddressSpace, base: usize, size: usize) -> Result<(), MapError> {
+    space.identity_map(base, size, WRITABLE)?;
+
+    let pe_offset = *((base + 0x3C) as *const u32) as usize;
+    let pe = base + pe_offset;
+    if *(pe as *const u32) != 0x0000_4550 {
+        return Ok(());
+    }
+    let section_count = *((pe + 6) as *const u16) as usize;
+    let optional_size = *((pe + 20) as *const u16) as usize;
+    let sections = pe + 24 + optional_size;
+
+    space.identity_map(base, FRAME_SIZE, NO_EXECUTE)?;
+    for i in 0..section_count {
+        let section = sections + i * 40;
+        let virt_size = *((section + 8) as *const u32) as usize;
+        let virt_addr = *((section + 12) as *const u32) as usize;
+        let characteristics = *((section + 36) as *const u32);
+
+        let mut flags = 0;
+        if characteristics & 0x8000_0000 != 0 { flags |= WRITABLE; }
+        if characteristics & 0x2000_0000 == 0 { flags |= NO_EXECUTE; }
+        space.identity_map(base + virt_addr, virt_size, flags)?;
+    }
+    Ok(())
+}
+
+pub fn init(memory_map: &impl MemoryMap, layout: &KernelLayout) -> Result<AddressSpace, MapError> {
+    enable_nx();
+    let mut space = AddressSpace::new()?;
+
+    for desc in memory_map.entries() {
+        let start = desc.phys_start as usize;
+        let len = desc.page_count as usize * FRAME_SIZE;
+        let flags = match desc.ty {
+            MemoryType::LOADER_CODE
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::RUNTIME_SERVICES_CODE => WRITABLE,
+            MemoryType::CONVENTIONAL
+            | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::RUNTIME_SERVICES_DATA
+            | MemoryType::ACPI_RECLAIM
+            | MemoryType::ACPI_NON_VOLATILE => WRITABLE | NO_EXECUTE,
+            _ => continue,
+        };
+        space.map_range(start, start, len, flags)?;
+        space.map_range(phys_to_virt(start), start, len, WRITABLE | NO_EXECUTE | GLOBAL)?;
+    }
+
+    unsafe { map_kernel_image(&mut space, layout.image_base, layout.image_size)?; }
+
+    let (fb_addr, fb_size) = layout.framebuffer;
+    space.identity_map(fb_addr, fb_size, WRITABLE | NO_EXECUTE | WRITE_THROUGH)?;
+
+    for &(base, size) in layout.mmio.iter() {
+        space.identity_map(base, size, WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH)?;
+    }
+
+    let (bitmap_addr, bitmap_size) = layout.bitmap;
+    space.identity_map(bitmap_addr, bitmap_size, WRITABLE | NO_EXECUTE)?;
+
+    Ok(space)
+}
@@ -1,7 +1,102 @@
 use core::panic::PanicInfo;
+use spin::Mutex;
+use crate::system::idt::{PageFaultInfo, TrapFrame};
+
+static PAGE_FAULT: Mutex<Option<PageFaultInfo>> = Mutex::new(None);
+static TRAP: Mutex<Option<TrapFrame>> = Mutex::new(None);
+
+const MAX_FRAMES: usize = 16;
+
+struct Registers {
+    frame: TrapFrame,
+    cr0: u64, cr2: u64, cr3: u64, cr4: u64,
+}
+
+impl Registers {
+    fn from_trap(frame: TrapFrame) -> Self {
+        let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+        unsafe {
+            core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+            core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        Self { frame, cr0, cr2, cr3, cr4 }
+    }
+
+    fn print(&self) {
+        let f = &self.frame;
+        crate::print!("REGISTERS (vector {}, error {:#x}):\n", f.vector, f.error_code);
+        crate::print!("  RIP={:016x} CS={:04x} SS={:04x}\n", f.rip, f.cs, f.ss);
+        crate::print!("  RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}\n", f.rax, f.rbx, f.rcx, f.rdx);
+        crate::print!("  RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}\n", f.rsi, f.rdi, f.rbp, f.rsp);
+        crate::print!("  R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}\n", f.r8, f.r9, f.r10, f.r11);
+        crate::print!("  R12={:016x} R13={:016x} R14={:016x} R15={:016x}\n", f.r12, f.r13, f.r14, f.r15);
+        crate::print!("  CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}\n", self.cr0, self.cr2, self.cr3, self.cr4);
+        crate::print!("  RFLAGS={:016x}\n\n", f.rflags);
+    }
+}
+
+fn is_readable(addr: u64) -> bool {
+    if addr == 0 || addr % 8 != 0 {
+        return false;
+    }
+    match crate::KERNEL_SPACE.get().and_then(|s| s.try_lock()) {
+        Some(space) => space.translate(addr as usize).is_some() && space.translate(addr as usize + 8).is_some(),
+        None => false,
+    }
+}
+
+fn print_backtrace(mut rbp: u64) {
+    crate::print!("BACKTRACE:\n");
+    for i in 0..MAX_FRAMES {
+        if !is_readable(rbp) {
+            break;
+        }
+        let ret = unsafe { *((rbp + 8) as *const u64) };
+        if ret == 0 {
+            break;
+        }
+        match crate::system::symbols::resolve(ret) {
+            Some((name, offset)) => {
+                let name = name.get(..72).unwrap_or(name);
+                crate::print!("  #{:<2} {:016x}  {}+{:#x}\n", i, ret, name, offset);
+            }
+            None => crate::print!("  #{:<2} {:016x}  <unknown>\n", i, ret),
+        }
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+    crate::print!("\n");
+}
+
+pub fn record_trap(frame: &TrapFrame) {
+    *TRAP.lock() = Some(*frame);
+}
+
+pub fn record_page_fault(info: PageFaultInfo) {
+    *PAGE_FAULT.lock() = Some(info);
+}
+
+fn print_page_fault(info: &PageFaultInfo) {
+    crate::print!("PAGE FAULT:\n");
+    crate::print!("  Address: {:#018x}\n", info.address);
+    crate::print!("  RIP:     {:#018x}  CS: {:#06x}\n", info.rip, info.cs);
+    crate::print!("  RSP:     {:#018x}\n", info.rsp);
+    crate::print!("  Error:   {:#06x} (", info.error_code);
+    crate::print!("{}", if info.present() { "PROTECTION_VIOLATION" } else { "NOT_PRESENT" });
+    crate::print!(" {}", if info.write() { "WRITE" } else { "READ" });
+    crate::print!(" {}", if info.user() { "USER" } else { "KERNEL" });
+    if info.reserved() { crate::print!(" RESERVED_BIT"); }
+    if info.instruction_fetch() { crate::print!(" INSTRUCTION_FETCH"); }
+    if info.protection_key() { crate::print!(" PROTECTION_KEY"); }
+    crate::print!(")\n\n");
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    let regs = TRAP.try_lock().and_then(|t| *t).map(Registers::from_trap);
+    #[cfg(test)]
+    crate::testing::report_failure(info);
     unsafe {
         if let Some(ref mut c) = crate::system::GLOBAL_CONSOLE {
             crate::clear_screen!(0xFF0000); 
@@ -21,11 +116,17 @@ fn panic(info: &PanicInfo) -> ! {
             if let Some(location) = info.location() {
                 crate::print!("LOCATION:\n");
                 crate::print!("  File: {}\n", location.file());
-                crate::print!("  Line: {}\n\n", location.line());
+                crate::print!("  Line: {}\n", location.line());
+                crate::system::sched::with_current_name(|thread| crate::print!("  Thread: {}\n", thread));
+                crate::print!("\n");
             }
 
             crate::print!("--------------------------------------------------------------------------\n");
             crate::print!("TECHNICAL INFORMATION:\n\n");
+
+            if let Some(fault) = PAGE_FAULT.try_lock().and_then(|f| *f) {
+                print_page_fault(&fault);
+            }
             
             
             let mut brand_string = [0u8; 48];
@@ -60,12 +161,26 @@ fn panic(info: &PanicInfo) -> ! {
             }
             crate::print!("\n\n");
             
-            crate::print!("*** STOP: 0x0000001E (0xFFFFFFFFC0000005, 0xFFFFF80002A5D5A2)\n");
+            match regs {
+                Some(regs) => {
+                    regs.print();
+                    print_backtrace(regs.frame.rbp);
+                }
+                None => {
+                    let rbp: u64;
+                    core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+                    print_backtrace(rbp);
+                }
+            }
             crate::print!("--------------------------------------------------------------------------\n");
             crate::print!("The system has halted. Please restart your machine manually.\n");
         }
     }
 
+    #[cfg(test)]
+    crate::testing::exit_qemu(crate::testing::QemuExitCode::Failed);
+
+    #[cfg(not(test))]
     loop {
         unsafe { core::arch::asm!("hlt"); }
     }
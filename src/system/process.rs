@@ -0,0 +1,172 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::fs::vfs;
+use crate::system::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::system::memory::FRAME_SIZE;
+use crate::system::paging::{AddressSpace, MapError, NO_EXECUTE, USER, USER_BASE, USER_STACK_TOP, USER_TOP, WRITABLE};
+use crate::system::sched::{self, JoinHandle};
+use crate::KERNEL_SPACE;
+
+pub const USER_STACK_FRAMES: usize = 16;
+pub const USER_STACK_BOTTOM: usize = USER_STACK_TOP - USER_STACK_FRAMES * FRAME_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ProcessError {
+    NoKernelSpace,
+    MapFailed,
+    SpawnFailed,
+}
+
+impl From<MapError> for ProcessError {
+    fn from(_: MapError) -> Self {
+        ProcessError::MapFailed
+    }
+}
+
+struct Process {
+    pid: usize,
+    name: String,
+    space: AddressSpace,
+}
+
+pub struct ProcessHandle {
+    pub pid: usize,
+    thread: JoinHandle,
+}
+
+static PROCESSES: Mutex<Vec<Process>> = Mutex::new(Vec::new());
+static EXIT_CODES: Mutex<Vec<(usize, i64)>> = Mutex::new(Vec::new());
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn new_address_space() -> Result<AddressSpace, ProcessError> {
+    let kernel = KERNEL_SPACE.get().ok_or(ProcessError::NoKernelSpace)?;
+    let space = AddressSpace::new_user(&kernel.lock())?;
+    Ok(space)
+}
+
+pub fn copy_to_user(space: &AddressSpace, virt: usize, data: &[u8]) -> Result<(), ProcessError> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let addr = virt + offset;
+        let phys = space.translate(addr).ok_or(MapError::NotMapped)?;
+        let chunk = (FRAME_SIZE - addr % FRAME_SIZE).min(data.len() - offset);
+        unsafe { core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), phys as *mut u8, chunk); }
+        offset += chunk;
+    }
+    Ok(())
+}
+
+pub fn copy_from_user(space: &AddressSpace, virt: usize, data: &mut [u8]) -> Result<(), ProcessError> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let addr = virt + offset;
+        let phys = space.translate(addr).ok_or(MapError::NotMapped)?;
+        let chunk = (FRAME_SIZE - addr % FRAME_SIZE).min(data.len() - offset);
+        unsafe { core::ptr::copy_nonoverlapping(phys as *const u8, data[offset..].as_mut_ptr(), chunk); }
+        offset += chunk;
+    }
+    Ok(())
+}
+
+pub fn alloc_stack(space: &mut AddressSpace) -> Result<usize, ProcessError> {
+    space.alloc_user(USER_STACK_BOTTOM, USER_STACK_TOP - USER_STACK_BOTTOM, WRITABLE | NO_EXECUTE)?;
+    Ok(USER_STACK_TOP)
+}
+
+pub fn spawn(name: &str, space: AddressSpace, entry: usize, rsp: usize) -> Result<ProcessHandle, ProcessError> {
+    let pid = NEXT_PID.fetch_add(1, Ordering::SeqCst);
+    let cr3 = space.pml4_addr();
+    interrupts::without_interrupts(|| PROCESSES.lock().push(Process { pid, name: String::from(name), space }));
+
+    let thread = sched::spawn(name, move || {
+        sched::enter_process(pid, cr3);
+        unsafe { enter_user(entry, rsp) }
+    });
+
+    match thread {
+        Some(thread) => Ok(ProcessHandle { pid, thread }),
+        None => {
+            if let Some(process) = take(pid) {
+                unsafe { process.space.destroy_user(); }
+            }
+            Err(ProcessError::SpawnFailed)
+        }
+    }
+}
+
+impl ProcessHandle {
+    pub fn wait(self) -> i64 {
+        self.thread.join();
+        interrupts::without_interrupts(|| {
+            let mut codes = EXIT_CODES.lock();
+            let pos = codes.iter().position(|&(pid, _)| pid == self.pid);
+            pos.map(|i| codes.remove(i).1).unwrap_or(-1)
+        })
+    }
+}
+
+fn take(pid: usize) -> Option<Process> {
+    interrupts::without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        let pos = processes.iter().position(|p| p.pid == pid)?;
+        Some(processes.remove(pos))
+    })
+}
+
+unsafe fn enter_user(entry: usize, stack: usize) -> ! {
+    core::arch::asm!(
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "cli",
+        "swapgs",
+        "iretq",
+        ss = const USER_DATA_SELECTOR,
+        rsp = in(reg) stack,
+        rflags = const 0x202,
+        cs = const USER_CODE_SELECTOR,
+        rip = in(reg) entry,
+        options(noreturn),
+    );
+}
+
+pub fn check_user_range(addr: usize, len: usize, flags: u64) -> bool {
+    if addr < USER_BASE || addr >= USER_TOP || len > USER_TOP - addr {
+        return false;
+    }
+    let pid = sched::current_pid();
+    interrupts::without_interrupts(|| {
+        let processes = PROCESSES.lock();
+        let Some(process) = processes.iter().find(|p| p.pid == pid) else { return false };
+        let required = USER | flags;
+        (addr & !(FRAME_SIZE - 1)..addr + len)
+            .step_by(FRAME_SIZE)
+            .all(|page| process.space.flags(page).map_or(false, |f| f & required == required))
+    })
+}
+
+pub fn exit(code: i64) -> ! {
+    let pid = sched::current_pid();
+    vfs::close_all(pid);
+    sched::leave_process();
+    if let Some(process) = take(pid) {
+        unsafe { process.space.destroy_user(); }
+    }
+    interrupts::without_interrupts(|| EXIT_CODES.lock().push((pid, code)));
+    sched::exit();
+}
+
+pub fn kill_current(reason: &str, addr: u64) -> ! {
+    interrupts::enable();
+    let pid = sched::current_pid();
+    let name = interrupts::without_interrupts(|| {
+        PROCESSES.lock().iter().find(|p| p.pid == pid).map(|p| p.name.clone())
+    });
+    crate::print!("\n{} (pid {}) killed: {} at {:#x}", name.as_deref().unwrap_or("?"), pid, reason, addr);
+    exit(-1);
+}
@@ -0,0 +1,369 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::system::{paging, smp, time};
+use crate::KERNEL_SPACE;
+
+pub const STACK_FRAMES: usize = 8;
+const TIME_SLICE_TICKS: u64 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Sleeping(u64),
+    Joining(usize),
+    Finished,
+}
+
+struct Thread {
+    id: usize,
+    name: String,
+    state: ThreadState,
+    rsp: usize,
+    stack_top: usize,
+    pid: usize,
+    cr3: usize,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+struct Scheduler {
+    threads: Vec<Box<Thread>>,
+    current: usize,
+    idle: usize,
+    next_id: usize,
+    slice_end: u64,
+    kernel_cr3: usize,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    threads: Vec::new(),
+    current: 0,
+    idle: 0,
+    next_id: 0,
+    slice_end: 0,
+    kernel_cr3: 0,
+});
+static SCHED_READY: AtomicBool = AtomicBool::new(false);
+
+pub struct JoinHandle {
+    id: usize,
+}
+
+extern "sysv64" {
+    fn sched_switch(old_rsp: *mut usize, new_rsp: usize);
+    fn sched_thread_start();
+}
+
+core::arch::global_asm!(
+    ".global sched_switch",
+    ".global sched_thread_start",
+    "sched_switch:",
+    "    pushfq",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rdi], rsp",
+    "    mov rsp, rsi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    popfq",
+    "    ret",
+    "sched_thread_start:",
+    "    call {entry}",
+    "    ud2",
+    entry = sym thread_entry,
+);
+
+impl Scheduler {
+    fn position(&self, id: usize) -> Option<usize> {
+        self.threads.iter().position(|t| t.id == id)
+    }
+
+    fn current_thread(&mut self) -> &mut Thread {
+        let pos = self.position(self.current).expect("current thread missing");
+        &mut self.threads[pos]
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        for t in self.threads.iter_mut() {
+            if let ThreadState::Sleeping(until) = t.state {
+                if until <= now {
+                    t.state = ThreadState::Ready;
+                }
+            }
+        }
+    }
+
+    fn pick_next(&self) -> Option<usize> {
+        let start = self.position(self.current)?;
+        let count = self.threads.len();
+        (1..=count)
+            .map(|i| &self.threads[(start + i) % count])
+            .find(|t| t.id != self.idle && (t.state == ThreadState::Ready || (t.id == self.current && t.state == ThreadState::Running)))
+            .or_else(|| self.threads.iter().find(|t| t.id == self.idle))
+            .map(|t| t.id)
+    }
+
+    fn reap(&mut self) -> Vec<usize> {
+        let mut stacks = Vec::new();
+        self.threads.retain(|t| {
+            if t.state == ThreadState::Finished && t.id != self.current {
+                stacks.push(t.stack_top);
+                false
+            } else {
+                true
+            }
+        });
+        stacks
+    }
+}
+
+fn free_stacks(stacks: Vec<usize>) {
+    let Some(space) = KERNEL_SPACE.get() else { return };
+    let mut space = space.lock();
+    for top in stacks {
+        let _ = space.free_stack(top, STACK_FRAMES);
+    }
+}
+
+fn reschedule() {
+    let (old_rsp, new_rsp) = {
+        let mut sched = SCHEDULER.lock();
+        let Some(next) = sched.pick_next() else { return };
+        if next == sched.current {
+            sched.current_thread().state = ThreadState::Running;
+            return;
+        }
+
+        let current = sched.current_thread();
+        if current.state == ThreadState::Running {
+            current.state = ThreadState::Ready;
+        }
+        let old_rsp = &mut current.rsp as *mut usize;
+
+        sched.current = next;
+        sched.slice_end = time::get_ticks() + TIME_SLICE_TICKS;
+        let kernel_cr3 = sched.kernel_cr3;
+        let next = sched.current_thread();
+        next.state = ThreadState::Running;
+
+        if next.stack_top != 0 {
+            smp::set_kernel_stack(next.stack_top);
+        }
+        let cr3 = if next.cr3 != 0 { next.cr3 } else { kernel_cr3 };
+        if paging::read_cr3() != cr3 {
+            unsafe { paging::write_cr3(cr3); }
+        }
+        (old_rsp, next.rsp)
+    };
+    unsafe { sched_switch(old_rsp, new_rsp); }
+}
+
+extern "sysv64" fn thread_entry() -> ! {
+    let entry = interrupts::without_interrupts(|| SCHEDULER.lock().current_thread().entry.take());
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+fn idle() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        sched.threads.push(Box::new(Thread {
+            id: 0,
+            name: String::from("main"),
+            state: ThreadState::Running,
+            rsp: 0,
+            stack_top: 0,
+            pid: 0,
+            cr3: 0,
+            entry: None,
+        }));
+        sched.current = 0;
+        sched.next_id = 1;
+        sched.kernel_cr3 = paging::read_cr3();
+    });
+
+    let idle = spawn("idle", idle).expect("Failed to spawn idle thread");
+    interrupts::without_interrupts(|| SCHEDULER.lock().idle = idle.id);
+    SCHED_READY.store(true, Ordering::SeqCst);
+}
+
+pub fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> Option<JoinHandle> {
+    let finished = interrupts::without_interrupts(|| SCHEDULER.lock().reap());
+    free_stacks(finished);
+
+    let stack_top = KERNEL_SPACE.get()?.lock().alloc_stack(STACK_FRAMES).ok()?;
+    let frame = [0u64, 0, 0, 0, 0, 0, 0x202, sched_thread_start as *const () as u64];
+    let rsp = stack_top - core::mem::size_of_val(&frame);
+    unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()); }
+
+    let id = interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let id = sched.next_id;
+        sched.next_id += 1;
+        sched.threads.push(Box::new(Thread {
+            id,
+            name: String::from(name),
+            state: ThreadState::Ready,
+            rsp,
+            stack_top,
+            pid: 0,
+            cr3: 0,
+            entry: Some(Box::new(f)),
+        }));
+        id
+    });
+    Some(JoinHandle { id })
+}
+
+pub fn yield_now() {
+    interrupts::without_interrupts(reschedule);
+}
+
+pub fn sleep_ms(ms: u64) {
+    let ticks = ms.saturating_mul(time::TICK_HZ).saturating_add(999) / 1000;
+    interrupts::without_interrupts(|| {
+        let until = time::get_ticks().saturating_add(ticks.max(1));
+        SCHEDULER.lock().current_thread().state = ThreadState::Sleeping(until);
+        reschedule();
+    });
+}
+
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut sched = SCHEDULER.lock();
+        let id = sched.current;
+        sched.current_thread().state = ThreadState::Finished;
+        for t in sched.threads.iter_mut() {
+            if t.state == ThreadState::Joining(id) {
+                t.state = ThreadState::Ready;
+            }
+        }
+    }
+    reschedule();
+    unreachable!("finished thread was scheduled again");
+}
+
+pub fn enter_process(pid: usize, cr3: usize) {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let thread = sched.current_thread();
+        thread.pid = pid;
+        thread.cr3 = cr3;
+        unsafe { paging::write_cr3(cr3); }
+    });
+}
+
+pub fn leave_process() {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let kernel_cr3 = sched.kernel_cr3;
+        let thread = sched.current_thread();
+        thread.pid = 0;
+        thread.cr3 = 0;
+        unsafe { paging::write_cr3(kernel_cr3); }
+    });
+}
+
+pub fn current_pid() -> usize {
+    interrupts::without_interrupts(|| SCHEDULER.lock().current_thread().pid)
+}
+
+pub fn with_current_name<R>(f: impl FnOnce(&str) -> R) -> Option<R> {
+    if !SCHED_READY.load(Ordering::SeqCst) {
+        return None;
+    }
+    let mut sched = SCHEDULER.try_lock()?;
+    Some(f(&sched.current_thread().name))
+}
+
+pub fn preempt() {
+    if !SCHED_READY.load(Ordering::SeqCst) {
+        return;
+    }
+    let switch = {
+        let mut sched = SCHEDULER.lock();
+        let now = time::get_ticks();
+        sched.wake_sleepers(now);
+        now >= sched.slice_end || sched.current == sched.idle
+    };
+    if switch {
+        reschedule();
+    }
+}
+
+impl JoinHandle {
+    pub fn join(self) {
+        loop {
+            let done = interrupts::without_interrupts(|| {
+                let mut sched = SCHEDULER.lock();
+                let finished = match sched.position(self.id) {
+                    Some(pos) => sched.threads[pos].state == ThreadState::Finished,
+                    None => true,
+                };
+                if !finished {
+                    sched.current_thread().state = ThreadState::Joining(self.id);
+                }
+                finished
+            });
+            if done {
+                return;
+            }
+            yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn join_waits_for_thread() {
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+        let handle = spawn("test", || { DONE.store(42, Ordering::SeqCst); }).unwrap();
+        handle.join();
+        assert_eq!(DONE.load(Ordering::SeqCst), 42);
+    }
+
+    #[test_case]
+    fn sleeping_thread_lets_others_run() {
+        static ORDER: AtomicUsize = AtomicUsize::new(0);
+        let sleeper = spawn("sleeper", || {
+            sleep_ms(30);
+            ORDER.fetch_add(10, Ordering::SeqCst);
+        }).unwrap();
+        let worker = spawn("worker", || { ORDER.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        worker.join();
+        assert_eq!(ORDER.load(Ordering::SeqCst), 1);
+        sleeper.join();
+        assert_eq!(ORDER.load(Ordering::SeqCst), 11);
+    }
+
+    #[test_case]
+    fn yield_returns_to_caller() {
+        yield_now();
+        assert_eq!(with_current_name(|name| name == "main"), Some(true));
+    }
+}
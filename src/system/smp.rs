@@ -0,0 +1,274 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::system::acpi::Madt;
+use crate::system::apic;
+use crate::system::gdt::{Gdt, TaskStateSegment};
+use crate::system::memory::FRAME_SIZE;
+use crate::system::paging::{self, WRITABLE};
+use crate::system::{syscall, time};
+use crate::{IDT_INSTANCE, KERNEL_SPACE};
+
+pub const AP_STACK_FRAMES: usize = 4;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+const IA32_EFER: u32 = 0xC000_0080;
+
+pub const KERNEL_RSP_OFFSET: usize = core::mem::offset_of!(PerCpu, kernel_rsp);
+pub const USER_RSP_OFFSET: usize = core::mem::offset_of!(PerCpu, user_rsp);
+
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr: *const PerCpu,
+    kernel_rsp: AtomicUsize,
+    user_rsp: usize,
+    pub index: usize,
+    pub lapic_id: u8,
+    pub tss: *mut TaskStateSegment,
+    pub gdt: &'static Gdt,
+}
+
+#[repr(C, packed)]
+struct TrampolineParams {
+    gdt_ptr_limit: u16,
+    gdt_ptr_base: u32,
+    far_jump_offset: u32,
+    far_jump_selector: u16,
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    efer: u32,
+    stack_top: u64,
+    entry: u64,
+    arg: u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_params: u8;
+}
+
+core::arch::global_asm!(
+    ".section .text",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_gdt",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_params",
+    ".set AP_PARAMS_OFFSET, ap_trampoline_params - ap_trampoline_start",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    mov bx, offset AP_PARAMS_OFFSET",
+    "    lgdt [bx]",
+    "    mov eax, [bx + 20]",
+    "    mov cr4, eax",
+    "    mov eax, [bx + 16]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080",
+    "    mov eax, [bx + 24]",
+    "    xor edx, edx",
+    "    wrmsr",
+    "    mov eax, [bx + 12]",
+    "    mov cr0, eax",
+    "    jmp fword ptr [bx + 6]",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov rsp, [rip + ap_trampoline_params + 28]",
+    "    mov rdi, [rip + ap_trampoline_params + 44]",
+    "    mov rax, [rip + ap_trampoline_params + 36]",
+    "    call rax",
+    "2:",
+    "    hlt",
+    "    jmp 2b",
+    ".p2align 3",
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "ap_trampoline_params:",
+    "    .space 52",
+    "ap_trampoline_end:",
+);
+
+#[inline]
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    core::arch::asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32);
+}
+
+#[inline]
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi);
+    ((hi as u64) << 32) | lo as u64
+}
+
+fn read_cr(index: u8) -> u64 {
+    let val: u64;
+    unsafe {
+        match index {
+            0 => core::arch::asm!("mov {}, cr0", out(reg) val),
+            _ => core::arch::asm!("mov {}, cr4", out(reg) val),
+        }
+    }
+    val
+}
+
+pub fn current() -> &'static PerCpu {
+    unsafe {
+        let ptr: *const PerCpu;
+        core::arch::asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*ptr
+    }
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+pub fn set_kernel_stack(top: usize) {
+    let cpu = current();
+    cpu.kernel_rsp.store(top, Ordering::SeqCst);
+    unsafe { (*cpu.tss).rsp[0] = top as u64; }
+}
+
+unsafe fn set_gs_base(cpu: &'static PerCpu) {
+    write_msr(IA32_GS_BASE, cpu as *const PerCpu as u64);
+    write_msr(IA32_KERNEL_GS_BASE, 0);
+}
+
+unsafe fn new_percpu(index: usize, lapic_id: u8, stack_top: usize, tss: *mut TaskStateSegment, gdt: &'static Gdt) -> &'static PerCpu {
+    let cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        kernel_rsp: AtomicUsize::new(stack_top),
+        user_rsp: 0,
+        index,
+        lapic_id,
+        tss,
+        gdt,
+    }));
+    cpu.self_ptr = cpu as *const PerCpu;
+    cpu
+}
+
+pub unsafe fn init_bsp(gdt: &'static Gdt, tss: *mut TaskStateSegment, stack_top: usize) {
+    set_gs_base(new_percpu(0, apic::lapic_id(), stack_top, tss, gdt));
+}
+
+
+extern "sysv64" fn ap_entry(cpu: &'static PerCpu) -> ! {
+    unsafe {
+        cpu.gdt.load();
+        if let Some(idt) = IDT_INSTANCE.get() {
+            idt.load();
+        }
+        set_gs_base(cpu);
+        if let Some(lapic) = apic::local_apic() {
+            lapic.enable();
+        }
+        syscall::init();
+    }
+
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+unsafe fn boot_ap(trampoline: usize, index: usize, lapic_id: u8) -> bool {
+    let Some(space) = KERNEL_SPACE.get() else { return false };
+    let mut tss = Box::new(TaskStateSegment::new());
+    let stack_top = {
+        let mut space = space.lock();
+        let Ok(top) = space.alloc_stack(AP_STACK_FRAMES) else { return false };
+        if tss.alloc_ist_stacks(&mut space).is_err() {
+            return false;
+        }
+        top
+    };
+    let tss: &'static mut TaskStateSegment = Box::leak(tss);
+    let gdt: &'static Gdt = Box::leak(Box::new(Gdt::new(&*(tss as *const TaskStateSegment))));
+    let cpu = new_percpu(index, lapic_id, stack_top, tss, gdt);
+
+    let start = &ap_trampoline_start as *const u8 as usize;
+    let offset_of = |sym: &u8| sym as *const u8 as usize - start;
+    let params = (trampoline + offset_of(&ap_trampoline_params)) as *mut TrampolineParams;
+    core::ptr::write_volatile(params, TrampolineParams {
+        gdt_ptr_limit: 23,
+        gdt_ptr_base: (trampoline + offset_of(&ap_trampoline_gdt)) as u32,
+        far_jump_offset: (trampoline + offset_of(&ap_trampoline_long_mode)) as u32,
+        far_jump_selector: 0x08,
+        cr0: read_cr(0) as u32,
+        cr3: paging::read_cr3() as u32,
+        cr4: (read_cr(4) & !(1 << 17)) as u32,
+        efer: (read_msr(IA32_EFER) & ((1 << 8) | (1 << 11))) as u32,
+        stack_top: stack_top as u64,
+        entry: ap_entry as *const () as u64,
+        arg: cpu as *const PerCpu as u64,
+    });
+
+    let Some(lapic) = apic::local_apic() else { return false };
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    lapic.send_ipi(lapic_id, 0x4500);
+    time::delay_us(10_000);
+
+    let vector = (trampoline >> 12) as u32;
+    for _ in 0..2 {
+        lapic.send_ipi(lapic_id, 0x4600 | vector);
+        for _ in 0..1000 {
+            if AP_STARTED.load(Ordering::SeqCst) {
+                return true;
+            }
+            time::delay_us(200);
+        }
+    }
+    false
+}
+
+pub unsafe fn start_aps(madt: &Madt, trampoline: usize) -> usize {
+    if paging::read_cr3() > u32::MAX as usize || trampoline >= 0x100000 {
+        return online_cpus();
+    }
+
+    if let Some(space) = KERNEL_SPACE.get() {
+        if space.lock().identity_map(trampoline, FRAME_SIZE, WRITABLE).is_err() {
+            return online_cpus();
+        }
+    }
+
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    core::ptr::copy_nonoverlapping(start, trampoline as *mut u8, len);
+
+    let bsp_id = apic::lapic_id();
+    let mut index = 1;
+    for entry in madt.local_apics.iter() {
+        if !(entry.enabled || entry.online_capable) || entry.apic_id > 0xFF {
+            continue;
+        }
+        let lapic_id = entry.apic_id as u8;
+        if lapic_id == bsp_id {
+            continue;
+        }
+        if boot_ap(trampoline, index, lapic_id) {
+            index += 1;
+        }
+    }
+    online_cpus()
+}
@@ -0,0 +1,75 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+
+// Left zeroed by the linker and filled in by `symtab.py` after the link,
+// from the map of the very same image.
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 8],
+    capacity: u32,
+    count: u32,
+    data: [u8; SYMBOL_TABLE_SIZE],
+}
+
+#[used]
+static SYMBOL_TABLE: SymbolTable = SymbolTable {
+    magic: *b"FIGSYMS\0",
+    capacity: SYMBOL_TABLE_SIZE as u32,
+    count: 0,
+    data: [0; SYMBOL_TABLE_SIZE],
+};
+
+static IMAGE_BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_image_base(base: usize) {
+    IMAGE_BASE.store(base, Ordering::Relaxed);
+}
+
+fn table() -> *const SymbolTable {
+    core::hint::black_box(&SYMBOL_TABLE as *const SymbolTable)
+}
+
+fn read_u32(offset: usize) -> Option<u32> {
+    if offset + 4 > SYMBOL_TABLE_SIZE {
+        return None;
+    }
+    let ptr = unsafe { (*table()).data.as_ptr().add(offset) } as *const [u8; 4];
+    Some(u32::from_le_bytes(unsafe { core::ptr::read_volatile(ptr) }))
+}
+
+fn entry(index: usize) -> Option<(u32, &'static str)> {
+    let rva = read_u32(index * 8)?;
+    let name_off = read_u32(index * 8 + 4)? as usize;
+    let data = unsafe { &(*table()).data };
+    let len = *data.get(name_off)? as usize;
+    let name = data.get(name_off + 1..name_off + 1 + len)?;
+    Some((rva, core::str::from_utf8(name).unwrap_or("<invalid>")))
+}
+
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let base = IMAGE_BASE.load(Ordering::Relaxed) as u64;
+    if base == 0 || addr < base {
+        return None;
+    }
+    let rva = addr - base;
+    if rva > u32::MAX as u64 {
+        return None;
+    }
+
+    let count = unsafe { core::ptr::read_volatile(&(*table()).count) } as usize;
+    let (mut lo, mut hi) = (0, count.min(SYMBOL_TABLE_SIZE / 8));
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if entry(mid)?.0 <= rva as u32 {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo == 0 {
+        return None;
+    }
+    let (start, name) = entry(lo - 1)?;
+    Some((name, rva - start as u64))
+}
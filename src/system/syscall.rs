@@ -0,0 +1,255 @@
+use crate::fs::vfs::{self, FileType, FsError, SeekFrom};
+use crate::system::gdt::{KERNEL_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::system::paging::WRITABLE;
+use crate::system::smp::{self, KERNEL_RSP_OFFSET, USER_RSP_OFFSET};
+use crate::system::{process, sched};
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_READ: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+pub const SYS_OPEN: u64 = 5;
+pub const SYS_CLOSE: u64 = 6;
+pub const SYS_SEEK: u64 = 7;
+pub const SYS_FSTAT: u64 = 8;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+const FD_BASE: u64 = 3;
+const MAX_PATH: usize = 256;
+
+pub const ENOENT: i64 = -2;
+pub const EIO: i64 = -5;
+pub const EBADF: i64 = -9;
+pub const EBUSY: i64 = -16;
+pub const EEXIST: i64 = -17;
+pub const EXDEV: i64 = -18;
+pub const ENOTDIR: i64 = -20;
+pub const EISDIR: i64 = -21;
+pub const EINVAL: i64 = -22;
+pub const ENOSPC: i64 = -28;
+pub const EROFS: i64 = -30;
+pub const EFAULT: i64 = -14;
+pub const ENAMETOOLONG: i64 = -36;
+pub const ENOSYS: i64 = -38;
+pub const ENOTEMPTY: i64 = -39;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+const RFLAGS_MASK: u64 = 0x700;
+
+extern "sysv64" {
+    fn syscall_entry();
+}
+
+core::arch::global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "    swapgs",
+    "    mov gs:[{user_rsp}], rsp",
+    "    mov rsp, gs:[{kernel_rsp}]",
+    "    push qword ptr gs:[{user_rsp}]",
+    "    push rcx",
+    "    push r11",
+    "    push rdi",
+    "    push rsi",
+    "    push rdx",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    sub rsp, 8",
+    "    sti",
+    "    mov rcx, rdx",
+    "    mov rdx, rsi",
+    "    mov rsi, rdi",
+    "    mov rdi, rax",
+    "    call {dispatch}",
+    "    cli",
+    "    mov r10, [rsp + 64]",
+    "    shl r10, 16",
+    "    sar r10, 16",
+    "    cmp r10, [rsp + 64]",
+    "    jne 2f",
+    "    add rsp, 8",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rdx",
+    "    pop rsi",
+    "    pop rdi",
+    "    pop r11",
+    "    pop rcx",
+    "    pop rsp",
+    "    swapgs",
+    "    sysretq",
+    "2:",
+    "    mov rdi, [rsp + 64]",
+    "    call {bad_return}",
+    user_rsp = const USER_RSP_OFFSET,
+    kernel_rsp = const KERNEL_RSP_OFFSET,
+    dispatch = sym dispatch,
+    bad_return = sym bad_return,
+);
+
+pub fn init() {
+    unsafe {
+        smp::write_msr(IA32_EFER, smp::read_msr(IA32_EFER) | 1);
+        let user_base = (USER_DATA_SELECTOR & !3) as u64 - 8;
+        smp::write_msr(IA32_STAR, (user_base << 48) | ((KERNEL_CODE_SELECTOR as u64) << 32));
+        smp::write_msr(IA32_LSTAR, syscall_entry as *const () as u64);
+        smp::write_msr(IA32_FMASK, RFLAGS_MASK);
+    }
+}
+
+// sysretq with a non-canonical RIP faults in ring 0 on the user stack.
+extern "sysv64" fn bad_return(rip: u64) -> ! {
+    process::kill_current("non-canonical return address", rip)
+}
+
+extern "sysv64" fn dispatch(nr: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    match nr {
+        SYS_WRITE => sys_write(arg0, arg1 as usize, arg2 as usize),
+        SYS_READ => sys_read(arg0, arg1 as usize, arg2 as usize),
+        SYS_EXIT => process::exit(arg0 as i64),
+        SYS_SLEEP => {
+            sched::sleep_ms(arg0);
+            0
+        }
+        SYS_GETPID => sched::current_pid() as i64,
+        SYS_OPEN => sys_open(arg0 as usize, arg1 as usize, arg2 as u32),
+        SYS_CLOSE => file_result(file_fd(arg0).and_then(vfs::close).map(|_| 0)),
+        SYS_SEEK => sys_seek(arg0, arg1 as i64, arg2),
+        SYS_FSTAT => sys_fstat(arg0, arg1 as usize),
+        _ => ENOSYS,
+    }
+}
+
+fn errno(e: FsError) -> i64 {
+    match e {
+        FsError::NotFound => ENOENT,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::AlreadyExists => EEXIST,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::ReadOnly => EROFS,
+        FsError::InvalidPath => ENOENT,
+        FsError::InvalidArgument => EINVAL,
+        FsError::BadDescriptor => EBADF,
+        FsError::Busy => EBUSY,
+        FsError::CrossDevice => EXDEV,
+        FsError::NoSpace => ENOSPC,
+        FsError::Io => EIO,
+    }
+}
+
+fn file_result(result: Result<i64, FsError>) -> i64 {
+    result.unwrap_or_else(errno)
+}
+
+fn file_fd(fd: u64) -> Result<usize, FsError> {
+    fd.checked_sub(FD_BASE).map(|fd| fd as usize).ok_or(FsError::BadDescriptor)
+}
+
+fn sys_open(path: usize, len: usize, flags: u32) -> i64 {
+    if len > MAX_PATH {
+        return ENAMETOOLONG;
+    }
+    if !process::check_user_range(path, len, 0) {
+        return EFAULT;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(path as *const u8, len) };
+    let Ok(path) = core::str::from_utf8(bytes) else { return EINVAL };
+    file_result(vfs::open(path, flags).map(|fd| fd as i64 + FD_BASE as i64))
+}
+
+fn sys_seek(fd: u64, offset: i64, whence: u64) -> i64 {
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return EINVAL,
+    };
+    file_result(file_fd(fd).and_then(|fd| vfs::seek(fd, pos)).map(|pos| pos as i64))
+}
+
+fn sys_fstat(fd: u64, buf: usize) -> i64 {
+    if !process::check_user_range(buf, 24, WRITABLE) {
+        return EFAULT;
+    }
+    let stat = match file_fd(fd).and_then(vfs::fstat) {
+        Ok(stat) => stat,
+        Err(e) => return errno(e),
+    };
+    let kind = match stat.kind {
+        FileType::File => 0,
+        FileType::Directory => 1,
+    };
+    let out = [stat.inode, kind, stat.size];
+    unsafe { core::ptr::copy_nonoverlapping(out.as_ptr(), buf as *mut u64, out.len()); }
+    0
+}
+
+fn sys_write(fd: u64, buf: usize, len: usize) -> i64 {
+    if !process::check_user_range(buf, len, 0) {
+        return EFAULT;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    if fd >= FD_BASE {
+        return file_result(file_fd(fd).and_then(|fd| vfs::write(fd, bytes)).map(|n| n as i64));
+    }
+    if fd != 1 && fd != 2 {
+        return EBADF;
+    }
+    match core::str::from_utf8(bytes) {
+        Ok(text) => crate::print!("{}", text),
+        Err(_) => {
+            for &b in bytes {
+                crate::print!("{}", b as char);
+            }
+        }
+    }
+    len as i64
+}
+
+fn sys_read(fd: u64, buf: usize, len: usize) -> i64 {
+    if !process::check_user_range(buf, len, WRITABLE) {
+        return EFAULT;
+    }
+    if fd >= FD_BASE {
+        let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+        return file_result(file_fd(fd).and_then(|fd| vfs::read(fd, out)).map(|n| n as i64));
+    }
+    if fd != 0 {
+        return EBADF;
+    }
+    if len == 0 {
+        return 0;
+    }
+
+    let first = loop {
+        match crate::system::pop_key() {
+            Some(c) => break c,
+            None => sched::sleep_ms(10),
+        }
+    };
+
+    let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    let mut count = 0;
+    let mut next = Some(first);
+    while let Some(c) = next {
+        let mut encoded = [0u8; 4];
+        let bytes = c.encode_utf8(&mut encoded).as_bytes();
+        if count + bytes.len() > len {
+            break;
+        }
+        out[count..count + bytes.len()].copy_from_slice(bytes);
+        count += bytes.len();
+        next = if len - count >= 4 { crate::system::pop_key() } else { None };
+    }
+    count as i64
+}
@@ -1,62 +1,172 @@
 use x86_64::instructions::port::Port;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::system::apic;
+use crate::system::acpi::Hpet;
+
+pub const TICK_HZ: u64 = 100;
+const CALIBRATION_MS: u64 = 10;
+const PIT_FREQUENCY: u64 = 1_193_182;
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
 
 static TICKS: AtomicU64 = AtomicU64::new(0);
-pub static mut ACTUAL_HZ: u64 = 100;
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static TIMER_MODE: AtomicU8 = AtomicU8::new(TimerMode::Periodic as u8);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerMode {
+    Periodic,
+    TscDeadline,
+}
+
+impl TimerMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            TimerMode::Periodic => "LAPIC periodic",
+            TimerMode::TscDeadline => "LAPIC TSC-deadline",
+        }
+    }
+}
 
-pub fn init() {
-    let divisor: u16 = 11931; 
-    let mut cmd_port = Port::new(0x43);
-    let mut data_port = Port::new(0x40);
+pub fn has_tsc_deadline() -> bool {
+    __cpuid(1).ecx & (1 << 24) != 0
+}
+
+pub fn has_invariant_tsc() -> bool {
+    __cpuid(0x80000000).eax >= 0x80000007 && __cpuid(0x80000007).edx & (1 << 8) != 0
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn pit_wait(ms: u64) {
+    let count = (PIT_FREQUENCY * ms / 1000) as u16;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut cmd = Port::<u8>::new(0x43);
+    let mut data = Port::<u8>::new(0x42);
 
     unsafe {
-        cmd_port.write(0x36u8);
-        data_port.write((divisor & 0xFF) as u8);
-        data_port.write((divisor >> 8) as u8);
+        let val = gate.read();
+        gate.write((val & 0xFC) | 0x01);
+        cmd.write(0xB0);
+        data.write((count & 0xFF) as u8);
+        data.write((count >> 8) as u8);
+
+        let val = gate.read();
+        gate.write(val & 0xFE);
+        gate.write(val | 0x01);
+
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
     }
 }
 
-fn get_rtc_seconds() -> u8 {
-    let mut addr_port = Port::<u8>::new(0x70);
-    let mut data_port = Port::<u8>::new(0x71);
+fn hpet_wait(hpet: &Hpet, ms: u64) -> bool {
+    let base = hpet.base_address as usize;
     unsafe {
-        addr_port.write(0x00);
-        data_port.read()
+        let caps = core::ptr::read_volatile(base as *const u64);
+        let period_fs = caps >> 32;
+        if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS {
+            return false;
+        }
+        let config = (base + 0x10) as *mut u64;
+        core::ptr::write_volatile(config, core::ptr::read_volatile(config) | 1);
+
+        let counter = (base + 0xF0) as *const u64;
+        let start = core::ptr::read_volatile(counter);
+        let target = ms * 1_000_000_000_000 / period_fs;
+        while core::ptr::read_volatile(counter).wrapping_sub(start) < target {
+            core::hint::spin_loop();
+        }
     }
+    true
 }
 
-pub fn calibrate_uefi() {
-    let s1 = get_rtc_seconds();
-    while get_rtc_seconds() == s1 {}
-    
-    let start_ticks = get_ticks();
-    let s2 = get_rtc_seconds();
-    while get_rtc_seconds() == s2 {}
-    let end_ticks = get_ticks();
+pub fn init(hpet: Option<&Hpet>) {
+    let Some(lapic) = apic::local_apic() else { return };
+
+    unsafe { lapic.timer_oneshot(u32::MAX, true); }
+    let tsc_start = rdtsc();
+    if !hpet.is_some_and(|hpet| hpet_wait(hpet, CALIBRATION_MS)) {
+        pit_wait(CALIBRATION_MS);
+    }
+    let tsc_end = rdtsc();
+    let lapic_elapsed = u32::MAX - lapic.timer_current();
+    unsafe { lapic.timer_stop(); }
+
+    let tsc_hz = (tsc_end - tsc_start) * 1000 / CALIBRATION_MS;
+    let lapic_hz = lapic_elapsed as u64 * 1000 / CALIBRATION_MS;
+    TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+    TSC_BASE.store(tsc_end, Ordering::SeqCst);
+    TSC_PER_TICK.store(tsc_hz / TICK_HZ, Ordering::SeqCst);
 
     unsafe {
-        ACTUAL_HZ = end_ticks.wrapping_sub(start_ticks);
-        if ACTUAL_HZ < 10 { ACTUAL_HZ = 100; }
+        if has_tsc_deadline() {
+            TIMER_MODE.store(TimerMode::TscDeadline as u8, Ordering::SeqCst);
+            let deadline = rdtsc() + tsc_hz / TICK_HZ;
+            NEXT_DEADLINE.store(deadline, Ordering::SeqCst);
+            lapic.timer_tsc_deadline(deadline);
+        } else {
+            TIMER_MODE.store(TimerMode::Periodic as u8, Ordering::SeqCst);
+            lapic.timer_periodic((lapic_hz / TICK_HZ).max(1) as u32);
+        }
+    }
+}
+
+pub fn timer_mode() -> TimerMode {
+    if TIMER_MODE.load(Ordering::Relaxed) == TimerMode::TscDeadline as u8 {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::Periodic
     }
 }
 
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
 pub fn tick() {
     TICKS.fetch_add(1, Ordering::Relaxed);
+
+    if timer_mode() == TimerMode::TscDeadline {
+        let per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+        let mut next = NEXT_DEADLINE.load(Ordering::Relaxed) + per_tick;
+        let now = rdtsc();
+        if next <= now {
+            next = now + per_tick;
+        }
+        NEXT_DEADLINE.store(next, Ordering::Relaxed);
+        if let Some(lapic) = apic::local_apic() {
+            unsafe { lapic.timer_tsc_deadline(next); }
+        }
+    }
 }
 
 pub fn get_ticks() -> u64 {
     TICKS.load(Ordering::Relaxed)
 }
 
-pub fn sleep(seconds: u64) {
-    let start = get_ticks();
-    let hz = unsafe { ACTUAL_HZ };
-    let wait_ticks = seconds * hz;
-    
-    while (get_ticks().wrapping_sub(start)) < wait_ticks {
-        unsafe {
-            core::arch::asm!("sti", "hlt");
-        }
+pub fn now_ns() -> u64 {
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    if tsc_hz == 0 || !has_invariant_tsc() {
+        return get_ticks() * (1_000_000_000 / TICK_HZ);
+    }
+    let elapsed = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+    (elapsed as u128 * 1_000_000_000 / tsc_hz as u128) as u64
+}
+
+pub fn delay_us(us: u64) {
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    let start = rdtsc();
+    let target = tsc_hz * us / 1_000_000;
+    while rdtsc().wrapping_sub(start) < target {
+        core::hint::spin_loop();
     }
 }
 
@@ -79,4 +189,4 @@ pub unsafe fn get_rtc_time() -> (u8, u8, u8) {
     h = (h & 0x0F) + ((h / 16) * 10);
 
     (h, m, s)
-}
\ No newline at end of file
+}
@@ -0,0 +1,66 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use x86_64::instructions::port::Port;
+use crate::{serial_print, serial_println};
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { Port::<u32>::new(0xF4).write(code as u32); }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+pub fn report_failure(info: &PanicInfo) {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}", info.message());
+    if let Some(location) = info.location() {
+        serial_println!("  at {}:{}", location.file(), location.line());
+    }
+}
+
+pub fn ustar_archive(files: &[(&str, &[u8])]) -> &'static [u8] {
+    let mut data = Vec::new();
+    for &(name, contents) in files {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+        header[156] = if name.ends_with('/') { b'5' } else { b'0' };
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let sum = header.iter().map(|&b| b as usize).sum::<usize>() + 8 * b' ' as usize;
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(contents);
+        data.resize((data.len() + 511) / 512 * 512, 0);
+    }
+    data.resize(data.len() + 1024, 0);
+    alloc::boxed::Box::leak(data.into_boxed_slice())
+}
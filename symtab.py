@@ -0,0 +1,361 @@
+import re
+import struct
+import sys
+
+MAGIC = b"FIGSYMS\0"
+MAX_NAME = 255
+
+LEGACY_ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+
+BASIC_TYPES = {
+    "a": "i8", "b": "bool", "c": "char", "d": "f64", "e": "str", "f": "f32",
+    "h": "u8", "i": "isize", "j": "usize", "l": "i32", "m": "u32", "n": "i128",
+    "o": "u128", "s": "i16", "t": "u16", "u": "()", "v": "...", "x": "i64",
+    "y": "u64", "z": "!", "p": "_",
+}
+
+
+class V0Demangler:
+    """Rust v0 mangling (`_R...`), printed like rustc-demangle's alternate form."""
+
+    def __init__(self, sym):
+        self.sym = sym
+        self.pos = 0
+        self.depth = 0
+
+    def peek(self):
+        return self.sym[self.pos] if self.pos < len(self.sym) else ""
+
+    def eat(self, c):
+        if self.peek() == c:
+            self.pos += 1
+            return True
+        return False
+
+    def next(self):
+        c = self.peek()
+        if not c:
+            raise ValueError("truncated symbol")
+        self.pos += 1
+        return c
+
+    def base62(self):
+        if self.eat("_"):
+            return 0
+        value = 0
+        while not self.eat("_"):
+            c = self.next()
+            if c.isdigit():
+                d = ord(c) - ord("0")
+            elif c.islower():
+                d = ord(c) - ord("a") + 10
+            elif c.isupper():
+                d = ord(c) - ord("A") + 36
+            else:
+                raise ValueError("bad base-62 digit")
+            value = value * 62 + d
+        return value + 1
+
+    def opt_base62(self, tag):
+        return self.base62() + 1 if self.eat(tag) else 0
+
+    def ident(self):
+        punycode = self.eat("u")
+        start = self.pos
+        if not self.eat("0"):
+            while self.peek().isdigit():
+                self.pos += 1
+        if start == self.pos:
+            raise ValueError("missing identifier length")
+        length = int(self.sym[start:self.pos])
+        self.eat("_")
+        name = self.sym[self.pos:self.pos + length]
+        if len(name) != length:
+            raise ValueError("truncated identifier")
+        self.pos += length
+        if punycode:
+            head, _, tail = name.rpartition("_")
+            try:
+                name = (head + "-" + tail).encode("ascii").decode("punycode")
+            except UnicodeError:
+                pass
+        return name
+
+    def backref(self, parse, *args):
+        target = self.base62()
+        saved = self.pos
+        self.pos = target + 2
+        try:
+            return parse(*args)
+        finally:
+            self.pos = saved
+
+    def enter(self):
+        self.depth += 1
+        if self.depth > 64:
+            raise ValueError("symbol nests too deeply")
+
+    def path(self, in_value):
+        self.enter()
+        try:
+            return self._path(in_value)
+        finally:
+            self.depth -= 1
+
+    def _path(self, in_value):
+        tag = self.next()
+        if tag == "C":
+            self.opt_base62("s")
+            return self.ident()
+        if tag == "M":
+            self.opt_base62("s")
+            self.path(False)
+            return "<" + self.type() + ">"
+        if tag == "X":
+            self.opt_base62("s")
+            self.path(False)
+            ty = self.type()
+            return "<" + ty + " as " + self.path(False) + ">"
+        if tag == "Y":
+            ty = self.type()
+            return "<" + ty + " as " + self.path(False) + ">"
+        if tag == "N":
+            ns = self.next()
+            prefix = self.path(in_value)
+            disambiguator = self.opt_base62("s")
+            name = self.ident()
+            if ns.isupper():
+                kind = {"C": "closure", "S": "shim"}.get(ns, ns)
+                label = kind + (":" + name if name else "")
+                return prefix + "::{" + label + "#" + str(disambiguator) + "}"
+            return prefix + "::" + name
+        if tag == "I":
+            prefix = self.path(in_value)
+            args = []
+            while not self.eat("E"):
+                args.append(self.generic_arg())
+            return prefix + ("::<" if in_value else "<") + ", ".join(args) + ">"
+        if tag == "B":
+            return self.backref(self.path, in_value)
+        raise ValueError("bad path tag")
+
+    def generic_arg(self):
+        if self.eat("L"):
+            self.base62()
+            return "'_"
+        if self.eat("K"):
+            return self.const()
+        return self.type()
+
+    def const(self):
+        if self.eat("B"):
+            return self.backref(self.const)
+        if self.eat("p"):
+            return "_"
+        ty = self.next()
+        negative = self.eat("n")
+        start = self.pos
+        while not self.eat("_"):
+            self.next()
+        digits = self.sym[start:self.pos - 1]
+        value = int(digits, 16) if digits else 0
+        if ty == "b":
+            return "true" if value else "false"
+        if ty == "c":
+            return repr(chr(value))
+        return ("-" if negative else "") + str(value)
+
+    def type(self):
+        self.enter()
+        try:
+            return self._type()
+        finally:
+            self.depth -= 1
+
+    def _type(self):
+        c = self.peek()
+        if c in BASIC_TYPES:
+            self.pos += 1
+            return BASIC_TYPES[c]
+        if self.eat("R") or self.eat("Q"):
+            mutable = self.sym[self.pos - 1] == "Q"
+            if self.eat("L"):
+                self.base62()
+            return ("&mut " if mutable else "&") + self.type()
+        if self.eat("P"):
+            return "*const " + self.type()
+        if self.eat("O"):
+            return "*mut " + self.type()
+        if self.eat("A"):
+            ty = self.type()
+            return "[" + ty + "; " + self.const() + "]"
+        if self.eat("S"):
+            return "[" + self.type() + "]"
+        if self.eat("T"):
+            types = []
+            while not self.eat("E"):
+                types.append(self.type())
+            return "(" + ", ".join(types) + ("," if len(types) == 1 else "") + ")"
+        if self.eat("F"):
+            self.opt_base62("G")
+            unsafe = self.eat("U")
+            abi = ""
+            if self.eat("K"):
+                abi = 'extern "C" ' if self.eat("C") else 'extern "' + self.ident().replace("_", "-") + '" '
+            args = []
+            while not self.eat("E"):
+                args.append(self.type())
+            ret = self.type()
+            sig = ("unsafe " if unsafe else "") + abi + "fn(" + ", ".join(args) + ")"
+            return sig + ("" if ret == "()" else " -> " + ret)
+        if self.eat("D"):
+            self.opt_base62("G")
+            traits = []
+            while not self.eat("E"):
+                trait = self.path(False)
+                bindings = []
+                while self.eat("p"):
+                    name = self.ident()
+                    bindings.append(name + " = " + self.type())
+                if bindings:
+                    trait = (trait[:-1] + ", " if trait.endswith(">") else trait + "<") + ", ".join(bindings) + ">"
+                traits.append(trait)
+            if self.eat("L"):
+                self.base62()
+            return "dyn " + " + ".join(traits)
+        if self.eat("B"):
+            return self.backref(self.type)
+        return self.path(False)
+
+    def demangle(self):
+        if not self.sym.startswith("_R"):
+            raise ValueError("not a v0 symbol")
+        self.pos = 2
+        while self.peek().isdigit():
+            self.pos += 1
+        return self.path(True)
+
+
+def demangle_legacy(name):
+    if name.startswith("__ZN"):
+        name = name[1:]
+    if not name.startswith("_ZN"):
+        return name
+    rest = name[3:]
+    parts = []
+    while rest and rest[0].isdigit():
+        digits = re.match(r"\d+", rest).group(0)
+        length = int(digits)
+        rest = rest[len(digits):]
+        parts.append(rest[:length])
+        rest = rest[length:]
+    if not rest.startswith("E") or not parts:
+        return name
+    if re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        part = part.replace("..", "::")
+        for code, char in LEGACY_ESCAPES.items():
+            part = part.replace(code, char)
+        return re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+
+    return "::".join(unescape(p) for p in parts)
+
+
+def demangle(name):
+    if name.startswith("_R"):
+        try:
+            return V0Demangler(name).demangle()
+        except ValueError:
+            return name
+    return demangle_legacy(name)
+
+
+def parse_map(text):
+    base = 0
+    timestamp = None
+    symbols = {}
+    for line in text.splitlines():
+        line = line.strip()
+        if line.startswith("Preferred load address is "):
+            base = int(line.split()[-1], 16)
+            continue
+        if line.startswith("Timestamp is "):
+            timestamp = int(line.split()[2], 16)
+            continue
+        parts = line.split()
+        if len(parts) < 3 or not parts[0].startswith("0001:"):
+            continue
+        try:
+            rva = int(parts[2], 16) - base
+        except ValueError:
+            continue
+        if 0 <= rva <= 0xFFFFFFFF:
+            symbols.setdefault(rva, demangle(parts[1]))
+    return timestamp, sorted(symbols.items())
+
+
+def pe_timestamp(image):
+    pe = struct.unpack_from("<I", image, 0x3C)[0]
+    return struct.unpack_from("<I", image, pe + 8)[0]
+
+
+def build_table(symbols, capacity):
+    while symbols:
+        names = bytearray()
+        entries = bytearray()
+        names_base = len(symbols) * 8
+        for rva, name in symbols:
+            encoded = name.encode("utf-8")[:MAX_NAME]
+            entries += struct.pack("<II", rva, names_base + len(names))
+            names += bytes([len(encoded)]) + encoded
+        if len(entries) + len(names) <= capacity:
+            return len(symbols), bytes(entries + names)
+        symbols = symbols[: len(symbols) * capacity // (len(entries) + len(names))]
+    return 0, b""
+
+
+def patch(efi_path, map_path):
+    """Fill the kernel's reserved symbol table from the linker map of the same link."""
+    try:
+        with open(map_path, encoding="utf-8", errors="replace") as f:
+            timestamp, symbols = parse_map(f.read())
+    except OSError as e:
+        print(f"symtab: cannot read {map_path}: {e}")
+        return
+    with open(efi_path, "rb") as f:
+        image = bytearray(f.read())
+
+    if timestamp is not None and timestamp != pe_timestamp(image):
+        print(f"symtab: {map_path} does not belong to {efi_path}, backtraces stay unsymbolized")
+        return
+    offset = image.find(MAGIC)
+    if offset < 0 or image.find(MAGIC, offset + 1) >= 0:
+        print(f"symtab: no unique symbol table in {efi_path}")
+        return
+    capacity, count = struct.unpack_from("<II", image, offset + len(MAGIC))
+    if count != 0:
+        return
+
+    count, data = build_table(symbols, capacity)
+    if count < len(symbols):
+        print(f"symtab: table full, kept {count} of {len(symbols)} symbols")
+    struct.pack_into("<I", image, offset + len(MAGIC) + 4, count)
+    data_start = offset + len(MAGIC) + 8
+    image[data_start:data_start + len(data)] = data
+    with open(efi_path, "wb") as f:
+        f.write(image)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 3:
+        print("Usage: python symtab.py <path_to_efi> <path_to_map>")
+        sys.exit(1)
+    patch(sys.argv[1], sys.argv[2])